@@ -0,0 +1,29 @@
+//! Generates the `neptune-triton` crate from `poseidon.fut`.
+//!
+//! Run from `futhark-practice` with
+//! `cargo run --manifest-path codegen/Cargo.toml`, the crate is written to
+//! `./neptune-triton`.
+
+fn main() {
+    let (backend, description) = if cfg!(feature = "opencl") {
+        (
+            genfut::Backend::OpenCL,
+            "GPU implementation of neptune-compatible Poseidon hashing.",
+        )
+    } else {
+        (
+            genfut::Backend::Multicore,
+            "CPU implementation of neptune-compatible Poseidon hashing.",
+        )
+    };
+
+    genfut::genfut(genfut::Opt {
+        name: "neptune-triton".to_string(),
+        file: std::path::PathBuf::from("poseidon.fut"),
+        author: "porcuquine@gmail.com".to_string(),
+        version: "1.0.0".to_string(),
+        description: description.to_string(),
+        license: "MIT OR Apache-2.0".to_string(),
+        backend,
+    })
+}
@@ -0,0 +1,255 @@
+//! `BatchHasher` running the kernels `codegen` generated from `poseidon.fut`
+//! into `../neptune-triton`, with futhark's multicore C backend unless
+//! `codegen` was built with its `opencl` feature. Its tests check it against
+//! `NeptuneBatchHasher` bit for bit.
+
+use std::marker::PhantomData;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use ff::PrimeField;
+use generic_array::GenericArray;
+use neptune::poseidon::PoseidonConstants;
+use neptune::Arity;
+use neptune_triton::{
+    Array_u64_1d, Array_u64_2d, Array_u64_3d, FutharkContext, FutharkOpaqueP11State,
+    FutharkOpaqueP2State, FutharkOpaqueP8State,
+};
+use paired::bls12_381::{Bls12, Fr, FrRepr};
+
+use futhark_practice::{BatchHasher, DEFAULT_BATCH_SIZE};
+
+/// Number of u64 limbs of an `Fr`.
+const LIMBS: usize = 4;
+
+enum State {
+    Arity2(FutharkOpaqueP2State),
+    Arity8(FutharkOpaqueP8State),
+    Arity11(FutharkOpaqueP11State),
+}
+
+/// `BatchHasher` running the `mbatch_hash*` entries of `poseidon.fut`.
+///
+/// Only the arities `poseidon.fut` has kernels for, 2, 8 and 11, are
+/// supported. Preimages and hashes cross the boundary in Montgomery form,
+/// the round constants in canonical form.
+pub struct FutharkBatchHasher<A: Arity<Fr>> {
+    ctx: FutharkContext,
+    state: State,
+    max_batch_size: usize,
+    _a: PhantomData<A>,
+}
+
+impl<A: Arity<Fr>> FutharkBatchHasher<A> {
+    pub fn new(max_batch_size: usize) -> Result<Self> {
+        let mut ctx = FutharkContext::new();
+        let state = init_state::<A>(&mut ctx)?;
+
+        Ok(FutharkBatchHasher {
+            ctx,
+            state,
+            max_batch_size,
+            _a: PhantomData,
+        })
+    }
+
+    pub fn with_default_batch_size() -> Result<Self> {
+        Self::new(DEFAULT_BATCH_SIZE)
+    }
+}
+
+impl<A: Arity<Fr>> BatchHasher<A> for FutharkBatchHasher<A> {
+    fn hash(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>> {
+        ensure!(
+            preimages.len() <= self.max_batch_size,
+            "batch of {} preimages exceeds the maximum of {}",
+            preimages.len(),
+            self.max_batch_size
+        );
+        if preimages.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let monts: Vec<u64> = preimages
+            .iter()
+            .flat_map(|preimage| preimage.iter())
+            .flat_map(|fr| fr.into_raw_repr().0.to_vec())
+            .collect();
+        let input = Array_u64_1d::from_vec(self.ctx, &monts, &[monts.len() as i64, 1])
+            .map_err(|e| anyhow!("failed to copy preimages: {:?}", e))?;
+
+        let ctx = &mut self.ctx;
+        let hashes = match &mut self.state {
+            State::Arity2(state) => {
+                let (hashes, next) = ctx.mbatch_hash2(state, input).map_err(futhark_error)?;
+                *state = next;
+                hashes
+            }
+            State::Arity8(state) => {
+                let (hashes, next) = ctx.mbatch_hash8(state, input).map_err(futhark_error)?;
+                *state = next;
+                hashes
+            }
+            State::Arity11(state) => {
+                let (hashes, next) = ctx.mbatch_hash11(state, input).map_err(futhark_error)?;
+                *state = next;
+                hashes
+            }
+        };
+
+        let (monts, _shape) = hashes.to_vec().map_err(futhark_error)?;
+        ensure!(
+            monts.len() == preimages.len() * LIMBS,
+            "{} limbs for {} hashes",
+            monts.len(),
+            preimages.len()
+        );
+
+        monts
+            .chunks(LIMBS)
+            .map(|limbs| {
+                let mut repr = FrRepr::default();
+                repr.0.copy_from_slice(limbs);
+                Fr::from_raw_repr(repr).map_err(|e| anyhow!("invalid hash: {:?}", e))
+            })
+            .collect()
+    }
+
+    fn max_batch_size(&self) -> usize {
+        self.max_batch_size
+    }
+}
+
+fn futhark_error<E: std::fmt::Debug>(err: E) -> anyhow::Error {
+    anyhow!("futhark error: {:?}", err)
+}
+
+fn init_state<A: Arity<Fr>>(ctx: &mut FutharkContext) -> Result<State> {
+    let constants = PoseidonConstants::<Bls12, A>::new();
+
+    let arity_tag = array_1d(ctx, &[constants.arity_tag])?;
+    let round_keys = array_2d(ctx, &constants.compressed_round_constants)?;
+    let mds_matrix = array_3d(ctx, &constants.mds_matrices.m)?;
+    let pre_sparse_matrix = array_3d(ctx, &constants.pre_sparse_matrix)?;
+    let sparse_matrixes: Vec<Vec<Fr>> = constants
+        .sparse_matrixes
+        .iter()
+        .map(|m| m.w_hat.iter().chain(m.v_rest.iter()).copied().collect())
+        .collect();
+    let sparse_matrixes = array_3d(ctx, &sparse_matrixes)?;
+
+    let state = match A::to_usize() {
+        2 => State::Arity2(
+            ctx.init2(
+                arity_tag,
+                round_keys,
+                mds_matrix,
+                pre_sparse_matrix,
+                sparse_matrixes,
+            )
+            .map_err(futhark_error)?,
+        ),
+        8 => State::Arity8(
+            ctx.init8(
+                arity_tag,
+                round_keys,
+                mds_matrix,
+                pre_sparse_matrix,
+                sparse_matrixes,
+            )
+            .map_err(futhark_error)?,
+        ),
+        11 => State::Arity11(
+            ctx.init11(
+                arity_tag,
+                round_keys,
+                mds_matrix,
+                pre_sparse_matrix,
+                sparse_matrixes,
+            )
+            .map_err(futhark_error)?,
+        ),
+        arity => bail!("no futhark kernel for arity {}", arity),
+    };
+
+    Ok(state)
+}
+
+/// Canonical limbs of `frs`, as expected by `make_constants`.
+fn canonical_u64s(frs: &[Fr]) -> Vec<u64> {
+    frs.iter()
+        .flat_map(|fr| fr.into_repr().0.to_vec())
+        .collect()
+}
+
+fn array_1d(ctx: &FutharkContext, frs: &[Fr]) -> Result<Array_u64_1d> {
+    let u64s = canonical_u64s(frs);
+    Array_u64_1d::from_vec(*ctx, &u64s, &[u64s.len() as i64, 1]).map_err(futhark_error)
+}
+
+fn array_2d(ctx: &FutharkContext, frs: &[Fr]) -> Result<Array_u64_2d> {
+    let u64s = canonical_u64s(frs);
+    Array_u64_2d::from_vec(*ctx, &u64s, &[frs.len() as i64, LIMBS as i64]).map_err(futhark_error)
+}
+
+fn array_3d(ctx: &FutharkContext, rows: &[Vec<Fr>]) -> Result<Array_u64_3d> {
+    ensure!(!rows.is_empty(), "empty matrix");
+    let width = rows[0].len();
+    ensure!(
+        rows.iter().all(|row| row.len() == width),
+        "matrix rows must have the same length"
+    );
+
+    let u64s: Vec<u64> = rows.iter().flat_map(|row| canonical_u64s(row)).collect();
+    Array_u64_3d::from_vec(
+        *ctx,
+        &u64s,
+        &[rows.len() as i64, width as i64, LIMBS as i64],
+    )
+    .map_err(futhark_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ff::Field;
+    use generic_array::sequence::GenericSequence;
+    use generic_array::typenum::{U11, U2, U8};
+
+    use futhark_practice::{build_tree, hash_columns, NeptuneBatchHasher};
+
+    fn check_columns<A: Arity<Fr>>(count: usize) {
+        let mut rng = rand::thread_rng();
+        let columns: Vec<GenericArray<Fr, A>> = (0..count)
+            .map(|_| GenericArray::generate(|_| Fr::random(&mut rng)))
+            .collect();
+
+        let expected = hash_columns(&mut NeptuneBatchHasher::<A>::new(100), &columns).unwrap();
+        let mut futhark = FutharkBatchHasher::<A>::new(100).unwrap();
+        assert_eq!(hash_columns(&mut futhark, &columns).unwrap(), expected);
+    }
+
+    #[test]
+    fn futhark_matches_neptune_columns() {
+        check_columns::<U2>(250);
+        check_columns::<U8>(250);
+        check_columns::<U11>(250);
+    }
+
+    #[test]
+    fn futhark_matches_neptune_tree() {
+        let mut rng = rand::thread_rng();
+        let leaves: Vec<Fr> = (0..4096).map(|_| Fr::random(&mut rng)).collect();
+
+        let expected = build_tree(&mut NeptuneBatchHasher::<U8>::new(64), &leaves).unwrap();
+        let mut futhark = FutharkBatchHasher::<U8>::new(64).unwrap();
+        assert_eq!(build_tree(&mut futhark, &leaves).unwrap(), expected);
+    }
+
+    #[test]
+    fn futhark_rejects_oversized_batches() {
+        let mut futhark = FutharkBatchHasher::<U8>::new(1).unwrap();
+        let preimages = vec![GenericArray::<Fr, U8>::generate(|_| Fr::zero()); 2];
+        assert!(futhark.hash(&preimages).is_err());
+    }
+}
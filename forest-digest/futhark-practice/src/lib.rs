@@ -0,0 +1,167 @@
+//! Batched Poseidon hashing, compatible with neptune.
+//!
+//! `NeptuneBatchHasher` hashes every preimage with neptune and is the
+//! reference. The futhark backend lives in the `futhark` crate next to this
+//! one, which depends on the crate generated by `codegen`.
+
+use anyhow::{ensure, Result};
+use generic_array::GenericArray;
+use neptune::poseidon::{Poseidon, PoseidonConstants};
+use neptune::Arity;
+use paired::bls12_381::{Bls12, Fr};
+
+/// Default number of preimages hashed per call.
+pub const DEFAULT_BATCH_SIZE: usize = 1 << 16;
+
+/// Hashes many preimages of the same arity at once.
+pub trait BatchHasher<A: Arity<Fr>> {
+    /// Returns the hash of every preimage, in order.
+    fn hash(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>>;
+
+    /// Largest number of preimages `hash` should be given at once.
+    fn max_batch_size(&self) -> usize {
+        DEFAULT_BATCH_SIZE
+    }
+}
+
+/// Reference `BatchHasher`, hashing one preimage after the other with neptune.
+pub struct NeptuneBatchHasher<A: Arity<Fr>> {
+    constants: PoseidonConstants<Bls12, A>,
+    max_batch_size: usize,
+}
+
+impl<A: Arity<Fr>> NeptuneBatchHasher<A> {
+    pub fn new(max_batch_size: usize) -> Self {
+        NeptuneBatchHasher {
+            constants: PoseidonConstants::new(),
+            max_batch_size,
+        }
+    }
+}
+
+impl<A: Arity<Fr>> Default for NeptuneBatchHasher<A> {
+    fn default() -> Self {
+        Self::new(DEFAULT_BATCH_SIZE)
+    }
+}
+
+impl<A: Arity<Fr>> BatchHasher<A> for NeptuneBatchHasher<A> {
+    fn hash(&mut self, preimages: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>> {
+        Ok(preimages
+            .iter()
+            .map(|preimage| Poseidon::new_with_preimage(preimage, &self.constants).hash())
+            .collect())
+    }
+
+    fn max_batch_size(&self) -> usize {
+        self.max_batch_size
+    }
+}
+
+/// Hashes `columns` into the leaves of tree-c, splitting them in batches of
+/// at most `hasher.max_batch_size()` columns.
+pub fn hash_columns<A, B>(hasher: &mut B, columns: &[GenericArray<Fr, A>]) -> Result<Vec<Fr>>
+where
+    A: Arity<Fr>,
+    B: BatchHasher<A>,
+{
+    let batch_size = hasher.max_batch_size();
+    ensure!(batch_size > 0, "batch size must not be zero");
+
+    let mut hashes = Vec::with_capacity(columns.len());
+    for batch in columns.chunks(batch_size) {
+        let batch_hashes = hasher.hash(batch)?;
+        ensure!(
+            batch_hashes.len() == batch.len(),
+            "{} hashes for {} preimages",
+            batch_hashes.len(),
+            batch.len()
+        );
+        hashes.extend(batch_hashes);
+    }
+
+    Ok(hashes)
+}
+
+/// Builds the tree of arity `A` over `leaves` and returns all of its rows,
+/// leaves first and root last, in the layout of a `DiskStore`.
+pub fn build_tree<A, B>(hasher: &mut B, leaves: &[Fr]) -> Result<Vec<Fr>>
+where
+    A: Arity<Fr>,
+    B: BatchHasher<A>,
+{
+    let arity = A::to_usize();
+    ensure!(arity > 1, "invalid arity {}", arity);
+    ensure!(!leaves.is_empty(), "a tree needs leaves");
+
+    let mut width = leaves.len();
+    while width > 1 {
+        ensure!(
+            width % arity == 0,
+            "{} leaves do not make a full tree of arity {}",
+            leaves.len(),
+            arity
+        );
+        width /= arity;
+    }
+
+    let mut tree = leaves.to_vec();
+    let mut row_start = 0;
+    while tree.len() - row_start > 1 {
+        let preimages: Vec<GenericArray<Fr, A>> = tree[row_start..]
+            .chunks(arity)
+            .map(GenericArray::clone_from_slice)
+            .collect();
+        let row = hash_columns(hasher, &preimages)?;
+
+        row_start = tree.len();
+        tree.extend(row);
+    }
+
+    Ok(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ff::Field;
+    use generic_array::sequence::GenericSequence;
+    use generic_array::typenum::{U11, U2, U8};
+
+    fn random_columns<A: Arity<Fr>>(count: usize) -> Vec<GenericArray<Fr, A>> {
+        let mut rng = rand::thread_rng();
+        (0..count)
+            .map(|_| GenericArray::generate(|_| Fr::random(&mut rng)))
+            .collect()
+    }
+
+    #[test]
+    fn hash_columns_in_batches() {
+        let columns = random_columns::<U11>(100);
+        let expected = hash_columns(&mut NeptuneBatchHasher::<U11>::default(), &columns).unwrap();
+        let batched = hash_columns(&mut NeptuneBatchHasher::<U11>::new(7), &columns).unwrap();
+
+        assert_eq!(expected.len(), columns.len());
+        assert_eq!(expected, batched);
+        assert!(hash_columns(&mut NeptuneBatchHasher::<U11>::new(0), &columns).is_err());
+    }
+
+    #[test]
+    fn build_tree_rows() {
+        let mut rng = rand::thread_rng();
+        let leaves: Vec<Fr> = (0..4).map(|_| Fr::random(&mut rng)).collect();
+        let constants = PoseidonConstants::<Bls12, U2>::new();
+        let hash = |a: Fr, b: Fr| Poseidon::new_with_preimage(&[a, b], &constants).hash();
+
+        let left = hash(leaves[0], leaves[1]);
+        let right = hash(leaves[2], leaves[3]);
+        let mut expected = leaves.clone();
+        expected.extend(vec![left, right, hash(left, right)]);
+
+        let tree = build_tree(&mut NeptuneBatchHasher::<U2>::new(1), &leaves).unwrap();
+        assert_eq!(tree, expected);
+
+        assert!(build_tree(&mut NeptuneBatchHasher::<U8>::default(), &leaves).is_err());
+    }
+}
@@ -1,5 +1,11 @@
-use blake2b_simd::blake2b;
-use std::mem;
+//! Keyed, format-preserving pseudo-random permutations of `[0, domain_size)`,
+//! built from a balanced Feistel network with cycle-walking.
+
+mod round;
+
+pub use round::{Round, RoundFunction};
+
+use thiserror::Error;
 
 pub const FEISTEL_ROUNDS: usize = 3;
 // 3 rounds is an acceptable value for a pseudo-random permutation,
@@ -8,226 +14,224 @@ pub const FEISTEL_ROUNDS: usize = 3;
 
 pub type Index = u64;
 
-pub type FeistelPrecomputed = (Index, Index, Index);
-
-// Find the minimum number of even bits to represent `num_elements`
-// within a `u32` maximum. Returns the left and right masks evenly
-// distributed that together add up to that minimum number of bits.
-pub fn precompute(num_elements: Index) -> FeistelPrecomputed {
-    let mut next_pow4: Index = 4;
-    let mut log4 = 1;
-    while next_pow4 < num_elements {
-        next_pow4 *= 4;
-        log4 += 1;
-    }
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("the domain of a permutation must not be empty")]
+    EmptyDomain,
+    #[error("a Feistel network needs at least one round")]
+    NoRounds,
+    #[error("{rounds} rounds need {rounds} keys, got {keys}")]
+    NotEnoughKeys { rounds: usize, keys: usize },
+}
 
-    let left_mask = ((1 << log4) - 1) << log4;
-    let right_mask = (1 << log4) - 1;
-    let half_bits = log4;
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Pseudo-random permutation of `[0, domain_size)`, keyed by one key per round.
+///
+/// Indices are split into a `left` and a `right` half of `half_bits` bits each,
+/// the smallest even number of bits able to represent every index of the
+/// domain. As that can encode values outside of it, the network is applied
+/// again to those until landing in the domain (cycle-walking), which keeps
+/// the permutation within `[0, domain_size)` for any `u64` domain size.
+#[derive(Clone)]
+pub struct FeistelPermutation {
+    domain_size: Index,
+    keys: Vec<Index>,
+    round: Round,
+    right_mask: Index,
+    half_bits: u32,
+}
 
-    (left_mask, right_mask, half_bits)
+impl std::fmt::Debug for FeistelPermutation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FeistelPermutation")
+            .field("domain_size", &self.domain_size)
+            .field("rounds", &self.keys.len())
+            .field("round_function", &self.round.function())
+            .finish()
+    }
 }
 
-// Pseudo-randomly shuffle an input from a starting position to another
-// one within the `[0, num_elements)` range using a `key` that will allow
-// the reverse operation to take place.
-pub fn permute(
-    num_elements: Index,
-    index: Index,
-    keys: &[Index],
-    precomputed: FeistelPrecomputed,
-) -> Index {
-    let mut u = encode(index, keys, precomputed);
-
-    while u >= num_elements {
-        u = encode(u, keys, precomputed)
+impl FeistelPermutation {
+    /// Permutation of `[0, domain_size)` with `rounds` rounds, using the first
+    /// `rounds` entries of `keys`, and Blake2b as the round function.
+    pub fn new(domain_size: Index, keys: &[Index], rounds: usize) -> Result<Self> {
+        Self::with_round_function(domain_size, keys, rounds, RoundFunction::default())
     }
-    // Since we are representing `num_elements` using an even number of bits,
-    // that can encode many values above it, so keep repeating the operation
-    // until we land in the permitted range.
 
-    u
-}
+    /// Like `new`, hashing with `function` in the round function.
+    pub fn with_round_function(
+        domain_size: Index,
+        keys: &[Index],
+        rounds: usize,
+        function: RoundFunction,
+    ) -> Result<Self> {
+        if domain_size == 0 {
+            return Err(Error::EmptyDomain);
+        }
+        if rounds == 0 {
+            return Err(Error::NoRounds);
+        }
+        if keys.len() < rounds {
+            return Err(Error::NotEnoughKeys {
+                rounds,
+                keys: keys.len(),
+            });
+        }
 
-// Inverts the `permute` result to its starting value for the same `key`.
-pub fn invert_permute(
-    num_elements: Index,
-    index: Index,
-    keys: &[Index],
-    precomputed: FeistelPrecomputed,
-) -> Index {
-    let mut u = decode(index, keys, precomputed);
-
-    while u >= num_elements {
-        u = decode(u, keys, precomputed);
+        // Find the minimum number of even bits to represent every index of the
+        // domain, at least 2. Both halves get one half of them.
+        let bits = 64 - (domain_size - 1).leading_zeros();
+        let half_bits = (bits / 2 + bits % 2).max(1);
+        let right_mask = (1 << half_bits) - 1;
+
+        Ok(FeistelPermutation {
+            domain_size,
+            keys: keys[..rounds].to_vec(),
+            round: Round::new(function),
+            right_mask,
+            half_bits,
+        })
     }
-    u
-}
 
-/// common_setup performs common calculations on inputs shared by encode and decode.
-/// Decompress the `precomputed` part of the algorithm into the initial `left` and
-/// `right` pieces `(L_0, R_0)` with the `right_mask` and `half_bits` to manipulate
-/// them.
-fn common_setup(index: Index, precomputed: FeistelPrecomputed) -> (Index, Index, Index, Index) {
-    let (left_mask, right_mask, half_bits) = precomputed;
+    pub fn domain_size(&self) -> Index {
+        self.domain_size
+    }
 
-    let left = (index & left_mask) >> half_bits;
-    let right = index & right_mask;
+    pub fn rounds(&self) -> usize {
+        self.keys.len()
+    }
 
-    (left, right, right_mask, half_bits)
-}
+    pub fn round_function(&self) -> RoundFunction {
+        self.round.function()
+    }
 
-fn encode(index: Index, keys: &[Index], precomputed: FeistelPrecomputed) -> Index {
-    let (mut left, mut right, right_mask, half_bits) = common_setup(index, precomputed);
+    /// Pseudo-randomly shuffles `index` to another position of the domain.
+    ///
+    /// Panics if `index` is outside of the domain.
+    pub fn permute(&self, index: Index) -> Index {
+        self.check_index(index);
+
+        let mut u = self.encode(index);
+        // Since we are representing the domain using an even number of bits,
+        // that can encode many values above it, keep repeating the operation
+        // until we land in the permitted range.
+        while u >= self.domain_size {
+            u = self.encode(u);
+        }
 
-    for key in keys.iter().take(FEISTEL_ROUNDS) {
-        let (l, r) = (right, left ^ feistel(right, *key, right_mask));
-        left = l;
-        right = r;
+        u
     }
 
-    (left << half_bits) | right
-}
+    /// Inverts the `permute` result to its starting value.
+    ///
+    /// Panics if `index` is outside of the domain.
+    pub fn invert(&self, index: Index) -> Index {
+        self.check_index(index);
 
-fn decode(index: Index, keys: &[Index], precomputed: FeistelPrecomputed) -> Index {
-    let (mut left, mut right, right_mask, half_bits) = common_setup(index, precomputed);
+        let mut u = self.decode(index);
+        while u >= self.domain_size {
+            u = self.decode(u);
+        }
 
-    for i in (0..FEISTEL_ROUNDS).rev() {
-        let (l, r) = ((right ^ feistel(left, keys[i], right_mask)), left);
-        left = l;
-        right = r;
+        u
     }
 
-    (left << half_bits) | right
-}
+    fn check_index(&self, index: Index) {
+        assert!(
+            index < self.domain_size,
+            "index {} is outside of the domain of size {}",
+            index,
+            self.domain_size
+        );
+    }
 
-const HALF_FEISTEL_BYTES: usize = mem::size_of::<Index>();
-const FEISTEL_BYTES: usize = 2 * HALF_FEISTEL_BYTES;
-
-// Round function of the Feistel network: `F(Ri, Ki)`. Joins the `right`
-// piece and the `key`, hashes it and returns the lower `u32` part of
-// the hash filtered trough the `right_mask`.
-fn feistel(right: Index, key: Index, right_mask: Index) -> Index {
-    let mut data: [u8; FEISTEL_BYTES] = [0; FEISTEL_BYTES];
-
-    // So ugly, but the price of (relative) speed.
-    let r = if FEISTEL_BYTES <= 8 {
-        data[0] = (right >> 24) as u8;
-        data[1] = (right >> 16) as u8;
-        data[2] = (right >> 8) as u8;
-        data[3] = right as u8;
-
-        data[4] = (key >> 24) as u8;
-        data[5] = (key >> 16) as u8;
-        data[6] = (key >> 8) as u8;
-        data[7] = key as u8;
-
-        let raw = blake2b(&data);
-        let hash = raw.as_bytes();
-
-        Index::from(hash[0]) << 24
-            | Index::from(hash[1]) << 16
-            | Index::from(hash[2]) << 8
-            | Index::from(hash[3])
-    } else {
-        data[0] = (right >> 56) as u8;
-        data[1] = (right >> 48) as u8;
-        data[2] = (right >> 40) as u8;
-        data[3] = (right >> 32) as u8;
-        data[4] = (right >> 24) as u8;
-        data[5] = (right >> 16) as u8;
-        data[6] = (right >> 8) as u8;
-        data[7] = right as u8;
-
-        data[8] = (key >> 56) as u8;
-        data[9] = (key >> 48) as u8;
-        data[10] = (key >> 40) as u8;
-        data[11] = (key >> 32) as u8;
-        data[12] = (key >> 24) as u8;
-        data[13] = (key >> 16) as u8;
-        data[14] = (key >> 8) as u8;
-        data[15] = key as u8;
-
-        let raw = blake2b(&data);
-        let hash = raw.as_bytes();
-
-        Index::from(hash[0]) << 56
-            | Index::from(hash[1]) << 48
-            | Index::from(hash[2]) << 40
-            | Index::from(hash[3]) << 32
-            | Index::from(hash[4]) << 24
-            | Index::from(hash[5]) << 16
-            | Index::from(hash[6]) << 8
-            | Index::from(hash[7])
-    };
-
-    r & right_mask
+    /// Splits `index` into its initial `left` and `right` pieces `(L_0, R_0)`.
+    fn split(&self, index: Index) -> (Index, Index) {
+        (
+            (index >> self.half_bits) & self.right_mask,
+            index & self.right_mask,
+        )
+    }
+
+    fn join(&self, left: Index, right: Index) -> Index {
+        (left << self.half_bits) | right
+    }
+
+    fn encode(&self, index: Index) -> Index {
+        let (mut left, mut right) = self.split(index);
+
+        for key in &self.keys {
+            let (l, r) = (right, left ^ self.round.apply(right, *key, self.right_mask));
+            left = l;
+            right = r;
+        }
+
+        self.join(left, right)
+    }
+
+    fn decode(&self, index: Index) -> Index {
+        let (mut left, mut right) = self.split(index);
+
+        for key in self.keys.iter().rev() {
+            let (l, r) = (right ^ self.round.apply(left, *key, self.right_mask), left);
+            left = l;
+            right = r;
+        }
+
+        self.join(left, right)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use std::collections::HashSet;
 
-    // Some sample n-values which are not powers of four and also don't coincidentally happen to
-    // encode/decode correctly.
-    // const BAD_NS: &[Index] = &[5, 6, 8, 12, 17]; //
-    //                                              //
-    // fn encode_decode(n: Index, expect_success: bool) {
-    //     let mut failed = false;
-    //     let precomputed = precompute(n);
-    //     for i in 0..n {
-    //         let p = encode(i, &[1, 2, 3, 4], precomputed);
-    //         let v = decode(p, &[1, 2, 3, 4], precomputed);
-    //         let equal = i == v;
-    //         let in_range = p <= n;
-    //         if expect_success {
-    //             assert!(equal, "failed to permute (n = {})", n);
-    //             assert!(in_range, "output number is too big (n = {})", n);
-    //         } else {
-    //             if !equal || !in_range {
-    //                 failed = true;
-    //             }
-    //         }
-    //     }
-    //     if !expect_success {
-    //         assert!(failed, "expected failure (n = {})", n);
-    //     }
-    // }
-
-    // #[test]
-    // fn test_feistel_power_of_4() {
-    //     // Our implementation is guaranteed to produce a permutation when input size (number of elements)
-    //     // is a power of our.
-    //     let mut n = 1;
-
-    //     // Powers of 4 always succeed.
-    //     for _ in 0..4 {
-    //         n *= 4;
-    //         encode_decode(n, true);
-    //     }
-
-    //     // Some non-power-of 4 also succeed, but here is a selection of examples values showing
-    //     // that this is not guaranteed.
-    //     for i in BAD_NS.iter() {
-    //         encode_decode(*i, false);
-    //     }
-    // }
-
-    // #[test]
-    // fn test_feistel_on_arbitrary_set() {
-    //     for n in BAD_NS.iter() {
-    //         let precomputed = precompute(*n as Index);
-    //         for i in 0..*n {
-    //             let p = permute(*n, i, &[1, 2, 3, 4], precomputed);
-    //             let v = invert_permute(*n, p, &[1, 2, 3, 4], precomputed);
-    //             // Since every element in the set is reversibly mapped to another element also in the set,
-    //             // this is indeed a permutation.
-    //             assert_eq!(i, v, "failed to permute");
-    //             assert!(p <= *n, "output number is too big");
-    //         }
-    //     }
-    // }
+    const KEYS: &[Index] = &[1, 2, 3, 4];
+
+    #[test]
+    fn matches_stacked_graph_permutation() {
+        // Outputs of the permutation used by the stacked graphs, Blake2b over
+        // 3 rounds keyed with the first 3 of `KEYS`.
+        let expected: &[(Index, Index, Index)] = &[
+            (8192, 0, 2318),
+            (8192, 1, 4282),
+            (8192, 8191, 5387),
+            (1000, 7, 526),
+            (17, 16, 8),
+            (4_294_967_295, 123_456_789, 2_520_715_978),
+        ];
+
+        for (domain_size, index, permuted) in expected {
+            let feistel = FeistelPermutation::new(*domain_size, KEYS, FEISTEL_ROUNDS).unwrap();
+            assert_eq!(feistel.permute(*index), *permuted);
+            assert_eq!(feistel.invert(*permuted), *index);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        assert_eq!(
+            FeistelPermutation::new(0, KEYS, 3).unwrap_err(),
+            Error::EmptyDomain
+        );
+        assert_eq!(
+            FeistelPermutation::new(10, KEYS, 0).unwrap_err(),
+            Error::NoRounds
+        );
+        assert_eq!(
+            FeistelPermutation::new(10, &KEYS[..2], 3).unwrap_err(),
+            Error::NotEnoughKeys { rounds: 3, keys: 2 }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "outside of the domain")]
+    fn rejects_indices_outside_of_the_domain() {
+        FeistelPermutation::new(10, KEYS, 3).unwrap().permute(10);
+    }
 
     // Test that 3 (or more) rounds of the Feistel cipher can be used
     // as a pseudorandom permutation, that is, each input will be mapped
@@ -245,12 +249,11 @@ mod tests {
 
         let mut shuffled: HashSet<u64> = HashSet::with_capacity((n * d) as usize);
 
-        let feistel_keys = &[1, 2, 3, 4];
-        let feistel_precomputed = precompute((n * d) as Index);
+        let feistel = FeistelPermutation::new(n * d, KEYS, FEISTEL_ROUNDS).unwrap();
 
         for i in 0..n {
             for k in 0..d {
-                let permuted = permute(n * d, i * d + k, feistel_keys, feistel_precomputed);
+                let permuted = feistel.permute(i * d + k);
 
                 // Since the permutation implies a one-to-one correspondence,
                 // traversing the entire input space should generate the entire
@@ -267,4 +270,57 @@ mod tests {
         // have skipped as duplicates).
         assert_eq!(shuffled.len(), (n * d) as usize);
     }
-}
\ No newline at end of file
+
+    fn round_functions() -> impl Strategy<Value = RoundFunction> {
+        prop_oneof![
+            Just(RoundFunction::Blake2b),
+            Just(RoundFunction::Blake2s),
+            Just(RoundFunction::Sha256)
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        // Small domains, most of them not powers of four, are permuted as a
+        // whole: every index must land in the domain, exactly once, and be
+        // recovered by `invert`.
+        #[test]
+        fn permutes_small_domains(
+            domain_size in 1..2_000u64,
+            keys in prop::collection::vec(any::<u64>(), 1..6),
+            function in round_functions(),
+        ) {
+            let feistel =
+                FeistelPermutation::with_round_function(domain_size, &keys, keys.len(), function)
+                    .unwrap();
+
+            let mut seen = vec![false; domain_size as usize];
+            for index in 0..domain_size {
+                let permuted = feistel.permute(index);
+                prop_assert!(permuted < domain_size);
+                prop_assert!(!seen[permuted as usize]);
+                seen[permuted as usize] = true;
+                prop_assert_eq!(feistel.invert(permuted), index);
+            }
+        }
+
+        // Any `u64` domain, up to `u64::MAX`, keeps indices in range and is
+        // inverted.
+        #[test]
+        fn permutes_any_domain(
+            domain_size in 1..=u64::MAX,
+            index in any::<u64>(),
+            keys in prop::collection::vec(any::<u64>(), 3..5),
+            function in round_functions(),
+        ) {
+            let index = index % domain_size;
+            let feistel =
+                FeistelPermutation::with_round_function(domain_size, &keys, 3, function).unwrap();
+
+            let permuted = feistel.permute(index);
+            prop_assert!(permuted < domain_size);
+            prop_assert_eq!(feistel.invert(permuted), index);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::mem;
+
+use blake2b_simd::blake2b;
+use blake2s_simd::blake2s;
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "poseidon")]
+use ff::PrimeField;
+#[cfg(feature = "poseidon")]
+use generic_array::typenum::U2;
+#[cfg(feature = "poseidon")]
+use neptune::poseidon::{Poseidon, PoseidonConstants};
+#[cfg(feature = "poseidon")]
+use paired::bls12_381::{Bls12, Fr, FrRepr};
+
+use crate::Index;
+
+const HALF_FEISTEL_BYTES: usize = mem::size_of::<Index>();
+const FEISTEL_BYTES: usize = 2 * HALF_FEISTEL_BYTES;
+
+/// Hash function used by the round function `F(Ri, Ki)` of the Feistel network.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum RoundFunction {
+    /// Blake2b over `Ri || Ki`, the function used by the stacked graphs.
+    #[default]
+    Blake2b,
+    /// Blake2s over `Ri || Ki`, which has a circuit gadget, unlike Blake2b.
+    Blake2s,
+    /// SHA-256 over `Ri || Ki`.
+    Sha256,
+    /// Poseidon of arity 2 over `[Ri, Ki]`, cheap to prove in a circuit.
+    #[cfg(feature = "poseidon")]
+    Poseidon,
+}
+
+/// A `RoundFunction` together with whatever it needs to be evaluated.
+#[derive(Clone)]
+pub enum Round {
+    Blake2b,
+    Blake2s,
+    Sha256,
+    #[cfg(feature = "poseidon")]
+    Poseidon(Box<PoseidonConstants<Bls12, U2>>),
+}
+
+impl Round {
+    pub fn new(function: RoundFunction) -> Self {
+        match function {
+            RoundFunction::Blake2b => Round::Blake2b,
+            RoundFunction::Blake2s => Round::Blake2s,
+            RoundFunction::Sha256 => Round::Sha256,
+            #[cfg(feature = "poseidon")]
+            RoundFunction::Poseidon => Round::Poseidon(Box::new(PoseidonConstants::new())),
+        }
+    }
+
+    pub fn function(&self) -> RoundFunction {
+        match self {
+            Round::Blake2b => RoundFunction::Blake2b,
+            Round::Blake2s => RoundFunction::Blake2s,
+            Round::Sha256 => RoundFunction::Sha256,
+            #[cfg(feature = "poseidon")]
+            Round::Poseidon(_) => RoundFunction::Poseidon,
+        }
+    }
+
+    /// Round function of the Feistel network: `F(Ri, Ki)`. Joins the `right`
+    /// piece and the `key`, hashes it and returns the first 8 bytes of the
+    /// hash, big-endian, filtered through the `right_mask`.
+    pub fn apply(&self, right: Index, key: Index, right_mask: Index) -> Index {
+        let r = match self {
+            Round::Blake2b => read_index(blake2b(&input(right, key)).as_bytes()),
+            Round::Blake2s => read_index(blake2s(&input(right, key)).as_bytes()),
+            Round::Sha256 => read_index(&Sha256::digest(&input(right, key))),
+            #[cfg(feature = "poseidon")]
+            Round::Poseidon(constants) => {
+                let preimage = [to_fr(right), to_fr(key)];
+                let hash = Poseidon::new_with_preimage(&preimage, constants).hash();
+                hash.into_repr().as_ref()[0]
+            }
+        };
+
+        r & right_mask
+    }
+}
+
+fn input(right: Index, key: Index) -> [u8; FEISTEL_BYTES] {
+    let mut data = [0u8; FEISTEL_BYTES];
+    data[..HALF_FEISTEL_BYTES].copy_from_slice(&right.to_be_bytes());
+    data[HALF_FEISTEL_BYTES..].copy_from_slice(&key.to_be_bytes());
+
+    data
+}
+
+fn read_index(hash: &[u8]) -> Index {
+    let mut bytes = [0u8; HALF_FEISTEL_BYTES];
+    bytes.copy_from_slice(&hash[..HALF_FEISTEL_BYTES]);
+
+    Index::from_be_bytes(bytes)
+}
+
+#[cfg(feature = "poseidon")]
+fn to_fr(value: Index) -> Fr {
+    Fr::from_repr(FrRepr::from(value)).expect("a u64 is always in the field")
+}
@@ -71,6 +71,27 @@ lazy_static! {
         .copied()
         .collect()
     );
+    /// Number of rows of tree-r-last which are not kept on disk, but
+    /// regenerated from the replica when generating proofs. Larger values
+    /// save disk space at the cost of proving time. Applied to the cache
+    /// directories returned by `sector_cache_dir`.
+    pub static ref ROWS_TO_DISCARD: RwLock<HashMap<u64, usize>> = RwLock::new(
+        [
+            (SECTOR_SIZE_2_KIB, 1),
+            (SECTOR_SIZE_4_KIB, 1),
+            (SECTOR_SIZE_16_KIB, 1),
+            (SECTOR_SIZE_32_KIB, 1),
+            (SECTOR_SIZE_8_MIB, 2),
+            (SECTOR_SIZE_16_MIB, 2),
+            (SECTOR_SIZE_512_MIB, 2),
+            (SECTOR_SIZE_1_GIB, 2),
+            (SECTOR_SIZE_32_GIB, 2),
+            (SECTOR_SIZE_64_GIB, 2),
+        ]
+        .iter()
+        .copied()
+        .collect()
+    );
     // These numbers must match those used for Window PoSt scheduling in the miner actor.
     // Please coordinate changes with actor code.
     // https://github.com/filecoin-project/specs-actors/blob/master/actors/abi/sector.go
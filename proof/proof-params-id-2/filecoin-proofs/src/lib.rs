@@ -1,10 +1,14 @@
 pub mod constants;
 pub mod types;
 pub mod parameters;
+pub mod pieces;
+pub mod tree_d;
 
 pub use self::types::*;
 pub use self::constants::*;
 pub use self::parameters::*;
+pub use self::pieces::*;
+pub use self::tree_d::*;
 
 
 #[cfg(test)]
@@ -1,8 +1,11 @@
-use anyhow::{ensure, Result};
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use storage_proofs::merkle::CacheDir;
 use storage_proofs::porep::stacked::{self, LayerChallenges};
 
 use crate::constants::*;
-use crate::types::{ PaddedBytesAmount };
+use crate::types::{ PaddedBytesAmount, SectorSize };
 
 
 pub fn setup_params(
@@ -44,6 +47,18 @@ pub fn setup_params(
     })
 }
 
+/// Cache directory at `cache_path` of a sector of `sector_size`, discarding
+/// the rows of tree-r-last configured for that size in `ROWS_TO_DISCARD`.
+pub fn sector_cache_dir(cache_path: &Path, sector_size: SectorSize) -> Result<CacheDir> {
+    let rows_to_discard = *ROWS_TO_DISCARD
+        .read()
+        .unwrap()
+        .get(&u64::from(sector_size))
+        .with_context(|| format!("unknown sector size {}", u64::from(sector_size)))?;
+
+    Ok(CacheDir::new(cache_path).with_rows_to_discard(rows_to_discard))
+}
+
 fn select_challenges(
     partitions: usize,
     minimum_total_challenges: usize,
@@ -58,3 +73,19 @@ fn select_challenges(
     Ok(guess)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sector_cache_dir_uses_configured_rows_to_discard() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let cache = sector_cache_dir(dir.path(), SectorSize(SECTOR_SIZE_2_KIB)).unwrap();
+        assert_eq!(cache.rows_to_discard(8, 8), 1);
+        let cache = sector_cache_dir(dir.path(), SectorSize(SECTOR_SIZE_32_GIB)).unwrap();
+        assert_eq!(cache.rows_to_discard(1 << 27, 8), 2);
+
+        assert!(sector_cache_dir(dir.path(), SectorSize(3 << 10)).is_err());
+    }
+}
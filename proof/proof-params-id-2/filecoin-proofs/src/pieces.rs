@@ -0,0 +1,188 @@
+use anyhow::{ensure, Result};
+use storage_proofs::hasher::{Domain, HashFunction, Hasher};
+use storage_proofs::util::NODE_SIZE;
+
+use crate::constants::DefaultPieceHasher;
+use crate::types::*;
+
+/// Computes comm_d, the root of tree-d, of a sector holding `piece_infos` in
+/// order.
+///
+/// Every piece starts at a multiple of its padded size, which must be a power of
+/// two. Gaps before a piece and the space after the last one are filled with
+/// zeros, so the pieces do not need to fill the sector.
+pub fn compute_comm_d(sector_size: SectorSize, piece_infos: &[PieceInfo]) -> Result<Commitment> {
+    let sector_size = u64::from(sector_size);
+    ensure!(
+        sector_size.is_power_of_two() && sector_size >= NODE_SIZE as u64,
+        "invalid sector size {}",
+        sector_size
+    );
+
+    // Roots of the complete subtrees over the data placed so far, with their
+    // sizes in bytes, decreasing.
+    let mut stack: Vec<(Commitment, u64)> = Vec::new();
+    let mut total = 0;
+
+    for piece_info in piece_infos {
+        let size = u64::from(PaddedBytesAmount::from(piece_info.size));
+        ensure!(
+            size.is_power_of_two() && size >= NODE_SIZE as u64,
+            "padded piece size {} is not a power of two",
+            size
+        );
+
+        while total % size != 0 {
+            let padding = lowest_bit(total);
+            push(&mut stack, zero_commitment(padding)?, padding)?;
+            total += padding;
+        }
+        ensure!(
+            total + size <= sector_size,
+            "pieces of {} bytes do not fit in a sector of {}",
+            total + size,
+            sector_size
+        );
+        push(&mut stack, piece_info.commitment, size)?;
+        total += size;
+    }
+
+    while total < sector_size {
+        let padding = if total == 0 {
+            sector_size
+        } else {
+            lowest_bit(total)
+        };
+        push(&mut stack, zero_commitment(padding)?, padding)?;
+        total += padding;
+    }
+
+    ensure!(stack.len() == 1, "pieces did not reduce to a single root");
+    Ok(stack[0].0)
+}
+
+/// Root of the tree over `size` zero bytes.
+pub fn zero_commitment(size: u64) -> Result<Commitment> {
+    ensure!(
+        size.is_power_of_two() && size >= NODE_SIZE as u64,
+        "invalid padded size {}",
+        size
+    );
+
+    let mut commitment = [0u8; 32];
+    let mut covered = NODE_SIZE as u64;
+    while covered < size {
+        commitment = piece_hash(&commitment, &commitment)?;
+        covered *= 2;
+    }
+
+    Ok(commitment)
+}
+
+/// Hashes two nodes of tree-d into their parent.
+pub fn piece_hash(left: &[u8], right: &[u8]) -> Result<Commitment> {
+    let left = <DefaultPieceHasher as Hasher>::Domain::try_from_bytes(left)?;
+    let right = <DefaultPieceHasher as Hasher>::Domain::try_from_bytes(right)?;
+    let parent = <DefaultPieceHasher as Hasher>::Function::hash2(&left, &right);
+
+    let mut commitment = [0u8; 32];
+    commitment.copy_from_slice(parent.as_ref());
+    Ok(commitment)
+}
+
+fn lowest_bit(n: u64) -> u64 {
+    n & n.wrapping_neg()
+}
+
+/// Pushes a subtree onto `stack`, merging it with its left neighbours of the same
+/// size.
+fn push(stack: &mut Vec<(Commitment, u64)>, commitment: Commitment, size: u64) -> Result<()> {
+    let mut node = (commitment, size);
+    while let Some((left, left_size)) = stack.last().copied() {
+        if left_size != node.1 {
+            break;
+        }
+        stack.pop();
+        node = (piece_hash(&left, &node.0)?, 2 * node.1);
+    }
+    stack.push(node);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::Rng;
+    use storage_proofs::merkle::BinaryMerkleTree;
+
+    /// Fr32 compatible random data of `size` bytes.
+    fn random_data(size: usize) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let mut data: Vec<u8> = (0..size).map(|_| rng.gen()).collect();
+        for node in data.chunks_mut(NODE_SIZE) {
+            node[31] &= 0b0011_1111;
+        }
+        data
+    }
+
+    fn root(data: &[u8]) -> Commitment {
+        let leaves = data
+            .chunks(NODE_SIZE)
+            .map(|node| <DefaultPieceHasher as Hasher>::Domain::try_from_bytes(node).unwrap());
+        let tree = BinaryMerkleTree::<DefaultPieceHasher>::new(leaves).unwrap();
+
+        let mut commitment = [0u8; 32];
+        commitment.copy_from_slice(tree.root().as_ref());
+        commitment
+    }
+
+    fn piece(data: &[u8]) -> PieceInfo {
+        let size = UnpaddedBytesAmount::from(PaddedBytesAmount(data.len() as u64));
+        PieceInfo::new(root(data), size).unwrap()
+    }
+
+    #[test]
+    fn comm_d_of_pieces_matches_tree_d() {
+        let sector_size = SectorSize(4096);
+        let a = random_data(512);
+        let b = random_data(1024);
+        let c = random_data(256);
+
+        // `b` is aligned after a gap of 512 zero bytes, zeros fill the rest.
+        let mut sector = a.clone();
+        sector.extend(vec![0u8; 512]);
+        sector.extend(&b);
+        sector.extend(&c);
+        sector.resize(4096, 0);
+
+        let comm_d = compute_comm_d(sector_size, &[piece(&a), piece(&b), piece(&c)]).unwrap();
+        assert_eq!(comm_d, root(&sector));
+    }
+
+    #[test]
+    fn comm_d_of_full_and_empty_sectors() {
+        let data = random_data(2048);
+        assert_eq!(
+            compute_comm_d(SectorSize(2048), &[piece(&data)]).unwrap(),
+            root(&data)
+        );
+
+        assert_eq!(
+            compute_comm_d(SectorSize(2048), &[]).unwrap(),
+            root(&[0u8; 2048])
+        );
+        assert_eq!(zero_commitment(2048).unwrap(), root(&[0u8; 2048]));
+    }
+
+    #[test]
+    fn comm_d_rejects_invalid_pieces() {
+        let data = random_data(1024);
+        assert!(compute_comm_d(SectorSize(1024), &[piece(&data), piece(&data)]).is_err());
+
+        let odd = PieceInfo::new([0u8; 32], UnpaddedBytesAmount(381)).unwrap();
+        assert!(compute_comm_d(SectorSize(2048), &[odd]).is_err());
+        assert!(PieceInfo::new([0u8; 32], UnpaddedBytesAmount(0)).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs::{self, File};
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use log::{info, warn};
+use memmap::MmapOptions;
+use merkletree::store::StoreConfig;
+use storage_proofs::cache_key::CacheKey;
+use storage_proofs::merkle::{BinaryMerkleTree, CacheDir};
+
+use crate::constants::DefaultPieceHasher;
+use crate::pieces::compute_comm_d;
+use crate::types::*;
+
+/// Builds tree-d over the staged sector at `staged_sector_path` and stores it in
+/// `cache` under `CacheKey::CommDTree`.
+///
+/// The root must be the comm_d computed from `piece_infos`, otherwise the staged
+/// sector does not hold the expected pieces: the stored tree is removed and an
+/// error returned, before any sealing work is done.
+pub fn build_tree_d(
+    cache: &CacheDir,
+    staged_sector_path: &Path,
+    sector_size: SectorSize,
+    piece_infos: &[PieceInfo],
+) -> Result<BinaryMerkleTree<DefaultPieceHasher>> {
+    let expected = compute_comm_d(sector_size, piece_infos)?;
+
+    let file = File::open(staged_sector_path)
+        .with_context(|| format!("could not open {:?}", staged_sector_path))?;
+    let len = file.metadata()?.len();
+    ensure!(
+        len == u64::from(sector_size),
+        "staged sector {:?} has {} bytes, expected {}",
+        staged_sector_path,
+        len,
+        u64::from(sector_size)
+    );
+    let data = unsafe { MmapOptions::new().map(&file) }
+        .with_context(|| format!("could not map {:?}", staged_sector_path))?;
+
+    info!("building tree-d of {:?}", staged_sector_path);
+    let config = cache.store_config(CacheKey::CommDTree, 0);
+    let tree =
+        BinaryMerkleTree::<DefaultPieceHasher>::from_byte_slice_with_config(&data, config.clone())?;
+
+    let root = tree.root();
+    if AsRef::<[u8]>::as_ref(&root) != &expected[..] {
+        drop(tree);
+        let path = StoreConfig::data_path(&config.path, &config.id);
+        if let Err(err) = fs::remove_file(&path) {
+            warn!("could not remove {:?}: {}", path, err);
+        }
+        bail!(
+            "comm_d of {:?} is {:?}, expected {:?} from the pieces",
+            staged_sector_path,
+            root,
+            expected
+        );
+    }
+
+    Ok(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::Rng;
+    use storage_proofs::hasher::{Domain, Hasher};
+    use storage_proofs::util::NODE_SIZE;
+
+    fn random_piece(size: usize) -> (Vec<u8>, PieceInfo) {
+        let mut rng = rand::thread_rng();
+        let mut data: Vec<u8> = (0..size).map(|_| rng.gen()).collect();
+        for node in data.chunks_mut(NODE_SIZE) {
+            node[31] &= 0b0011_1111;
+        }
+
+        let leaves = data
+            .chunks(NODE_SIZE)
+            .map(|node| <DefaultPieceHasher as Hasher>::Domain::try_from_bytes(node).unwrap());
+        let tree = BinaryMerkleTree::<DefaultPieceHasher>::new(leaves).unwrap();
+        let mut commitment = [0u8; 32];
+        commitment.copy_from_slice(tree.root().as_ref());
+
+        let size = UnpaddedBytesAmount::from(PaddedBytesAmount(size as u64));
+        (data, PieceInfo::new(commitment, size).unwrap())
+    }
+
+    #[test]
+    fn tree_d_matches_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+        let sector_size = SectorSize(4096);
+
+        let (a, a_info) = random_piece(1024);
+        let (b, b_info) = random_piece(2048);
+        let mut sector = a;
+        sector.resize(2048, 0);
+        sector.extend(b);
+        let staged_sector_path = dir.path().join("staged");
+        fs::write(&staged_sector_path, &sector).unwrap();
+
+        let tree =
+            build_tree_d(&cache, &staged_sector_path, sector_size, &[a_info, b_info]).unwrap();
+        assert_eq!(
+            AsRef::<[u8]>::as_ref(&tree.root()),
+            &compute_comm_d(sector_size, &[a_info, b_info]).unwrap()[..]
+        );
+        drop(tree);
+
+        // Reopens like any stored tree.
+        let reopened = cache
+            .open_disk_tree::<BinaryMerkleTree<DefaultPieceHasher>>(CacheKey::CommDTree, 128)
+            .unwrap();
+        assert_eq!(reopened.leaves(), 128);
+    }
+
+    #[test]
+    fn tree_d_rejects_other_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+        let sector_size = SectorSize(2048);
+
+        let (data, _) = random_piece(2048);
+        let (_, other) = random_piece(2048);
+        let staged_sector_path = dir.path().join("staged");
+        fs::write(&staged_sector_path, &data).unwrap();
+
+        assert!(build_tree_d(&cache, &staged_sector_path, sector_size, &[other]).is_err());
+        assert!(cache
+            .open_disk_tree::<BinaryMerkleTree<DefaultPieceHasher>>(CacheKey::CommDTree, 64)
+            .is_err());
+
+        // A staged sector of the wrong size is rejected too.
+        fs::write(&staged_sector_path, &data[..1024]).unwrap();
+        assert!(build_tree_d(&cache, &staged_sector_path, sector_size, &[]).is_err());
+    }
+}
@@ -13,4 +13,27 @@ impl From<PaddedBytesAmount> for usize {
     fn from(n: PaddedBytesAmount) -> Self {
         n.0 as usize
     }
-}
\ No newline at end of file
+}
+
+/// Number of bytes of data before Fr32 padding, which turns every 127 bytes into
+/// 128 so that each 32-byte node is a valid field element.
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize, Eq, Ord)]
+pub struct UnpaddedBytesAmount(pub u64);
+
+impl From<UnpaddedBytesAmount> for u64 {
+    fn from(n: UnpaddedBytesAmount) -> Self {
+        n.0
+    }
+}
+
+impl From<UnpaddedBytesAmount> for PaddedBytesAmount {
+    fn from(n: UnpaddedBytesAmount) -> Self {
+        PaddedBytesAmount(n.0 * 128 / 127)
+    }
+}
+
+impl From<PaddedBytesAmount> for UnpaddedBytesAmount {
+    fn from(n: PaddedBytesAmount) -> Self {
+        UnpaddedBytesAmount(n.0 * 127 / 128)
+    }
+}
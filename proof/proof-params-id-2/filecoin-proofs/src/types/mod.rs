@@ -1,9 +1,13 @@
 mod bytes_amount;
+mod piece_info;
 mod porep_config;
 mod porep_proof_partitions;
 mod sector_size;
 
 pub use self::bytes_amount::*;
+pub use self::piece_info::*;
 pub use self::porep_config::*;
 pub use self::porep_proof_partitions::*;
-pub use self::sector_size::*;
\ No newline at end of file
+pub use self::sector_size::*;
+
+pub type Commitment = [u8; 32];
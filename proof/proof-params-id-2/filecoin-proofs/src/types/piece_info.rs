@@ -0,0 +1,20 @@
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::types::*;
+
+/// A piece of data stored in a sector.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PieceInfo {
+    /// Root of the binary tree over the padded piece, comm_p.
+    pub commitment: Commitment,
+    pub size: UnpaddedBytesAmount,
+}
+
+impl PieceInfo {
+    pub fn new(commitment: Commitment, size: UnpaddedBytesAmount) -> Result<Self> {
+        ensure!(u64::from(size) > 0, "piece size must not be zero");
+
+        Ok(PieceInfo { commitment, size })
+    }
+}
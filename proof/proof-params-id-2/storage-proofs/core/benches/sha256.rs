@@ -0,0 +1,77 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use storage_proofs_core::crypto::sha256_multi::{sha256_many_with, Sha256Backend};
+use storage_proofs_core::hasher::{Domain, HashFunction, Sha256Domain, Sha256Function};
+
+/// Number of messages hashed per iteration.
+const MESSAGES: usize = 1024;
+
+fn backends() -> Vec<Sha256Backend> {
+    vec![
+        Sha256Backend::Scalar,
+        Sha256Backend::Sse2,
+        Sha256Backend::Avx2,
+    ]
+    .into_iter()
+    .filter(|backend| backend.is_available())
+    .collect()
+}
+
+fn bench_messages(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+
+    // Tree-d nodes hash two children, labels a replica id, the indices and
+    // 37 parents.
+    for len in &[64usize, 1248] {
+        let mut data = vec![0u8; MESSAGES * len];
+        rng.fill_bytes(&mut data);
+        let messages: Vec<&[u8]> = data.chunks(*len).collect();
+        let mut digests = vec![[0u8; 32]; MESSAGES];
+
+        let mut group = c.benchmark_group(format!("sha256-{}-bytes", len));
+        group.throughput(Throughput::Bytes(data.len() as u64));
+
+        group.bench_function("per-call", |b| {
+            b.iter(|| {
+                for (message, digest) in messages.iter().zip(digests.iter_mut()) {
+                    digest.copy_from_slice(&Sha256::digest(message));
+                }
+            })
+        });
+        for backend in backends() {
+            group.bench_function(format!("{:?}", backend), |b| {
+                b.iter(|| sha256_many_with(backend, &messages, &mut digests))
+            });
+        }
+
+        group.finish();
+    }
+}
+
+fn bench_nodes(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let nodes: Vec<Sha256Domain> = (0..2 * MESSAGES)
+        .map(|_| Sha256Domain::random(&mut rng))
+        .collect();
+
+    let mut group = c.benchmark_group("sha256-tree-row");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+
+    group.bench_function("hash2", |b| {
+        b.iter(|| {
+            nodes
+                .chunks(2)
+                .map(|pair| Sha256Function::hash2(&pair[0], &pair[1]))
+                .collect::<Vec<_>>()
+        })
+    });
+    group.bench_function("hash_nodes", |b| {
+        b.iter(|| Sha256Function::hash_nodes(&nodes, 2))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_messages, bench_nodes);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+use std::fmt;
+
+/// Names of the files a sector keeps in its cache directory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheKey {
+    PAux,
+    TAux,
+    CommDTree,
+    CommCTree,
+    CommRLastTree,
+    /// Progress of sealing, see `SealManifest` in the porep crate.
+    SealManifest,
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CacheKey::PAux => write!(f, "p_aux"),
+            CacheKey::TAux => write!(f, "t_aux"),
+            CacheKey::CommDTree => write!(f, "tree-d"),
+            CacheKey::CommCTree => write!(f, "tree-c"),
+            CacheKey::CommRLastTree => write!(f, "tree-r-last"),
+            CacheKey::SealManifest => write!(f, "seal-manifest"),
+        }
+    }
+}
+
+impl CacheKey {
+    pub fn label_layer(layer: usize) -> String {
+        format!("layer-{}", layer)
+    }
+}
@@ -1,4 +1,6 @@
 use blake2b_simd::blake2b;
+use blake2b_simd::many::{hash_many, HashManyJob};
+use proof_crypto_feistel::{Round, RoundFunction};
 use std::mem;
 
 pub const FEISTEL_ROUNDS: usize = 3;
@@ -49,6 +51,94 @@ pub fn permute(
     u
 }
 
+/// Like `permute`, with `function` in the round function. The graphs use
+/// Blake2b, `gadgets::feistel` has circuits for Blake2s and SHA-256.
+pub fn permute_with(
+    function: RoundFunction,
+    num_elements: Index,
+    index: Index,
+    keys: &[Index],
+    precomputed: FeistelPrecomputed,
+) -> Index {
+    let round = Round::new(function);
+    let mut u = encode_with(&round, index, keys, precomputed);
+
+    while u >= num_elements {
+        u = encode_with(&round, u, keys, precomputed)
+    }
+
+    u
+}
+
+/// Permutes every index of `start..start + permuted.len()` into `permuted`,
+/// with the same results as calling `permute` on each of them.
+///
+/// The indices are encoded together, one round at a time, computing the round
+/// function of all of them with the many-hash mode of Blake2b, which hashes
+/// several inputs at once across SIMD lanes. An index that lands outside of
+/// `[0, num_elements)` is encoded again, like in `permute`, while the others
+/// are done.
+pub fn permute_range(
+    num_elements: Index,
+    start: Index,
+    keys: &[Index],
+    precomputed: FeistelPrecomputed,
+    permuted: &mut [Index],
+) {
+    let (_, right_mask, half_bits) = precomputed;
+    let rounds = keys.len().min(FEISTEL_ROUNDS);
+    let params = blake2b_simd::Params::new();
+
+    // The indices still being encoded: their position in `permuted` and their
+    // current `(left, right)` pieces.
+    let mut pending: Vec<(usize, Index, Index)> = (0..permuted.len())
+        .map(|i| {
+            let (left, right, _, _) = common_setup(start + i as Index, precomputed);
+            (i, left, right)
+        })
+        .collect();
+    let mut inputs = Vec::with_capacity(pending.len());
+
+    while !pending.is_empty() {
+        for key in keys.iter().take(rounds) {
+            inputs.clear();
+            inputs.extend(
+                pending
+                    .iter()
+                    .map(|(_, _, right)| feistel_input(*right, *key)),
+            );
+
+            let mut jobs: Vec<_> = inputs
+                .iter()
+                .map(|input| HashManyJob::new(&params, input))
+                .collect();
+            hash_many(jobs.iter_mut());
+
+            for ((_, left, right), job) in pending.iter_mut().zip(jobs.iter()) {
+                let f = feistel_output(job.to_hash().as_bytes(), right_mask);
+                let (l, r) = (*right, *left ^ f);
+                *left = l;
+                *right = r;
+            }
+        }
+
+        // Keep encoding the indices that are still out of range.
+        let mut i = 0;
+        while i < pending.len() {
+            let (pos, left, right) = pending[i];
+            let u = (left << half_bits) | right;
+            if u < num_elements {
+                permuted[pos] = u;
+                pending.swap_remove(i);
+            } else {
+                let (left, right, _, _) = common_setup(u, precomputed);
+                pending[i] = (pos, left, right);
+                i += 1;
+            }
+        }
+    }
+}
+
 // Inverts the `permute` result to its starting value for the same `key`.
 pub fn invert_permute(
     num_elements: Index,
@@ -78,10 +168,20 @@ fn common_setup(index: Index, precomputed: FeistelPrecomputed) -> (Index, Index,
 }
 
 fn encode(index: Index, keys: &[Index], precomputed: FeistelPrecomputed) -> Index {
+    encode_with(&Round::Blake2b, index, keys, precomputed)
+}
+
+/// One pass of the Feistel network over `index`, without cycle-walking.
+pub(crate) fn encode_with(
+    round: &Round,
+    index: Index,
+    keys: &[Index],
+    precomputed: FeistelPrecomputed,
+) -> Index {
     let (mut left, mut right, right_mask, half_bits) = common_setup(index, precomputed);
 
     for key in keys.iter().take(FEISTEL_ROUNDS) {
-        let (l, r) = (right, left ^ feistel(right, *key, right_mask));
+        let (l, r) = (right, left ^ round.apply(right, *key, right_mask));
         left = l;
         right = r;
     }
@@ -108,10 +208,18 @@ const FEISTEL_BYTES: usize = 2 * HALF_FEISTEL_BYTES;
 // piece and the `key`, hashes it and returns the lower `u32` part of
 // the hash filtered trough the `right_mask`.
 fn feistel(right: Index, key: Index, right_mask: Index) -> Index {
+    let data = feistel_input(right, key);
+    let raw = blake2b(&data);
+
+    feistel_output(raw.as_bytes(), right_mask)
+}
+
+// Bytes hashed by the round function for `right` and `key`.
+fn feistel_input(right: Index, key: Index) -> [u8; FEISTEL_BYTES] {
     let mut data: [u8; FEISTEL_BYTES] = [0; FEISTEL_BYTES];
 
     // So ugly, but the price of (relative) speed.
-    let r = if FEISTEL_BYTES <= 8 {
+    if FEISTEL_BYTES <= 8 {
         data[0] = (right >> 24) as u8;
         data[1] = (right >> 16) as u8;
         data[2] = (right >> 8) as u8;
@@ -121,14 +229,6 @@ fn feistel(right: Index, key: Index, right_mask: Index) -> Index {
         data[5] = (key >> 16) as u8;
         data[6] = (key >> 8) as u8;
         data[7] = key as u8;
-
-        let raw = blake2b(&data);
-        let hash = raw.as_bytes();
-
-        Index::from(hash[0]) << 24
-            | Index::from(hash[1]) << 16
-            | Index::from(hash[2]) << 8
-            | Index::from(hash[3])
     } else {
         data[0] = (right >> 56) as u8;
         data[1] = (right >> 48) as u8;
@@ -147,10 +247,19 @@ fn feistel(right: Index, key: Index, right_mask: Index) -> Index {
         data[13] = (key >> 16) as u8;
         data[14] = (key >> 8) as u8;
         data[15] = key as u8;
+    }
 
-        let raw = blake2b(&data);
-        let hash = raw.as_bytes();
+    data
+}
 
+// Output of the round function, read from the Blake2b `hash` of its input.
+fn feistel_output(hash: &[u8], right_mask: Index) -> Index {
+    let r = if FEISTEL_BYTES <= 8 {
+        Index::from(hash[0]) << 24
+            | Index::from(hash[1]) << 16
+            | Index::from(hash[2]) << 8
+            | Index::from(hash[3])
+    } else {
         Index::from(hash[0]) << 56
             | Index::from(hash[1]) << 48
             | Index::from(hash[2]) << 40
@@ -162,4 +271,55 @@ fn feistel(right: Index, key: Index, right_mask: Index) -> Index {
     };
 
     r & right_mask
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_range(num_elements: Index, start: Index, count: usize) {
+        let keys = [1, 2, 3, 4];
+        let precomputed = precompute(num_elements);
+
+        let mut batched = vec![0; count];
+        permute_range(num_elements, start, &keys, precomputed, &mut batched);
+
+        for (i, p) in batched.iter().enumerate() {
+            let expected = permute(num_elements, start + i as Index, &keys, precomputed);
+            assert_eq!(
+                *p,
+                expected,
+                "index {} of {}",
+                start + i as Index,
+                num_elements
+            );
+        }
+    }
+
+    #[test]
+    fn permute_range_matches_permute() {
+        // Powers of 4 never cycle-walk, the other sizes do.
+        check_range(16, 0, 16);
+        check_range(1 << 20, 12345, 1000);
+        check_range(17, 0, 17);
+        check_range(1000, 3, 997);
+        check_range(5 * 8 * (1 << 10), 0, 4096);
+        check_range(100, 50, 0);
+    }
+
+    #[test]
+    fn permute_range_is_a_permutation() {
+        let num_elements = 1111;
+        let mut batched = vec![0; num_elements as usize];
+        permute_range(
+            num_elements,
+            0,
+            &[7, 8, 9],
+            precompute(num_elements),
+            &mut batched,
+        );
+
+        batched.sort_unstable();
+        assert!(batched.iter().enumerate().all(|(i, p)| i as Index == *p));
+    }
+}
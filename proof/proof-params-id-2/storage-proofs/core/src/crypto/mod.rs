@@ -1,11 +1,53 @@
 use sha2::{Digest, Sha256};
 pub mod feistel;
+pub mod sha256_multi;
 pub mod sloth;
 
+/// Tag separating the randomness derived for one purpose from that derived for
+/// any other. Tags can only be taken from the registry below.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct DomainSeparationTag(&'static str);
 
-pub const DRSAMPLE_DST: DomainSeparationTag = DomainSeparationTag("Filecoin_DRSample");
-pub const FEISTEL_DST: DomainSeparationTag = DomainSeparationTag("Filecoin_Feistel");
+impl DomainSeparationTag {
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+macro_rules! domain_separation_tags {
+    ($($(#[$attr:meta])* $name:ident = $tag:expr;)*) => {
+        $(
+            $(#[$attr])*
+            pub const $name: DomainSeparationTag = DomainSeparationTag($tag);
+        )*
+
+        /// Every tag of the registry, which must all be distinct.
+        pub const DOMAIN_SEPARATION_TAGS: &[DomainSeparationTag] = &[$($name),*];
+    };
+}
+
+domain_separation_tags! {
+    /// Seed of the parents of the DRG.
+    DRSAMPLE_DST = "Filecoin_DRSample";
+    /// Keys of the Feistel permutation of the expander parents.
+    FEISTEL_DST = "Filecoin_Feistel";
+    /// Replica id of a sealed sector.
+    REPLICA_ID_DST = "Filecoin_ReplicaId";
+    /// Challenges of the interactive PoRep.
+    INTERACTIVE_POREP_CHALLENGES_DST = "Filecoin_InteractivePoRepChallenges";
+    /// Sectors challenged by a Window PoSt.
+    WINDOW_POST_SECTORS_DST = "Filecoin_WindowPoStSectors";
+    /// Challenges within the sectors of a Window PoSt.
+    WINDOW_POST_CHALLENGES_DST = "Filecoin_WindowPoStChallenges";
+    /// Sectors challenged by a Winning PoSt.
+    WINNING_POST_SECTORS_DST = "Filecoin_WinningPoStSectors";
+    /// Challenges within the sectors of a Winning PoSt.
+    WINNING_POST_CHALLENGES_DST = "Filecoin_WinningPoStChallenges";
+    /// Randomness mixing the new data of a sector update into its replica.
+    SECTOR_UPDATE_RHO_DST = "Filecoin_SectorUpdateRho";
+    /// Challenges of the proof of a sector update.
+    SECTOR_UPDATE_CHALLENGES_DST = "Filecoin_SectorUpdateChallenges";
+}
 
 pub fn derive_porep_domain_seed(
     domain_separation_tag: DomainSeparationTag,
@@ -17,3 +59,84 @@ pub fn derive_porep_domain_seed(
         .result()
         .into()
 }
+
+/// Fills `output`, of any length, with bytes derived from `input` for
+/// `domain_separation_tag`.
+///
+/// Block `i` of 32 bytes is `Sha256(len(tag) || tag || i || input)`, with the
+/// length of the tag as one byte and `i` as a big-endian `u32`. The length
+/// prefix keeps a tag from colliding with another one that extends it.
+pub fn expand(domain_separation_tag: DomainSeparationTag, input: &[u8], output: &mut [u8]) {
+    let tag = domain_separation_tag.0.as_bytes();
+    assert!(tag.len() <= u8::max_value() as usize, "tag too long");
+
+    for (i, block) in output.chunks_mut(32).enumerate() {
+        assert!(i <= u32::max_value() as usize, "output too long");
+
+        let digest = Sha256::new()
+            .chain(&[tag.len() as u8])
+            .chain(tag)
+            .chain(&(i as u32).to_be_bytes())
+            .chain(input)
+            .result();
+        block.copy_from_slice(&digest[..block.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    #[test]
+    fn domain_separation_tags_are_distinct() {
+        let mut seen = HashSet::new();
+        for tag in DOMAIN_SEPARATION_TAGS {
+            assert!(seen.insert(tag.as_str()), "duplicate tag {:?}", tag);
+        }
+
+        // `derive_porep_domain_seed` hashes the tag right before its input, so
+        // no tag may be a prefix of another either.
+        for a in DOMAIN_SEPARATION_TAGS {
+            for b in DOMAIN_SEPARATION_TAGS {
+                assert!(
+                    a == b || !b.as_str().starts_with(a.as_str()),
+                    "{:?} is a prefix of {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn expand_any_length() {
+        let input = b"some input";
+        let mut long = vec![0u8; 100];
+        expand(REPLICA_ID_DST, input, &mut long);
+
+        // Shorter outputs are prefixes of longer ones.
+        for len in &[0, 1, 31, 32, 33, 64, 99] {
+            let mut short = vec![0u8; *len];
+            expand(REPLICA_ID_DST, input, &mut short);
+            assert_eq!(&short[..], &long[..*len]);
+        }
+
+        let mut first = [0u8; 32];
+        first.copy_from_slice(
+            &Sha256::new()
+                .chain(&[REPLICA_ID_DST.as_str().len() as u8])
+                .chain(REPLICA_ID_DST.as_str())
+                .chain(&0u32.to_be_bytes())
+                .chain(&input[..])
+                .result(),
+        );
+        assert_eq!(&long[..32], &first[..]);
+        assert_ne!(&long[..32], &long[32..64]);
+
+        let mut other = vec![0u8; 100];
+        expand(SECTOR_UPDATE_RHO_DST, input, &mut other);
+        assert_ne!(long, other);
+    }
+}
@@ -0,0 +1,404 @@
+//! Multi-buffer SHA-256.
+//!
+//! Hashes several independent messages of the same length at once, one
+//! message per SIMD lane: 8 lanes with AVX2 and 4 lanes with SSE2. Without
+//! either, every message is hashed on its own through `sha2`.
+
+use sha2::{Digest, Sha256};
+
+const IV: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+#[rustfmt::skip]
+const K: [u32; 64] = [
+    0x428a_2f98, 0x7137_4491, 0xb5c0_fbcf, 0xe9b5_dba5, 0x3956_c25b, 0x59f1_11f1, 0x923f_82a4,
+    0xab1c_5ed5, 0xd807_aa98, 0x1283_5b01, 0x2431_85be, 0x550c_7dc3, 0x72be_5d74, 0x80de_b1fe,
+    0x9bdc_06a7, 0xc19b_f174, 0xe49b_69c1, 0xefbe_4786, 0x0fc1_9dc6, 0x240c_a1cc, 0x2de9_2c6f,
+    0x4a74_84aa, 0x5cb0_a9dc, 0x76f9_88da, 0x983e_5152, 0xa831_c66d, 0xb003_27c8, 0xbf59_7fc7,
+    0xc6e0_0bf3, 0xd5a7_9147, 0x06ca_6351, 0x1429_2967, 0x27b7_0a85, 0x2e1b_2138, 0x4d2c_6dfc,
+    0x5338_0d13, 0x650a_7354, 0x766a_0abb, 0x81c2_c92e, 0x9272_2c85, 0xa2bf_e8a1, 0xa81a_664b,
+    0xc24b_8b70, 0xc76c_51a3, 0xd192_e819, 0xd699_0624, 0xf40e_3585, 0x106a_a070, 0x19a4_c116,
+    0x1e37_6c08, 0x2748_774c, 0x34b0_bcb5, 0x391c_0cb3, 0x4ed8_aa4a, 0x5b9c_ca4f, 0x682e_6ff3,
+    0x748f_82ee, 0x78a5_636f, 0x84c8_7814, 0x8cc7_0208, 0x90be_fffa, 0xa450_6ceb, 0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+/// How `sha256_many_with` hashes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Sha256Backend {
+    /// One message at a time, through `sha2`.
+    Scalar,
+    /// 4 messages at a time.
+    Sse2,
+    /// 8 messages at a time.
+    Avx2,
+}
+
+impl Sha256Backend {
+    /// The backend with the most lanes this CPU supports.
+    pub fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                return Sha256Backend::Avx2;
+            }
+            if is_x86_feature_detected!("sse2") {
+                return Sha256Backend::Sse2;
+            }
+        }
+
+        Sha256Backend::Scalar
+    }
+
+    /// Number of messages hashed at once.
+    pub fn lanes(self) -> usize {
+        match self {
+            Sha256Backend::Scalar => 1,
+            Sha256Backend::Sse2 => 4,
+            Sha256Backend::Avx2 => 8,
+        }
+    }
+
+    pub fn is_available(self) -> bool {
+        match self {
+            Sha256Backend::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Sha256Backend::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(target_arch = "x86_64")]
+            Sha256Backend::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => false,
+        }
+    }
+}
+
+/// Writes the SHA-256 digest of every one of `messages` to `digests`, using
+/// the best backend available. All messages must have the same length.
+pub fn sha256_many(messages: &[&[u8]], digests: &mut [[u8; 32]]) {
+    sha256_many_with(Sha256Backend::detect(), messages, digests)
+}
+
+/// Like `sha256_many`, but with the given `backend`, which must be available.
+pub fn sha256_many_with(backend: Sha256Backend, messages: &[&[u8]], digests: &mut [[u8; 32]]) {
+    assert_eq!(
+        messages.len(),
+        digests.len(),
+        "every message needs a digest"
+    );
+    if messages.is_empty() {
+        return;
+    }
+    let len = messages[0].len();
+    assert!(
+        messages.iter().all(|message| message.len() == len),
+        "messages must have the same length"
+    );
+    assert!(backend.is_available(), "{:?} is not available", backend);
+
+    match backend {
+        Sha256Backend::Scalar => {
+            for (message, digest) in messages.iter().zip(digests.iter_mut()) {
+                digest.copy_from_slice(&Sha256::digest(message));
+            }
+        }
+        #[cfg(target_arch = "x86_64")]
+        Sha256Backend::Sse2 => unsafe { x86::sse2::hash(messages, digests) },
+        #[cfg(target_arch = "x86_64")]
+        Sha256Backend::Avx2 => unsafe { x86::avx2::hash(messages, digests) },
+        #[cfg(not(target_arch = "x86_64"))]
+        _ => unreachable!(),
+    }
+}
+
+/// Number of 64 byte blocks of a padded message of `len` bytes.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+fn padded_blocks(len: usize) -> usize {
+    (len + 9 + 63) / 64
+}
+
+/// Pads `message` into `out`, which is `padded_blocks(message.len())` blocks.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+fn pad(message: &[u8], out: &mut [u8]) {
+    let len = message.len();
+    out[..len].copy_from_slice(message);
+    out[len] = 0x80;
+    let end = out.len() - 8;
+    for byte in &mut out[len + 1..end] {
+        *byte = 0;
+    }
+    out[end..].copy_from_slice(&((len as u64) * 8).to_be_bytes());
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::{pad, padded_blocks, IV, K};
+
+    macro_rules! impl_lanes {
+        (
+            $module:ident, $feature:tt, $lanes:expr, $vec:ty,
+            $load:ident, $store:ident, $set1:ident, $add:ident, $and:ident,
+            $andnot:ident, $or:ident, $xor:ident, $srli:ident, $slli:ident
+        ) => {
+            pub mod $module {
+                use super::*;
+
+                const LANES: usize = $lanes;
+
+                #[inline]
+                #[target_feature(enable = $feature)]
+                unsafe fn load(words: &[u32; LANES]) -> $vec {
+                    $load(words.as_ptr() as *const $vec)
+                }
+
+                #[inline]
+                #[target_feature(enable = $feature)]
+                unsafe fn store(v: $vec) -> [u32; LANES] {
+                    let mut words = [0u32; LANES];
+                    $store(words.as_mut_ptr() as *mut $vec, v);
+                    words
+                }
+
+                #[inline]
+                #[target_feature(enable = $feature)]
+                unsafe fn big_sigma0(x: $vec) -> $vec {
+                    $xor(
+                        $xor(
+                            $or($srli(x, 2), $slli(x, 30)),
+                            $or($srli(x, 13), $slli(x, 19)),
+                        ),
+                        $or($srli(x, 22), $slli(x, 10)),
+                    )
+                }
+
+                #[inline]
+                #[target_feature(enable = $feature)]
+                unsafe fn big_sigma1(x: $vec) -> $vec {
+                    $xor(
+                        $xor(
+                            $or($srli(x, 6), $slli(x, 26)),
+                            $or($srli(x, 11), $slli(x, 21)),
+                        ),
+                        $or($srli(x, 25), $slli(x, 7)),
+                    )
+                }
+
+                #[inline]
+                #[target_feature(enable = $feature)]
+                unsafe fn small_sigma0(x: $vec) -> $vec {
+                    $xor(
+                        $xor(
+                            $or($srli(x, 7), $slli(x, 25)),
+                            $or($srli(x, 18), $slli(x, 14)),
+                        ),
+                        $srli(x, 3),
+                    )
+                }
+
+                #[inline]
+                #[target_feature(enable = $feature)]
+                unsafe fn small_sigma1(x: $vec) -> $vec {
+                    $xor(
+                        $xor(
+                            $or($srli(x, 17), $slli(x, 15)),
+                            $or($srli(x, 19), $slli(x, 13)),
+                        ),
+                        $srli(x, 10),
+                    )
+                }
+
+                #[inline]
+                #[target_feature(enable = $feature)]
+                unsafe fn compress(state: &mut [[u32; LANES]; 8], block: &[[u32; LANES]; 16]) {
+                    let mut w = [$set1(0); 64];
+                    for t in 0..16 {
+                        w[t] = load(&block[t]);
+                    }
+                    for t in 16..64 {
+                        w[t] = $add(
+                            $add(small_sigma1(w[t - 2]), w[t - 7]),
+                            $add(small_sigma0(w[t - 15]), w[t - 16]),
+                        );
+                    }
+
+                    let mut a = load(&state[0]);
+                    let mut b = load(&state[1]);
+                    let mut c = load(&state[2]);
+                    let mut d = load(&state[3]);
+                    let mut e = load(&state[4]);
+                    let mut f = load(&state[5]);
+                    let mut g = load(&state[6]);
+                    let mut h = load(&state[7]);
+
+                    for t in 0..64 {
+                        let ch = $xor($and(e, f), $andnot(e, g));
+                        let maj = $xor($xor($and(a, b), $and(a, c)), $and(b, c));
+                        let t1 = $add(
+                            $add($add(h, big_sigma1(e)), $add(ch, $set1(K[t] as i32))),
+                            w[t],
+                        );
+                        let t2 = $add(big_sigma0(a), maj);
+
+                        h = g;
+                        g = f;
+                        f = e;
+                        e = $add(d, t1);
+                        d = c;
+                        c = b;
+                        b = a;
+                        a = $add(t1, t2);
+                    }
+
+                    for (word, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+                        *word = store($add(load(word), *v));
+                    }
+                }
+
+                /// Hashes `messages`, all of the same length, `LANES` at a time.
+                #[target_feature(enable = $feature)]
+                pub unsafe fn hash(messages: &[&[u8]], digests: &mut [[u8; 32]]) {
+                    let blocks = padded_blocks(messages[0].len());
+                    let lane_len = blocks * 64;
+                    let mut padded = vec![0u8; LANES * lane_len];
+                    let mut block = [[0u32; LANES]; 16];
+
+                    for (group, out) in messages.chunks(LANES).zip(digests.chunks_mut(LANES)) {
+                        // Lanes without a message hash the first one again.
+                        for (lane, buf) in padded.chunks_mut(lane_len).enumerate() {
+                            pad(group.get(lane).unwrap_or(&group[0]), buf);
+                        }
+
+                        let mut state = [[0u32; LANES]; 8];
+                        for (words, iv) in state.iter_mut().zip(IV.iter()) {
+                            *words = [*iv; LANES];
+                        }
+
+                        for b in 0..blocks {
+                            for (t, words) in block.iter_mut().enumerate() {
+                                for (lane, word) in words.iter_mut().enumerate() {
+                                    let start = lane * lane_len + b * 64 + t * 4;
+                                    let mut bytes = [0u8; 4];
+                                    bytes.copy_from_slice(&padded[start..start + 4]);
+                                    *word = u32::from_be_bytes(bytes);
+                                }
+                            }
+                            compress(&mut state, &block);
+                        }
+
+                        for (lane, digest) in out.iter_mut().enumerate() {
+                            for (i, words) in state.iter().enumerate() {
+                                digest[i * 4..(i + 1) * 4]
+                                    .copy_from_slice(&words[lane].to_be_bytes());
+                            }
+                        }
+                    }
+                }
+            }
+        };
+    }
+
+    impl_lanes!(
+        sse2,
+        "sse2",
+        4,
+        __m128i,
+        _mm_loadu_si128,
+        _mm_storeu_si128,
+        _mm_set1_epi32,
+        _mm_add_epi32,
+        _mm_and_si128,
+        _mm_andnot_si128,
+        _mm_or_si128,
+        _mm_xor_si128,
+        _mm_srli_epi32,
+        _mm_slli_epi32
+    );
+
+    impl_lanes!(
+        avx2,
+        "avx2",
+        8,
+        __m256i,
+        _mm256_loadu_si256,
+        _mm256_storeu_si256,
+        _mm256_set1_epi32,
+        _mm256_add_epi32,
+        _mm256_and_si256,
+        _mm256_andnot_si256,
+        _mm256_or_si256,
+        _mm256_xor_si256,
+        _mm256_srli_epi32,
+        _mm256_slli_epi32
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::RngCore;
+
+    fn check_backend(backend: Sha256Backend) {
+        if !backend.is_available() {
+            return;
+        }
+
+        let mut rng = rand::thread_rng();
+        for len in &[0, 1, 31, 55, 56, 63, 64, 65, 119, 128, 1248] {
+            for count in &[1, 3, 4, 8, 13] {
+                let messages: Vec<Vec<u8>> = (0..*count)
+                    .map(|_| {
+                        let mut message = vec![0u8; *len];
+                        rng.fill_bytes(&mut message);
+                        message
+                    })
+                    .collect();
+                let refs: Vec<&[u8]> = messages.iter().map(|m| &m[..]).collect();
+
+                let mut digests = vec![[0u8; 32]; *count];
+                sha256_many_with(backend, &refs, &mut digests);
+
+                for (message, digest) in messages.iter().zip(digests.iter()) {
+                    assert_eq!(
+                        &Sha256::digest(message)[..],
+                        &digest[..],
+                        "{:?}, {} bytes",
+                        backend,
+                        len
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sha256_many_scalar() {
+        check_backend(Sha256Backend::Scalar);
+    }
+
+    #[test]
+    fn sha256_many_sse2() {
+        check_backend(Sha256Backend::Sse2);
+    }
+
+    #[test]
+    fn sha256_many_avx2() {
+        check_backend(Sha256Backend::Avx2);
+    }
+
+    #[test]
+    #[should_panic(expected = "same length")]
+    fn sha256_many_rejects_mixed_lengths() {
+        let mut digests = [[0u8; 32]; 2];
+        sha256_many(&[&[0u8; 32], &[0u8; 33]], &mut digests);
+    }
+}
@@ -1,10 +1,12 @@
 use std::cmp::{max, min};
 use std::marker::PhantomData;
+use std::ops::Range;
 
 use anyhow::ensure;
 use generic_array::typenum;
 use rand::{Rng, SeedableRng};
-use rand_chacha::ChaCha8Rng;
+use rand_chacha::{ChaCha20Rng, ChaCha8Rng};
+use rayon::prelude::*;
 use sha2::{Digest, Sha256};
 
 use crate::crypto::{derive_porep_domain_seed, DRSAMPLE_DST};
@@ -45,6 +47,19 @@ pub trait Graph<H: Hasher>: ::std::fmt::Debug + Clone + PartialEq + Eq {
     /// reasons, so that the vector can be allocated outside this call.
     fn parents(&self, node: usize, parents: &mut [u32]) -> Result<()>;
 
+    /// Writes the parents of every node of `start..end` into `parents`, `self.degree()`
+    /// entries per node, as `parents` would.
+    fn parents_range(&self, start: usize, end: usize, parents: &mut [u32]) -> Result<()> {
+        let degree = self.degree();
+        check_parents_range(start, end, self.size(), degree, parents)?;
+
+        for (node, node_parents) in (start..end).zip(parents.chunks_exact_mut(degree)) {
+            self.parents(node, node_parents)?;
+        }
+
+        Ok(())
+    }
+
     /// Returns the size of the graph (number of nodes).
     fn size(&self) -> usize;
 
@@ -71,6 +86,80 @@ pub trait Graph<H: Hasher>: ::std::fmt::Debug + Clone + PartialEq + Eq {
     ) -> Result<Self::Key>;
 }
 
+/// The parents of consecutive nodes, as yielded by `par_parents`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParentsBlock {
+    /// The first node of the block.
+    pub start: usize,
+    /// The parents of the nodes of the block, `degree` per node.
+    pub parents: Vec<u32>,
+}
+
+impl ParentsBlock {
+    /// Number of nodes of the block.
+    pub fn num_nodes(&self, degree: usize) -> usize {
+        self.parents.len() / degree
+    }
+
+    /// The parents of `node`, which must be in the block.
+    pub fn node_parents(&self, node: usize, degree: usize) -> &[u32] {
+        let offset = (node - self.start) * degree;
+        &self.parents[offset..offset + degree]
+    }
+}
+
+/// Yields the parents of the nodes of `range` in blocks of `block_size` nodes, the
+/// last one possibly shorter, generated in parallel. Blocks come in order when the
+/// iterator is collected.
+pub fn par_parents<'a, H, G>(
+    graph: &'a G,
+    range: Range<usize>,
+    block_size: usize,
+) -> impl IndexedParallelIterator<Item = Result<ParentsBlock>> + 'a
+where
+    H: Hasher,
+    G: Graph<H> + Sync,
+{
+    assert!(block_size > 0, "block size must not be zero");
+    let nodes = range.end.saturating_sub(range.start);
+    let blocks = nodes / block_size + (nodes % block_size != 0) as usize;
+
+    (0..blocks).into_par_iter().map(move |block| {
+        let start = range.start + block * block_size;
+        let end = min(start + block_size, range.end);
+        let mut parents = vec![0; (end - start) * graph.degree()];
+        graph.parents_range(start, end, &mut parents)?;
+
+        Ok(ParentsBlock { start, parents })
+    })
+}
+
+/// Checks the arguments of `Graph::parents_range`.
+pub fn check_parents_range(
+    start: usize,
+    end: usize,
+    size: usize,
+    degree: usize,
+    parents: &[u32],
+) -> Result<()> {
+    ensure!(
+        start <= end && end <= size,
+        "invalid range {}..{} of a graph of {} nodes",
+        start,
+        end,
+        size
+    );
+    ensure!(
+        parents.len() == (end - start) * degree,
+        "{} parents for {} nodes of degree {}",
+        parents.len(),
+        end - start,
+        degree
+    );
+
+    Ok(())
+}
+
 pub fn graph_height<U: typenum::Unsigned>(number_of_leafs: usize) -> usize {
     merkletree::merkle::get_merkle_tree_row_count(number_of_leafs, U::to_usize())
 }
@@ -111,19 +200,7 @@ impl<H: Hasher> Graph<H> for BucketGraph<H> {
         base_parents_data: &[u8],
         _exp_parents_data: Option<&[u8]>,
     ) -> Result<Self::Key> {
-        let mut hasher = Sha256::new();
-        hasher.input(AsRef::<[u8]>::as_ref(id));
-
-        // The hash is about the parents, hence skip if a node doesn't have any parents
-        if node != parents[0] as usize {
-            for parent in parents.iter() {
-                let offset = data_at_node_offset(*parent as usize);
-                hasher.input(&base_parents_data[offset..offset + NODE_SIZE]);
-            }
-        }
-
-        let hash = hasher.result();
-        Ok(bytes_into_fr_repr_safe(hash.as_ref()).into())
+        Ok(create_drg_key::<H>(id, node, parents, base_parents_data))
     }
 
     #[inline]
@@ -145,41 +222,13 @@ impl<H: Hasher> Graph<H> for BucketGraph<H> {
                 // DRG node indexes are guaranteed to fit within a `u32`.
                 let node = node as u32;
 
-                let mut seed = [0u8; 32];
-                seed[..28].copy_from_slice(&self.seed);
-                seed[28..].copy_from_slice(&node.to_le_bytes());
-                let mut rng = ChaCha8Rng::from_seed(seed);
-
-                let m_prime = m - 1;
-                // Large sector sizes require that metagraph node indexes are `u64`.
-                let metagraph_node = node as u64 * m_prime as u64;
-                let n_buckets = (metagraph_node as f64).log2().ceil() as u64;
-
-                for parent in parents.iter_mut().take(m_prime) {
-                    let bucket_index = (rng.gen::<u64>() % n_buckets) + 1;
-                    let largest_distance_in_bucket = min(metagraph_node, 1 << bucket_index);
-                    let smallest_distance_in_bucket = max(2, largest_distance_in_bucket >> 1);
-
-                    // Add 1 becuase the number of distances in the bucket is inclusive.
-                    let n_distances_in_bucket =
-                        largest_distance_in_bucket - smallest_distance_in_bucket + 1;
-
-                    let distance =
-                        smallest_distance_in_bucket + (rng.gen::<u64>() % n_distances_in_bucket);
+                // The rng is keyed by the node index, so no ChaCha state can be
+                // carried over from one node to the next, and `parents_range`
+                // keeps the default loop over `parents`.
+                let mut rng = ChaCha8Rng::from_seed(node_seed(&self.seed, node));
+                bucket_sample(&mut rng, node, &mut parents[..m - 1]);
 
-                    let metagraph_parent = metagraph_node - distance;
-
-                    // Any metagraph node mapped onto the DRG can be safely cast back to `u32`.
-                    let mapped_parent = (metagraph_parent / m_prime as u64) as u32;
-
-                    *parent = if mapped_parent == node {
-                        node - 1
-                    } else {
-                        mapped_parent
-                    };
-                }
-
-                parents[m_prime] = node - 1;
+                parents[m - 1] = node - 1;
                 Ok(())
             }
         }
@@ -206,17 +255,7 @@ impl<H: Hasher> Graph<H> for BucketGraph<H> {
         expansion_degree: usize,
         porep_id: [u8; 32],
     ) -> Result<Self> {
-        ensure!(expansion_degree == 0, "Expension degree must be zero.");
-
-        // The number of metagraph nodes must be less than `2u64^54` as to not incur rounding errors
-        // when casting metagraph node indexes from `u64` to `f64` during parent generation.
-        let m_prime = base_degree - 1;
-        let n_metagraph_nodes = nodes as u64 * m_prime as u64;
-        ensure!(
-            n_metagraph_nodes <= 1u64 << 54,
-            "The number of metagraph nodes must be precisely castable to `f64`"
-        );
-
+        check_graph_params(nodes, base_degree, expansion_degree)?;
         let drg_seed = derive_drg_seed(porep_id);
 
         Ok(BucketGraph {
@@ -234,3 +273,350 @@ pub fn derive_drg_seed(porep_id: [u8; 32]) -> [u8; 28] {
     drg_seed.copy_from_slice(&raw_seed[..28]);
     drg_seed
 }
+
+/// Seed of the rng sampling the parents of `node`.
+fn node_seed(seed: &[u8; 28], node: u32) -> [u8; 32] {
+    let mut node_seed = [0u8; 32];
+    node_seed[..28].copy_from_slice(seed);
+    node_seed[28..].copy_from_slice(&node.to_le_bytes());
+    node_seed
+}
+
+/// Bucket sampling of the random parents of `node`, one per entry of `parents`, in the
+/// metagraph where every node is split in `parents.len()` nodes.
+fn bucket_sample<R: Rng>(rng: &mut R, node: u32, parents: &mut [u32]) {
+    let m_prime = parents.len();
+    // Large sector sizes require that metagraph node indexes are `u64`.
+    let metagraph_node = node as u64 * m_prime as u64;
+    let n_buckets = (metagraph_node as f64).log2().ceil() as u64;
+
+    for parent in parents.iter_mut() {
+        let bucket_index = (rng.gen::<u64>() % n_buckets) + 1;
+        let largest_distance_in_bucket = min(metagraph_node, 1 << bucket_index);
+        let smallest_distance_in_bucket = max(2, largest_distance_in_bucket >> 1);
+
+        // Add 1 becuase the number of distances in the bucket is inclusive.
+        let n_distances_in_bucket = largest_distance_in_bucket - smallest_distance_in_bucket + 1;
+
+        let distance = smallest_distance_in_bucket + (rng.gen::<u64>() % n_distances_in_bucket);
+
+        let metagraph_parent = metagraph_node - distance;
+
+        // Any metagraph node mapped onto the DRG can be safely cast back to `u32`.
+        let mapped_parent = (metagraph_parent / m_prime as u64) as u32;
+
+        *parent = if mapped_parent == node {
+            node - 1
+        } else {
+            mapped_parent
+        };
+    }
+}
+
+fn check_graph_params(nodes: usize, base_degree: usize, expansion_degree: usize) -> Result<()> {
+    ensure!(expansion_degree == 0, "Expension degree must be zero.");
+    ensure!(base_degree >= 2, "The base degree must be at least 2.");
+
+    // The number of metagraph nodes must be less than `2u64^54` as to not incur rounding errors
+    // when casting metagraph node indexes from `u64` to `f64` during parent generation.
+    let m_prime = base_degree - 1;
+    let n_metagraph_nodes = nodes as u64 * m_prime as u64;
+    ensure!(
+        n_metagraph_nodes <= 1u64 << 54,
+        "The number of metagraph nodes must be precisely castable to `f64`"
+    );
+
+    Ok(())
+}
+
+/// The key of `node` in a DRG: `Sha256(id | encodedParentNode1 | encodedParentNode1 | ...)`.
+fn create_drg_key<H: Hasher>(
+    id: &H::Domain,
+    node: usize,
+    parents: &[u32],
+    base_parents_data: &[u8],
+) -> H::Domain {
+    let mut hasher = Sha256::new();
+    hasher.input(AsRef::<[u8]>::as_ref(id));
+
+    // The hash is about the parents, hence skip if a node doesn't have any parents
+    if node != parents[0] as usize {
+        for parent in parents.iter() {
+            let offset = data_at_node_offset(*parent as usize);
+            hasher.input(&base_parents_data[offset..offset + NODE_SIZE]);
+        }
+    }
+
+    let hash = hasher.result();
+    bytes_into_fr_repr_safe(hash.as_ref()).into()
+}
+
+/// Algorithm sampling the random parents of the nodes of a `SampledGraph`.
+pub trait ParentSampler:
+    std::fmt::Debug + Clone + Copy + PartialEq + Eq + Send + Sync + 'static
+{
+    /// Name of the graph type, part of its identifier.
+    const NAME: &'static str;
+
+    /// Samples the random parents of `node`, at least 2, one per entry of `parents`.
+    fn sample(seed: &[u8; 28], node: u32, parents: &mut [u32]);
+}
+
+/// Original DRSample: for every parent, a bucket `i` of distances is picked uniformly
+/// in `[1, log2(node)]`, then a distance uniformly in `[max(2, 2^(i-1)), min(node, 2^i)]`,
+/// directly on the graph rather than on a metagraph as `BucketGraph` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrSample;
+
+impl ParentSampler for DrSample {
+    const NAME: &'static str = "DrSampleGraph";
+
+    fn sample(seed: &[u8; 28], node: u32, parents: &mut [u32]) {
+        let mut rng = ChaCha8Rng::from_seed(node_seed(seed, node));
+        let n_buckets = (node as f64).log2().floor() as u64;
+
+        for parent in parents.iter_mut() {
+            let bucket_index = (rng.gen::<u64>() % n_buckets) + 1;
+            let largest_distance_in_bucket = min(node as u64, 1 << bucket_index);
+            let smallest_distance_in_bucket = max(2, largest_distance_in_bucket >> 1);
+            let n_distances_in_bucket =
+                largest_distance_in_bucket - smallest_distance_in_bucket + 1;
+
+            let distance = smallest_distance_in_bucket + (rng.gen::<u64>() % n_distances_in_bucket);
+            *parent = (node as u64 - distance) as u32;
+        }
+    }
+}
+
+/// The bucket sampling of `BucketGraph`, with parents drawn from ChaCha20 instead of
+/// ChaCha8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChaCha20Bucket;
+
+impl ParentSampler for ChaCha20Bucket {
+    const NAME: &'static str = "ChaCha20BucketGraph";
+
+    fn sample(seed: &[u8; 28], node: u32, parents: &mut [u32]) {
+        let mut rng = ChaCha20Rng::from_seed(node_seed(seed, node));
+        bucket_sample(&mut rng, node, parents);
+    }
+}
+
+/// Baseline with no depth robustness guarantee: every parent is a predecessor picked
+/// uniformly at random.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UniformPredecessor;
+
+impl ParentSampler for UniformPredecessor {
+    const NAME: &'static str = "UniformGraph";
+
+    fn sample(seed: &[u8; 28], node: u32, parents: &mut [u32]) {
+        let mut rng = ChaCha8Rng::from_seed(node_seed(seed, node));
+        for parent in parents.iter_mut() {
+            *parent = rng.gen_range(0, node);
+        }
+    }
+}
+
+/// A DRG with the parents of every node sampled by `S`. Like in `BucketGraph`, the
+/// first node references itself, the second the first, and the last parent of any
+/// other node is its immediate predecessor.
+#[derive(Clone, Debug, PartialEq, Eq, Copy)]
+pub struct SampledGraph<H: Hasher, S: ParentSampler> {
+    nodes: usize,
+    base_degree: usize,
+    seed: [u8; 28],
+    _h: PhantomData<H>,
+    _s: PhantomData<S>,
+}
+
+pub type DrSampleGraph<H> = SampledGraph<H, DrSample>;
+pub type ChaCha20BucketGraph<H> = SampledGraph<H, ChaCha20Bucket>;
+pub type UniformGraph<H> = SampledGraph<H, UniformPredecessor>;
+
+impl<H: Hasher, S: ParentSampler> ParameterSetMetadata for SampledGraph<H, S> {
+    fn identifier(&self) -> String {
+        // NOTE: Seed is not included because it does not influence parameter generation.
+        format!(
+            "drgraph::{}{{size: {}; degree: {}; hasher: {}}}",
+            S::NAME,
+            self.nodes,
+            self.degree(),
+            H::name(),
+        )
+    }
+
+    fn sector_size(&self) -> u64 {
+        (self.nodes * NODE_SIZE) as u64
+    }
+}
+
+impl<H: Hasher, S: ParentSampler> Graph<H> for SampledGraph<H, S> {
+    type Key = H::Domain;
+
+    fn create_key(
+        &self,
+        id: &H::Domain,
+        node: usize,
+        parents: &[u32],
+        base_parents_data: &[u8],
+        _exp_parents_data: Option<&[u8]>,
+    ) -> Result<Self::Key> {
+        Ok(create_drg_key::<H>(id, node, parents, base_parents_data))
+    }
+
+    #[inline]
+    fn parents(&self, node: usize, parents: &mut [u32]) -> Result<()> {
+        let m = self.degree();
+
+        match node {
+            0 | 1 => {
+                for parent in parents.iter_mut().take(m) {
+                    *parent = 0;
+                }
+            }
+            _ => {
+                // DRG node indexes are guaranteed to fit within a `u32`.
+                let node = node as u32;
+                S::sample(&self.seed, node, &mut parents[..m - 1]);
+                parents[m - 1] = node - 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn size(&self) -> usize {
+        self.nodes
+    }
+
+    #[inline]
+    fn degree(&self) -> usize {
+        self.base_degree
+    }
+
+    fn seed(&self) -> [u8; 28] {
+        self.seed
+    }
+
+    fn new(
+        nodes: usize,
+        base_degree: usize,
+        expansion_degree: usize,
+        porep_id: [u8; 32],
+    ) -> Result<Self> {
+        check_graph_params(nodes, base_degree, expansion_degree)?;
+
+        Ok(SampledGraph {
+            nodes,
+            base_degree,
+            seed: derive_drg_seed(porep_id),
+            _h: PhantomData,
+            _s: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::hasher::Sha256Hasher;
+
+    fn check_parents<G: Graph<Sha256Hasher>>(graph: &G) {
+        let mut parents = vec![0u32; BASE_DEGREE];
+        let mut again = parents.clone();
+        for node in 2..graph.size() {
+            graph.parents(node, &mut parents).unwrap();
+            assert!(parents.iter().all(|parent| (*parent as usize) < node));
+            assert_eq!(parents[BASE_DEGREE - 1] as usize, node - 1);
+
+            graph.parents(node, &mut again).unwrap();
+            assert_eq!(parents, again);
+        }
+
+        graph.parents(1, &mut parents).unwrap();
+        assert!(parents.iter().all(|parent| *parent == 0));
+    }
+
+    #[test]
+    fn sampled_graphs_have_valid_parents() {
+        let nodes = 1024;
+        let porep_id = [7u8; 32];
+
+        check_parents(&BucketGraph::<Sha256Hasher>::new(nodes, BASE_DEGREE, 0, porep_id).unwrap());
+        check_parents(
+            &DrSampleGraph::<Sha256Hasher>::new(nodes, BASE_DEGREE, 0, porep_id).unwrap(),
+        );
+        check_parents(
+            &ChaCha20BucketGraph::<Sha256Hasher>::new(nodes, BASE_DEGREE, 0, porep_id).unwrap(),
+        );
+        check_parents(&UniformGraph::<Sha256Hasher>::new(nodes, BASE_DEGREE, 0, porep_id).unwrap());
+    }
+
+    fn check_parents_blocks<G: Graph<Sha256Hasher> + Sync>(graph: &G) {
+        let degree = graph.degree();
+        let mut expected = vec![0u32; graph.size() * degree];
+        for (node, node_parents) in expected.chunks_exact_mut(degree).enumerate() {
+            graph.parents(node, node_parents).unwrap();
+        }
+
+        let mut range = vec![0u32; (graph.size() - 3) * degree];
+        graph.parents_range(3, graph.size(), &mut range).unwrap();
+        assert_eq!(&range[..], &expected[3 * degree..]);
+        assert!(graph.parents_range(3, 2, &mut []).is_err());
+        assert!(graph.parents_range(0, 2, &mut range).is_err());
+
+        let blocks = par_parents::<Sha256Hasher, _>(graph, 1..graph.size(), 100)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(blocks.len(), 10);
+        let mut node = 1;
+        for block in &blocks {
+            assert_eq!(block.start, node);
+            for n in node..node + block.num_nodes(degree) {
+                assert_eq!(
+                    block.node_parents(n, degree),
+                    &expected[n * degree..(n + 1) * degree]
+                );
+            }
+            node += block.num_nodes(degree);
+        }
+        assert_eq!(node, graph.size());
+    }
+
+    #[test]
+    fn parents_in_blocks() {
+        check_parents_blocks(
+            &BucketGraph::<Sha256Hasher>::new(1000, BASE_DEGREE, 0, [2; 32]).unwrap(),
+        );
+        check_parents_blocks(
+            &UniformGraph::<Sha256Hasher>::new(1000, BASE_DEGREE, 0, [2; 32]).unwrap(),
+        );
+    }
+
+    #[test]
+    fn sampled_graph_identifiers_are_distinct() {
+        let identifiers = vec![
+            BucketGraph::<Sha256Hasher>::new(64, BASE_DEGREE, 0, [0; 32])
+                .unwrap()
+                .identifier(),
+            DrSampleGraph::<Sha256Hasher>::new(64, BASE_DEGREE, 0, [0; 32])
+                .unwrap()
+                .identifier(),
+            ChaCha20BucketGraph::<Sha256Hasher>::new(64, BASE_DEGREE, 0, [0; 32])
+                .unwrap()
+                .identifier(),
+            UniformGraph::<Sha256Hasher>::new(64, BASE_DEGREE, 0, [0; 32])
+                .unwrap()
+                .identifier(),
+        ];
+
+        for (i, a) in identifiers.iter().enumerate() {
+            for b in &identifiers[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 pub use anyhow::Result;
 
 #[derive(Debug, thiserror::Error)]
@@ -6,4 +8,13 @@ pub enum Error {
     BadFrBytes,
     #[error("invalid input size")]
     InvalidInputSize,
-}
\ No newline at end of file
+    #[error("missing {key} cache file {path:?}")]
+    MissingCacheFile { key: String, path: PathBuf },
+    #[error("truncated {key} cache file {path:?}: expected {expected} bytes, found {found}")]
+    TruncatedCacheFile {
+        key: String,
+        path: PathBuf,
+        expected: u64,
+        found: u64,
+    },
+}
@@ -0,0 +1,484 @@
+//! Circuit for the Feistel permutation of `crypto::feistel`, with Blake2s or
+//! SHA-256 in the round function.
+//!
+//! The graphs permute with Blake2b, which has no gadget, so this checks the
+//! `feistel::permute_with` variant over one of those two hashes, not the
+//! `feistel::permute` the graphs use.
+
+use bellperson::gadgets::{
+    blake2s::blake2s as blake2s_circuit,
+    boolean::{AllocatedBit, Boolean},
+    num::AllocatedNum,
+    sha256::sha256 as sha256_circuit,
+};
+use bellperson::{ConstraintSystem, SynthesisError};
+use ff::{Field, PrimeField, ScalarEngine};
+use proof_crypto_feistel::{Round, RoundFunction};
+
+use crate::crypto::feistel::{self, FeistelPrecomputed, Index, FEISTEL_ROUNDS};
+use crate::gadgets::multipack;
+
+/// Hash function of the round function inside the circuit. There is no
+/// Blake2b gadget, so only the hashes bellperson has gadgets for are offered.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FeistelCircuitHash {
+    Blake2s,
+    Sha256,
+}
+
+impl From<FeistelCircuitHash> for RoundFunction {
+    fn from(hash: FeistelCircuitHash) -> Self {
+        match hash {
+            FeistelCircuitHash::Blake2s => RoundFunction::Blake2s,
+            FeistelCircuitHash::Sha256 => RoundFunction::Sha256,
+        }
+    }
+}
+
+/// Outputs of the `permute` gadget.
+pub struct PermutedIndex<E: ScalarEngine> {
+    /// The permuted index.
+    pub index: AllocatedNum<E>,
+    /// Number of passes of the network the cycle-walking took, from 1 to the
+    /// maximum the circuit was built for.
+    pub iterations: AllocatedNum<E>,
+}
+
+/// Enforces that the output is `feistel::permute_with(hash, num_elements, index, keys,
+/// precomputed)`, for public `keys` and `num_elements`.
+///
+/// The circuit always computes `max_iterations` passes of the network. The number
+/// of passes the cycle-walking actually takes is a witness, and the output is the
+/// result of that pass, which must be the first one to land in `[0, num_elements)`.
+/// Synthesis fails with `Unsatisfiable` if `index` needs more passes.
+#[allow(clippy::too_many_arguments)]
+pub fn permute<E, CS>(
+    mut cs: CS,
+    hash: FeistelCircuitHash,
+    num_elements: Index,
+    index: &AllocatedNum<E>,
+    keys: &[Index],
+    precomputed: FeistelPrecomputed,
+    max_iterations: usize,
+) -> Result<PermutedIndex<E>, SynthesisError>
+where
+    E: ScalarEngine,
+    CS: ConstraintSystem<E>,
+{
+    let (_, _, half_bits) = precomputed;
+    let bits = 2 * half_bits as usize;
+    if max_iterations == 0 || num_elements == 0 {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+
+    // Compute the witnesses natively: the value of the index and the number
+    // of passes of the cycle-walking.
+    let index_value = index.get_value().map(fr_into_index).transpose()?;
+    let iterations_value = match index_value {
+        Some(index) => {
+            let round = Round::new(hash.into());
+            let mut u = feistel::encode_with(&round, index, keys, precomputed);
+            let mut iterations = 1;
+            while u >= num_elements {
+                u = feistel::encode_with(&round, u, keys, precomputed);
+                iterations += 1;
+            }
+            if iterations > max_iterations {
+                return Err(SynthesisError::Unsatisfiable);
+            }
+            Some(iterations)
+        }
+        None => None,
+    };
+
+    let index_bits = alloc_bits(cs.namespace(|| "index_bits"), index_value, bits)?;
+    let packed = multipack::pack_bits(cs.namespace(|| "index_packed"), &index_bits)?;
+    // packed * 1 = index
+    cs.enforce(
+        || "index_bits_constraint",
+        |lc| lc + packed.get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + index.get_variable(),
+    );
+    let index_in_range = less_than(cs.namespace(|| "index_in_range"), &index_bits, num_elements)?;
+    Boolean::enforce_equal(
+        cs.namespace(|| "index_in_range_constraint"),
+        &index_in_range,
+        &Boolean::constant(true),
+    )?;
+
+    // One selector per pass, set for the pass the walking stopped at.
+    let selectors = (0..max_iterations)
+        .map(|i| {
+            AllocatedBit::alloc(
+                cs.namespace(|| format!("selector_{}", i)),
+                iterations_value.map(|iterations| iterations == i + 1),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let iterations = AllocatedNum::alloc(cs.namespace(|| "iterations"), || {
+        iterations_value
+            .map(|iterations| index_into_fr::<E>(iterations as Index))
+            .ok_or(SynthesisError::AssignmentMissing)
+    })?;
+    // (sum selectors) * 1 = 1, (sum (i + 1) * selectors) * 1 = iterations
+    cs.enforce(
+        || "one_selector",
+        |lc| {
+            selectors
+                .iter()
+                .fold(lc, |lc, selector| lc + selector.get_variable())
+        },
+        |lc| lc + CS::one(),
+        |lc| lc + CS::one(),
+    );
+    cs.enforce(
+        || "iterations_constraint",
+        |lc| {
+            selectors.iter().enumerate().fold(lc, |lc, (i, selector)| {
+                lc + (index_into_fr::<E>(i as Index + 1), selector.get_variable())
+            })
+        },
+        |lc| lc + CS::one(),
+        |lc| lc + iterations.get_variable(),
+    );
+
+    let mut u = index_bits;
+    // Set while every pass so far landed outside of the range.
+    let mut walking = Boolean::constant(true);
+    let mut selected = Vec::with_capacity(max_iterations);
+    for (i, selector) in selectors.iter().enumerate() {
+        let mut cs = cs.namespace(|| format!("iteration_{}", i));
+
+        u = encode(
+            cs.namespace(|| "encode"),
+            hash,
+            &u,
+            keys,
+            half_bits as usize,
+        )?;
+        let in_range = less_than(cs.namespace(|| "in_range"), &u, num_elements)?;
+
+        // The selected pass must be in range and the first one to be.
+        let selector = Boolean::from(selector.clone());
+        let first_in_range = Boolean::and(cs.namespace(|| "first_in_range"), &walking, &in_range)?;
+        Boolean::enforce_equal(
+            cs.namespace(|| "selector_constraint"),
+            &selector,
+            &first_in_range,
+        )?;
+        walking = Boolean::and(cs.namespace(|| "walking"), &walking, &in_range.not())?;
+
+        let packed = multipack::pack_bits(cs.namespace(|| "packed"), &u)?;
+        let value = AllocatedNum::alloc(cs.namespace(|| "selected"), || {
+            if selector
+                .get_value()
+                .ok_or(SynthesisError::AssignmentMissing)?
+            {
+                packed.get_value().ok_or(SynthesisError::AssignmentMissing)
+            } else {
+                Ok(E::Fr::zero())
+            }
+        })?;
+        // packed * selector = value
+        cs.enforce(
+            || "selected_constraint",
+            |lc| lc + packed.get_variable(),
+            |_| selector.lc(CS::one(), E::Fr::one()),
+            |lc| lc + value.get_variable(),
+        );
+        selected.push(value);
+    }
+
+    let permuted = AllocatedNum::alloc(cs.namespace(|| "permuted"), || {
+        selected.iter().try_fold(E::Fr::zero(), |mut acc, value| {
+            acc.add_assign(&value.get_value().ok_or(SynthesisError::AssignmentMissing)?);
+            Ok(acc)
+        })
+    })?;
+    // (sum selected) * 1 = permuted
+    cs.enforce(
+        || "permuted_constraint",
+        |lc| {
+            selected
+                .iter()
+                .fold(lc, |lc, value| lc + value.get_variable())
+        },
+        |lc| lc + CS::one(),
+        |lc| lc + permuted.get_variable(),
+    );
+
+    Ok(PermutedIndex {
+        index: permuted,
+        iterations,
+    })
+}
+
+/// One pass of the network over the bits of an index, least significant bit
+/// first, as `feistel::encode_with`.
+fn encode<E, CS>(
+    mut cs: CS,
+    hash: FeistelCircuitHash,
+    index: &[Boolean],
+    keys: &[Index],
+    half_bits: usize,
+) -> Result<Vec<Boolean>, SynthesisError>
+where
+    E: ScalarEngine,
+    CS: ConstraintSystem<E>,
+{
+    // Masking with `right_mask` and shifting by `half_bits` only splits the bits.
+    let mut right = index[..half_bits].to_vec();
+    let mut left = index[half_bits..].to_vec();
+
+    for (round, key) in keys.iter().take(FEISTEL_ROUNDS).enumerate() {
+        let mut cs = cs.namespace(|| format!("round_{}", round));
+
+        let f = round_function(cs.namespace(|| "round_function"), hash, &right, *key)?;
+        let next_right = left
+            .iter()
+            .zip(f.iter())
+            .enumerate()
+            .map(|(i, (l, f))| Boolean::xor(cs.namespace(|| format!("xor_{}", i)), l, f))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        left = right;
+        right = next_right;
+    }
+
+    right.extend(left);
+    Ok(right)
+}
+
+/// The round function `F(Ri, Ki)`, as `half_bits` bits, least significant first.
+/// The hashed message is `right` then `key`, both as big-endian `u64`s, and the
+/// output is read from the first 8 bytes of the hash, big-endian.
+fn round_function<E, CS>(
+    mut cs: CS,
+    hash: FeistelCircuitHash,
+    right: &[Boolean],
+    key: Index,
+) -> Result<Vec<Boolean>, SynthesisError>
+where
+    E: ScalarEngine,
+    CS: ConstraintSystem<E>,
+{
+    let half_bits = right.len();
+
+    // The bits of every byte of the message, least significant first.
+    let mut bytes = Vec::with_capacity(16);
+    for byte in 0..8 {
+        bytes.push(
+            (0..8)
+                .map(|bit| {
+                    let i = 8 * (7 - byte) + bit;
+                    right
+                        .get(i)
+                        .cloned()
+                        .unwrap_or_else(|| Boolean::constant(false))
+                })
+                .collect::<Vec<_>>(),
+        );
+    }
+    for byte in key.to_be_bytes().iter() {
+        bytes.push(
+            (0..8)
+                .map(|bit| Boolean::constant((byte >> bit) & 1 == 1))
+                .collect(),
+        );
+    }
+
+    // Blake2s takes and returns the bits of every byte least significant
+    // first, SHA-256 most significant first.
+    let bit_index = |byte: usize, bit: usize| match hash {
+        FeistelCircuitHash::Blake2s => 8 * byte + bit,
+        FeistelCircuitHash::Sha256 => 8 * byte + 7 - bit,
+    };
+
+    let mut message = vec![Boolean::constant(false); 8 * bytes.len()];
+    for (byte, bits) in bytes.into_iter().enumerate() {
+        for (bit, value) in bits.into_iter().enumerate() {
+            message[bit_index(byte, bit)] = value;
+        }
+    }
+
+    let digest = match hash {
+        FeistelCircuitHash::Blake2s => {
+            blake2s_circuit(cs.namespace(|| "blake2s"), &message, &[0; 8])?
+        }
+        FeistelCircuitHash::Sha256 => sha256_circuit(cs.namespace(|| "sha256"), &message)?,
+    };
+
+    Ok((0..half_bits)
+        .map(|i| digest[bit_index(7 - i / 8, i % 8)].clone())
+        .collect())
+}
+
+/// Whether the number with the given bits, least significant first, is below
+/// the constant `bound`.
+fn less_than<E, CS>(mut cs: CS, bits: &[Boolean], bound: Index) -> Result<Boolean, SynthesisError>
+where
+    E: ScalarEngine,
+    CS: ConstraintSystem<E>,
+{
+    if bits.len() < 64 && bound >> bits.len() != 0 {
+        return Ok(Boolean::constant(true));
+    }
+
+    // Walk from the most significant bit, tracking whether the bits so far are
+    // equal to those of `bound`, or already below.
+    let mut equal = Boolean::constant(true);
+    let mut less = Boolean::constant(false);
+    for (i, bit) in bits.iter().enumerate().rev() {
+        let mut cs = cs.namespace(|| format!("bit_{}", i));
+        if (bound >> i) & 1 == 1 {
+            let below = Boolean::and(cs.namespace(|| "below"), &equal, &bit.not())?;
+            // less | below = !(!less & !below)
+            less = Boolean::and(cs.namespace(|| "less"), &less.not(), &below.not())?.not();
+            equal = Boolean::and(cs.namespace(|| "equal"), &equal, bit)?;
+        } else {
+            equal = Boolean::and(cs.namespace(|| "equal"), &equal, &bit.not())?;
+        }
+    }
+
+    Ok(less)
+}
+
+/// Allocates the `count` bits of `value`, least significant first.
+fn alloc_bits<E, CS>(
+    mut cs: CS,
+    value: Option<Index>,
+    count: usize,
+) -> Result<Vec<Boolean>, SynthesisError>
+where
+    E: ScalarEngine,
+    CS: ConstraintSystem<E>,
+{
+    (0..count)
+        .map(|i| {
+            AllocatedBit::alloc(
+                cs.namespace(|| format!("bit_{}", i)),
+                value.map(|value| (value >> i) & 1 == 1),
+            )
+            .map(Boolean::from)
+        })
+        .collect()
+}
+
+fn index_into_fr<E: ScalarEngine>(index: Index) -> E::Fr {
+    E::Fr::from_repr(<E::Fr as PrimeField>::Repr::from(index)).expect("a u64 is in the field")
+}
+
+fn fr_into_index<F: PrimeField>(fr: F) -> Result<Index, SynthesisError> {
+    let repr = fr.into_repr();
+    let limbs = repr.as_ref();
+    if limbs[1..].iter().any(|limb| *limb != 0) {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+
+    Ok(limbs[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bellperson::gadgets::test::TestConstraintSystem;
+    use paired::bls12_381::{Bls12, Fr};
+
+    use crate::crypto::feistel::{permute_with, precompute};
+
+    const KEYS: &[Index] = &[1, 2, 3, 4];
+
+    fn check_permute(hash: FeistelCircuitHash, num_elements: Index, max_iterations: usize) {
+        let precomputed = precompute(num_elements);
+
+        for index in 0..num_elements {
+            let mut cs = TestConstraintSystem::<Bls12>::new();
+            let index_num = AllocatedNum::alloc(cs.namespace(|| "index"), || {
+                Ok(index_into_fr::<Bls12>(index))
+            })
+            .unwrap();
+
+            let expected = permute_with(hash.into(), num_elements, index, KEYS, precomputed);
+            match permute(
+                &mut cs,
+                hash,
+                num_elements,
+                &index_num,
+                KEYS,
+                precomputed,
+                max_iterations,
+            ) {
+                Ok(permuted) => {
+                    assert!(cs.is_satisfied(), "{:?}", cs.which_is_unsatisfied());
+                    assert_eq!(
+                        permuted.index.get_value(),
+                        Some(index_into_fr::<Bls12>(expected))
+                    );
+                }
+                // Only indices walking for longer than the bound may fail.
+                Err(SynthesisError::Unsatisfiable) => {
+                    let mut u = index;
+                    let mut iterations = 0;
+                    loop {
+                        u = feistel::encode_with(&Round::new(hash.into()), u, KEYS, precomputed);
+                        iterations += 1;
+                        if u < num_elements {
+                            break;
+                        }
+                    }
+                    assert!(iterations > max_iterations);
+                }
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn feistel_circuit_sha256() {
+        // 16 elements never walk, 10 do.
+        check_permute(FeistelCircuitHash::Sha256, 16, 1);
+        check_permute(FeistelCircuitHash::Sha256, 10, 3);
+    }
+
+    #[test]
+    fn feistel_circuit_blake2s() {
+        check_permute(FeistelCircuitHash::Blake2s, 16, 1);
+        check_permute(FeistelCircuitHash::Blake2s, 10, 3);
+    }
+
+    #[test]
+    fn feistel_circuit_rejects_wrong_iterations() {
+        let hash = FeistelCircuitHash::Sha256;
+        let num_elements = 10;
+        let precomputed = precompute(num_elements);
+        let round = Round::new(hash.into());
+
+        // Find an index that needs to walk, and claim it stopped after one pass.
+        let index = (0..num_elements)
+            .find(|index| feistel::encode_with(&round, *index, KEYS, precomputed) >= num_elements)
+            .expect("some index walks");
+
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let index_num = AllocatedNum::alloc(cs.namespace(|| "index"), || {
+            Ok(index_into_fr::<Bls12>(index))
+        })
+        .unwrap();
+        permute(
+            &mut cs,
+            hash,
+            num_elements,
+            &index_num,
+            KEYS,
+            precomputed,
+            4,
+        )
+        .unwrap();
+        assert!(cs.is_satisfied());
+
+        cs.set("selector_0/boolean", Fr::one());
+        assert!(!cs.is_satisfied());
+    }
+}
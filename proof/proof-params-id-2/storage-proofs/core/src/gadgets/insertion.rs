@@ -0,0 +1,167 @@
+use bellperson::gadgets::{boolean::Boolean, num::AllocatedNum};
+use bellperson::{ConstraintSystem, SynthesisError};
+use ff::{Field, ScalarEngine};
+
+/// Inserts `element` into `elements` at the position given by `bits`, least
+/// significant bit first. The result has `elements.len() + 1` entries, which
+/// must equal `2^bits.len()`.
+///
+/// This is how a node on a merkle path is placed among its siblings before
+/// hashing them into the parent.
+pub fn insert<E, CS>(
+    cs: &mut CS,
+    element: &AllocatedNum<E>,
+    bits: &[Boolean],
+    elements: &[AllocatedNum<E>],
+) -> Result<Vec<AllocatedNum<E>>, SynthesisError>
+where
+    E: ScalarEngine,
+    CS: ConstraintSystem<E>,
+{
+    let size = elements.len() + 1;
+    if size != 1 << bits.len() {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+
+    (0..size)
+        .map(|position| {
+            // The value at `position` for every possible insertion index.
+            let candidates = (0..size)
+                .map(|index| {
+                    if index == position {
+                        element.clone()
+                    } else if index > position {
+                        elements[position].clone()
+                    } else {
+                        elements[position - 1].clone()
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            pick(
+                cs.namespace(|| format!("position_{}", position)),
+                bits,
+                candidates,
+            )
+        })
+        .collect()
+}
+
+/// Selects `candidates[n]`, where `bits` represents `n`, least significant bit first.
+fn pick<E, CS>(
+    mut cs: CS,
+    bits: &[Boolean],
+    mut candidates: Vec<AllocatedNum<E>>,
+) -> Result<AllocatedNum<E>, SynthesisError>
+where
+    E: ScalarEngine,
+    CS: ConstraintSystem<E>,
+{
+    for (i, bit) in bits.iter().enumerate() {
+        candidates = candidates
+            .chunks(2)
+            .enumerate()
+            .map(|(j, pair)| {
+                // Equal candidates need no constraint.
+                if pair[0].get_variable() == pair[1].get_variable() {
+                    Ok(pair[0].clone())
+                } else {
+                    select(
+                        cs.namespace(|| format!("select_{}_{}", i, j)),
+                        &pair[0],
+                        &pair[1],
+                        bit,
+                    )
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+    }
+
+    Ok(candidates.remove(0))
+}
+
+/// Returns `b` if `condition` is true, `a` otherwise.
+pub fn select<E, CS>(
+    mut cs: CS,
+    a: &AllocatedNum<E>,
+    b: &AllocatedNum<E>,
+    condition: &Boolean,
+) -> Result<AllocatedNum<E>, SynthesisError>
+where
+    E: ScalarEngine,
+    CS: ConstraintSystem<E>,
+{
+    let result = AllocatedNum::alloc(cs.namespace(|| "select result"), || {
+        if condition
+            .get_value()
+            .ok_or_else(|| SynthesisError::AssignmentMissing)?
+        {
+            b.get_value()
+                .ok_or_else(|| SynthesisError::AssignmentMissing)
+        } else {
+            a.get_value()
+                .ok_or_else(|| SynthesisError::AssignmentMissing)
+        }
+    })?;
+
+    // (b - a) * condition = result - a
+    cs.enforce(
+        || "select constraint",
+        |lc| lc + b.get_variable() - a.get_variable(),
+        |_| condition.lc(CS::one(), E::Fr::one()),
+        |lc| lc + result.get_variable() - a.get_variable(),
+    );
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bellperson::gadgets::boolean::AllocatedBit;
+    use bellperson::gadgets::test::TestConstraintSystem;
+    use paired::bls12_381::{Bls12, Fr};
+
+    #[test]
+    fn insert_at_every_position() {
+        let mut rng = rand::thread_rng();
+        for size_log in 1..4 {
+            let size = 1 << size_log;
+            let values: Vec<Fr> = (0..size).map(|_| Fr::random(&mut rng)).collect();
+
+            for index in 0..size {
+                let mut cs = TestConstraintSystem::<Bls12>::new();
+                let element =
+                    AllocatedNum::alloc(cs.namespace(|| "element"), || Ok(values[0])).unwrap();
+                let elements = values[1..]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| {
+                        AllocatedNum::alloc(cs.namespace(|| format!("elements_{}", i)), || Ok(*v))
+                            .unwrap()
+                    })
+                    .collect::<Vec<_>>();
+                let bits = (0..size_log)
+                    .map(|i| {
+                        Boolean::from(
+                            AllocatedBit::alloc(
+                                cs.namespace(|| format!("bit_{}", i)),
+                                Some((index >> i) & 1 == 1),
+                            )
+                            .unwrap(),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                let inserted = insert(&mut cs, &element, &bits, &elements).unwrap();
+                assert!(cs.is_satisfied());
+
+                let mut expected = values[1..].to_vec();
+                expected.insert(index, values[0]);
+                let actual: Vec<Fr> = inserted.iter().map(|n| n.get_value().unwrap()).collect();
+                assert_eq!(actual, expected);
+            }
+        }
+    }
+}
@@ -1 +1,4 @@
+pub mod feistel;
+pub mod insertion;
 pub mod multipack;
+pub mod por;
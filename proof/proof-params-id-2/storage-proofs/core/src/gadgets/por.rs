@@ -0,0 +1,310 @@
+use std::marker::PhantomData;
+
+use bellperson::gadgets::{
+    boolean::{AllocatedBit, Boolean},
+    num::AllocatedNum,
+};
+use bellperson::{Circuit, ConstraintSystem, SynthesisError};
+use generic_array::typenum::Unsigned;
+use paired::bls12_381::{Bls12, Fr};
+
+use crate::fr32::u64_into_fr;
+use crate::gadgets::{insertion::insert, multipack};
+use crate::hasher::{HashFunction, Hasher};
+use crate::merkle::{base_path_length, compound_path_length, MerkleProofTrait, MerkleTreeTrait};
+
+/// Authentication path in the form returned by `MerkleProofTrait::as_options`:
+/// for every level from the leaf up, the siblings and the position among them.
+pub type AuthPath = Vec<(Vec<Option<Fr>>, Option<usize>)>;
+
+/// Proof of Retrievability circuit.
+///
+/// Proves that `value` is the leaf at the position given by the path of a
+/// merkle tree of shape `Tree` with root `root`. The position is exposed as a
+/// public input; so is the root, unless `private` is set.
+pub struct PoRCircuit<Tree: MerkleTreeTrait> {
+    value: Option<Fr>,
+    auth_path: AuthPath,
+    root: Option<Fr>,
+    private: bool,
+    _tree: PhantomData<Tree>,
+}
+
+impl<Tree: 'static + MerkleTreeTrait> PoRCircuit<Tree> {
+    pub fn new(proof: Tree::Proof, private: bool) -> Self {
+        let root = Some(proof.root().into());
+        let (value, auth_path) = proof.into_options_with_leaf();
+
+        PoRCircuit {
+            value,
+            auth_path,
+            root,
+            private,
+            _tree: PhantomData,
+        }
+    }
+
+    /// A circuit without assignments for a tree of `leaves` leaves, used for
+    /// parameter generation.
+    pub fn blank(leaves: usize, private: bool) -> Self {
+        let base_levels =
+            base_path_length::<Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>(leaves);
+        let levels =
+            compound_path_length::<Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>(leaves);
+
+        let auth_path = (0..levels)
+            .map(|level| {
+                let arity = level_arity::<Tree>(level, base_levels);
+                (vec![None; arity - 1], None)
+            })
+            .collect();
+
+        PoRCircuit {
+            value: None,
+            auth_path,
+            root: None,
+            private,
+            _tree: PhantomData,
+        }
+    }
+
+    /// Public inputs matching the circuit, for the given challenged leaf.
+    pub fn generate_public_inputs(challenge: usize, root: Fr, private: bool) -> Vec<Fr> {
+        let mut inputs = vec![u64_into_fr(challenge as u64)];
+        if !private {
+            inputs.push(root);
+        }
+        inputs
+    }
+}
+
+impl<Tree: 'static + MerkleTreeTrait> Circuit<Bls12> for PoRCircuit<Tree> {
+    fn synthesize<CS: ConstraintSystem<Bls12>>(self, cs: &mut CS) -> Result<(), SynthesisError> {
+        let value = AllocatedNum::alloc(cs.namespace(|| "value"), || {
+            self.value.ok_or_else(|| SynthesisError::AssignmentMissing)
+        })?;
+        let root = AllocatedNum::alloc(cs.namespace(|| "root"), || {
+            self.root.ok_or_else(|| SynthesisError::AssignmentMissing)
+        })?;
+
+        let index_bits = enforce_inclusion::<Tree, _>(
+            cs.namespace(|| "inclusion"),
+            &value,
+            &self.auth_path,
+            &root,
+        )?;
+
+        // Expose the challenged position, so the verifier controls which leaf is proven.
+        let index = multipack::pack_bits(cs.namespace(|| "path_index"), &index_bits)?;
+        index.inputize(cs.namespace(|| "path_index_input"))?;
+
+        if !self.private {
+            root.inputize(cs.namespace(|| "root_input"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Enforces that `leaf` is included in the tree with root `root`, following
+/// `auth_path` from the leaf up.
+///
+/// Works for any combination of base, sub and top tree arities of `Tree`: the
+/// base levels are hashed with `Tree::Arity`, followed by one level with
+/// `Tree::SubTreeArity` and one with `Tree::TopTreeArity` where those are
+/// non-zero. The sub and top levels use `Tree::TopHasher`. At each level the
+/// current node is inserted among its siblings at the slot selected by the
+/// index bits of that level.
+///
+/// Returns the path index bits, least significant first, for callers which
+/// need to constrain the challenged position.
+pub fn enforce_inclusion<Tree, CS>(
+    mut cs: CS,
+    leaf: &AllocatedNum<Bls12>,
+    auth_path: &[(Vec<Option<Fr>>, Option<usize>)],
+    root: &AllocatedNum<Bls12>,
+) -> Result<Vec<Boolean>, SynthesisError>
+where
+    Tree: 'static + MerkleTreeTrait,
+    CS: ConstraintSystem<Bls12>,
+{
+    let upper_levels = (Tree::SubTreeArity::to_usize() > 0) as usize
+        + (Tree::TopTreeArity::to_usize() > 0) as usize;
+    if auth_path.len() <= upper_levels {
+        return Err(SynthesisError::Unsatisfiable);
+    }
+    let base_levels = auth_path.len() - upper_levels;
+
+    let mut cur = leaf.clone();
+    let mut index_bits = Vec::new();
+
+    for (level, (siblings, index)) in auth_path.iter().enumerate() {
+        let mut cs = cs.namespace(|| format!("level_{}", level));
+
+        let arity = level_arity::<Tree>(level, base_levels);
+        if siblings.len() != arity - 1 {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        let bits = (0..arity.trailing_zeros() as usize)
+            .map(|i| {
+                let bit = AllocatedBit::alloc(
+                    cs.namespace(|| format!("index_bit_{}", i)),
+                    index.map(|index| (index >> i) & 1 == 1),
+                )?;
+                Ok(Boolean::from(bit))
+            })
+            .collect::<Result<Vec<_>, SynthesisError>>()?;
+
+        let siblings = siblings
+            .iter()
+            .enumerate()
+            .map(|(i, sibling)| {
+                AllocatedNum::alloc(cs.namespace(|| format!("sibling_{}", i)), || {
+                    sibling.ok_or_else(|| SynthesisError::AssignmentMissing)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let nodes = insert(&mut cs, &cur, &bits, &siblings)?;
+
+        // Heights restart for the sub and top trees, as in `MerkleProof::verify`.
+        cur = if level < base_levels {
+            Function::<Tree>::hash_multi_leaf_circuit::<Tree::Arity, _>(
+                cs.namespace(|| "hash"),
+                &nodes,
+                level,
+            )?
+        } else if level == base_levels && Tree::SubTreeArity::to_usize() > 0 {
+            TopFunction::<Tree>::hash_multi_leaf_circuit::<Tree::SubTreeArity, _>(
+                cs.namespace(|| "hash"),
+                &nodes,
+                0,
+            )?
+        } else {
+            TopFunction::<Tree>::hash_multi_leaf_circuit::<Tree::TopTreeArity, _>(
+                cs.namespace(|| "hash"),
+                &nodes,
+                0,
+            )?
+        };
+
+        index_bits.extend(bits);
+    }
+
+    cs.enforce(
+        || "enforce root",
+        |lc| lc + cur.get_variable(),
+        |lc| lc + CS::one(),
+        |lc| lc + root.get_variable(),
+    );
+
+    Ok(index_bits)
+}
+
+type Function<Tree> = <<Tree as MerkleTreeTrait>::Hasher as Hasher>::Function;
+type TopFunction<Tree> = <<Tree as MerkleTreeTrait>::TopHasher as Hasher>::Function;
+
+fn level_arity<Tree: MerkleTreeTrait>(level: usize, base_levels: usize) -> usize {
+    if level < base_levels {
+        Tree::Arity::to_usize()
+    } else if level == base_levels && Tree::SubTreeArity::to_usize() > 0 {
+        Tree::SubTreeArity::to_usize()
+    } else {
+        Tree::TopTreeArity::to_usize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bellperson::gadgets::test::TestConstraintSystem;
+    use generic_array::typenum::{U0, U2, U4};
+
+    use crate::hasher::{Domain, PoseidonHasher, Sha256Hasher};
+    use crate::merkle::{DiskStore, HybridMerkleTree, MerkleTreeWrapper};
+
+    type H = Sha256Hasher;
+    type Store = DiskStore<<H as Hasher>::Domain>;
+
+    fn base_tree<U: 'static + crate::hasher::PoseidonArity>(
+        leaves: usize,
+    ) -> MerkleTreeWrapper<H, Store, U, U0, U0> {
+        let mut rng = rand::thread_rng();
+        MerkleTreeWrapper::new((0..leaves).map(|_| <H as Hasher>::Domain::random(&mut rng)))
+            .unwrap()
+    }
+
+    fn check_por<Tree: 'static + MerkleTreeTrait>(tree: &Tree) {
+        let leaves = tree.leaves();
+        for &challenge in &[0, 1, leaves - 1] {
+            for &private in &[false, true] {
+                let proof = tree.gen_proof(challenge).unwrap();
+                let root: Fr = proof.root().into();
+
+                let mut cs = TestConstraintSystem::<Bls12>::new();
+                PoRCircuit::<Tree>::new(proof.clone(), private)
+                    .synthesize(&mut cs)
+                    .unwrap();
+                assert!(cs.is_satisfied(), "constraints not satisfied");
+                assert!(cs.verify(&PoRCircuit::<Tree>::generate_public_inputs(
+                    challenge, root, private
+                )));
+
+                // The blank circuit must have the same shape as a real one.
+                let blank = PoRCircuit::<Tree>::blank(leaves, private);
+                let shape = |path: &AuthPath| path.iter().map(|(s, _)| s.len()).collect::<Vec<_>>();
+                assert_eq!(shape(&blank.auth_path), shape(&proof.as_options()));
+
+                // A different leaf must not satisfy the circuit.
+                let mut circuit = PoRCircuit::<Tree>::new(proof, private);
+                circuit.value = Some(root);
+                let mut cs = TestConstraintSystem::<Bls12>::new();
+                circuit.synthesize(&mut cs).unwrap();
+                assert!(!cs.is_satisfied(), "invalid leaf was accepted");
+            }
+        }
+    }
+
+    #[test]
+    fn por_sha256_2() {
+        check_por(&base_tree::<U2>(8));
+    }
+
+    #[test]
+    fn por_sha256_4() {
+        check_por(&base_tree::<U4>(16));
+    }
+
+    #[test]
+    fn por_sha256_2_2() {
+        let trees = (0..2).map(|_| base_tree::<U2>(4)).collect();
+        check_por(&MerkleTreeWrapper::<H, Store, U2, U2, U0>::from_trees(trees).unwrap());
+    }
+
+    #[test]
+    fn por_sha256_2_2_2() {
+        let sub_trees = (0..2)
+            .map(|_| {
+                let trees = (0..2).map(|_| base_tree::<U2>(4)).collect();
+                MerkleTreeWrapper::<H, Store, U2, U2, U0>::from_trees(trees).unwrap()
+            })
+            .collect();
+        check_por(&MerkleTreeWrapper::<H, Store, U2, U2, U2>::from_sub_trees(sub_trees).unwrap());
+    }
+
+    #[test]
+    fn por_hybrid_sha256_2_2_2() {
+        let trees = (0..4).map(|_| base_tree::<U2>(4)).collect();
+        check_por(&HybridMerkleTree::<H, H, Store, U2, U2, U2>::from_trees(trees).unwrap());
+    }
+
+    #[test]
+    fn por_hybrid_sha256_poseidon_2_4_2() {
+        let trees = (0..8).map(|_| base_tree::<U2>(4)).collect();
+        check_por(
+            &HybridMerkleTree::<H, PoseidonHasher, Store, U2, U4, U2>::from_trees(trees).unwrap(),
+        );
+    }
+}
@@ -1,5 +1,7 @@
 pub mod types;
 pub mod sha256;
+pub mod poseidon;
 
 pub use self::types::*;
 pub use self::sha256::*;
+pub use self::poseidon::*;
@@ -0,0 +1,355 @@
+use std::cmp::Ordering;
+use std::hash::Hasher as StdHasher;
+
+use anyhow::ensure;
+use bellperson::gadgets::{boolean, num};
+use bellperson::{ConstraintSystem, SynthesisError};
+use ff::{Field, PrimeField, PrimeFieldRepr};
+use generic_array::typenum::{U11, U16, U2, U24, U36, U4, U8};
+use merkletree::hash::{Algorithm, Hashable};
+use merkletree::merkle::Element;
+use neptune::circuit::poseidon_hash;
+use neptune::poseidon::Poseidon;
+use paired::bls12_381::{Bls12, Fr, FrRepr};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use super::{Domain, HashFunction, Hasher, PoseidonArity};
+use crate::crypto::sloth;
+use crate::error::*;
+
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PoseidonHasher {}
+
+impl Hasher for PoseidonHasher {
+    type Domain = PoseidonDomain;
+    type Function = PoseidonFunction;
+
+    fn name() -> String {
+        "poseidon_hasher".into()
+    }
+
+    fn sloth_encode(key: &Self::Domain, ciphertext: &Self::Domain) -> Result<Self::Domain> {
+        Ok(sloth::encode(&(*key).into(), &(*ciphertext).into()).into())
+    }
+
+    fn sloth_decode(key: &Self::Domain, ciphertext: &Self::Domain) -> Result<Self::Domain> {
+        Ok(sloth::decode(&(*key).into(), &(*ciphertext).into()).into())
+    }
+}
+
+/// Poseidon over BLS12-381, with the constants of `PoseidonArity`.
+///
+/// Nodes are hashed as field elements by `multi_node`, so `Algorithm::hash`
+/// only sees data written through `Hashable`, which is hashed the same way,
+/// one field element per 32 bytes.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct PoseidonFunction(Fr);
+
+impl StdHasher for PoseidonFunction {
+    #[inline]
+    fn write(&mut self, msg: &[u8]) {
+        self.0 = shared_hash(msg).into();
+    }
+
+    #[inline]
+    fn finish(&self) -> u64 {
+        unreachable!("unused by Function -- should never be called")
+    }
+}
+
+#[derive(Default, Copy, Clone, Serialize, Deserialize)]
+pub struct PoseidonDomain(pub FrRepr);
+
+impl std::fmt::Debug for PoseidonDomain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PoseidonDomain({})", hex::encode(self.into_bytes()))
+    }
+}
+
+impl AsRef<PoseidonDomain> for PoseidonDomain {
+    fn as_ref(&self) -> &Self {
+        self
+    }
+}
+
+impl AsRef<[u8]> for PoseidonDomain {
+    fn as_ref(&self) -> &[u8] {
+        as_bytes(&(self.0).0)
+    }
+}
+
+/// Little endian bytes of `limbs`, without copying them.
+#[inline(always)]
+fn as_bytes(limbs: &[u64; 4]) -> &[u8] {
+    // Limbs are little endian on the supported targets, so this matches
+    // `FrRepr::write_le`.
+    unsafe {
+        std::slice::from_raw_parts(
+            limbs.as_ptr() as *const u8,
+            limbs.len() * std::mem::size_of::<u64>(),
+        )
+    }
+}
+
+impl std::hash::Hash for PoseidonDomain {
+    fn hash<H: StdHasher>(&self, state: &mut H) {
+        std::hash::Hash::hash(&(self.0).0, state);
+    }
+}
+
+impl PartialEq for PoseidonDomain {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for PoseidonDomain {}
+
+impl Ord for PoseidonDomain {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl PartialOrd for PoseidonDomain {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hashable<PoseidonFunction> for PoseidonDomain {
+    fn hash(&self, state: &mut PoseidonFunction) {
+        state.write(&self.into_bytes())
+    }
+}
+
+impl From<Fr> for PoseidonDomain {
+    fn from(val: Fr) -> Self {
+        PoseidonDomain(val.into_repr())
+    }
+}
+
+impl From<FrRepr> for PoseidonDomain {
+    fn from(val: FrRepr) -> Self {
+        PoseidonDomain(val)
+    }
+}
+
+impl From<PoseidonDomain> for Fr {
+    fn from(val: PoseidonDomain) -> Self {
+        Fr::from_repr(val.0).unwrap()
+    }
+}
+
+impl Domain for PoseidonDomain {
+    fn into_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(PoseidonDomain::byte_len());
+        self.0.write_le(&mut out).unwrap();
+        out
+    }
+
+    fn try_from_bytes(raw: &[u8]) -> Result<Self> {
+        ensure!(
+            raw.len() == PoseidonDomain::byte_len(),
+            Error::InvalidInputSize
+        );
+
+        let mut res = FrRepr::default();
+        res.read_le(raw)?;
+        ensure!(Fr::from_repr(res).is_ok(), Error::BadFrBytes);
+
+        Ok(PoseidonDomain(res))
+    }
+
+    fn write_bytes(&self, dest: &mut [u8]) -> Result<()> {
+        ensure!(
+            dest.len() >= PoseidonDomain::byte_len(),
+            Error::InvalidInputSize
+        );
+
+        self.0.write_le(&mut dest[..PoseidonDomain::byte_len()])?;
+        Ok(())
+    }
+
+    fn random<R: RngCore>(rng: &mut R) -> Self {
+        Fr::random(rng).into()
+    }
+}
+
+impl Element for PoseidonDomain {
+    fn byte_len() -> usize {
+        32
+    }
+
+    fn from_slice(bytes: &[u8]) -> Self {
+        PoseidonDomain::try_from_bytes(bytes).expect("invalid Poseidon domain bytes")
+    }
+
+    fn copy_to_slice(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.into_bytes());
+    }
+}
+
+/// Hashes `data`, read as one field element per 32 bytes.
+fn shared_hash(data: &[u8]) -> PoseidonDomain {
+    let preimage = data
+        .chunks(PoseidonDomain::byte_len())
+        .map(|chunk| PoseidonDomain::from_slice(chunk).into())
+        .collect::<Vec<Fr>>();
+
+    shared_hash_frs(&preimage).into()
+}
+
+fn shared_hash_frs(preimage: &[Fr]) -> Fr {
+    match preimage.len() {
+        2 => Poseidon::new_with_preimage(preimage, U2::PARAMETERS()).hash(),
+        4 => Poseidon::new_with_preimage(preimage, U4::PARAMETERS()).hash(),
+        8 => Poseidon::new_with_preimage(preimage, U8::PARAMETERS()).hash(),
+        11 => Poseidon::new_with_preimage(preimage, U11::PARAMETERS()).hash(),
+        16 => Poseidon::new_with_preimage(preimage, U16::PARAMETERS()).hash(),
+        24 => Poseidon::new_with_preimage(preimage, U24::PARAMETERS()).hash(),
+        36 => Poseidon::new_with_preimage(preimage, U36::PARAMETERS()).hash(),
+        arity => panic!("unsupported arity for the Poseidon hasher: {}", arity),
+    }
+}
+
+impl HashFunction<PoseidonDomain> for PoseidonFunction {
+    fn hash(data: &[u8]) -> PoseidonDomain {
+        shared_hash(data)
+    }
+
+    fn hash2(a: &PoseidonDomain, b: &PoseidonDomain) -> PoseidonDomain {
+        shared_hash_frs(&[(*a).into(), (*b).into()]).into()
+    }
+
+    fn hash_leaf_circuit<CS: ConstraintSystem<Bls12>>(
+        cs: CS,
+        left: &num::AllocatedNum<Bls12>,
+        right: &num::AllocatedNum<Bls12>,
+        _height: usize,
+    ) -> std::result::Result<num::AllocatedNum<Bls12>, SynthesisError> {
+        Self::hash2_circuit(cs, left, right)
+    }
+
+    fn hash_multi_leaf_circuit<Arity: 'static + PoseidonArity, CS: ConstraintSystem<Bls12>>(
+        cs: CS,
+        leaves: &[num::AllocatedNum<Bls12>],
+        _height: usize,
+    ) -> std::result::Result<num::AllocatedNum<Bls12>, SynthesisError> {
+        poseidon_hash(cs, leaves.to_vec(), Arity::PARAMETERS())
+    }
+
+    fn hash_circuit<CS: ConstraintSystem<Bls12>>(
+        _cs: CS,
+        _bits: &[boolean::Boolean],
+    ) -> std::result::Result<num::AllocatedNum<Bls12>, SynthesisError> {
+        // Poseidon hashes field elements, not bits.
+        Err(SynthesisError::Unsatisfiable)
+    }
+
+    fn hash2_circuit<CS>(
+        cs: CS,
+        a: &num::AllocatedNum<Bls12>,
+        b: &num::AllocatedNum<Bls12>,
+    ) -> std::result::Result<num::AllocatedNum<Bls12>, SynthesisError>
+    where
+        CS: ConstraintSystem<Bls12>,
+    {
+        poseidon_hash(cs, vec![a.clone(), b.clone()], U2::PARAMETERS())
+    }
+}
+
+impl Algorithm<PoseidonDomain> for PoseidonFunction {
+    #[inline]
+    fn hash(&mut self) -> PoseidonDomain {
+        self.0.into()
+    }
+
+    #[inline]
+    fn reset(&mut self) {
+        self.0 = Fr::zero();
+    }
+
+    fn leaf(&mut self, leaf: PoseidonDomain) -> PoseidonDomain {
+        leaf
+    }
+
+    fn node(
+        &mut self,
+        left: PoseidonDomain,
+        right: PoseidonDomain,
+        _height: usize,
+    ) -> PoseidonDomain {
+        shared_hash_frs(&[left.into(), right.into()]).into()
+    }
+
+    fn multi_node(&mut self, parts: &[PoseidonDomain], _height: usize) -> PoseidonDomain {
+        let preimage = parts.iter().map(|part| (*part).into()).collect::<Vec<Fr>>();
+        shared_hash_frs(&preimage).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bellperson::gadgets::test::TestConstraintSystem;
+    use generic_array::typenum::U0;
+
+    use crate::merkle::{DiskStore, MerkleProofTrait, MerkleTreeTrait, MerkleTreeWrapper};
+
+    type D = PoseidonDomain;
+
+    #[test]
+    fn poseidon_domain_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let node = D::random(&mut rng);
+
+        assert_eq!(AsRef::<[u8]>::as_ref(&node), &node.into_bytes()[..]);
+        assert_eq!(D::try_from_bytes(&node.into_bytes()).unwrap(), node);
+        assert_eq!(D::from(Fr::from(node)), node);
+
+        // Bytes which are not a field element are rejected.
+        assert!(D::try_from_bytes(&[0xff; 32]).is_err());
+        assert!(D::try_from_bytes(&[0; 31]).is_err());
+    }
+
+    #[test]
+    fn poseidon_tree_proofs() {
+        let mut rng = rand::thread_rng();
+        let leaves: Vec<D> = (0..64).map(|_| D::random(&mut rng)).collect();
+        let tree = MerkleTreeWrapper::<PoseidonHasher, DiskStore<D>, U8, U0, U0>::new(
+            leaves.iter().copied(),
+        )
+        .unwrap();
+
+        let mut a = PoseidonFunction::default();
+        let row: Vec<D> = leaves.chunks(8).map(|c| a.multi_node(c, 0)).collect();
+        assert_eq!(tree.root(), a.multi_node(&row, 1));
+
+        for i in &[0, 9, 63] {
+            let proof = tree.gen_proof(*i).unwrap();
+            assert!(proof.verify());
+            assert!(proof.validate(*i));
+        }
+    }
+
+    #[test]
+    fn poseidon_hash2_circuit_matches() {
+        let mut rng = rand::thread_rng();
+        let (a, b) = (D::random(&mut rng), D::random(&mut rng));
+
+        let mut cs = TestConstraintSystem::<Bls12>::new();
+        let a_num = num::AllocatedNum::alloc(cs.namespace(|| "a"), || Ok(a.into())).unwrap();
+        let b_num = num::AllocatedNum::alloc(cs.namespace(|| "b"), || Ok(b.into())).unwrap();
+        let out =
+            PoseidonFunction::hash2_circuit(cs.namespace(|| "hash2"), &a_num, &b_num).unwrap();
+
+        assert!(cs.is_satisfied());
+        assert_eq!(
+            out.get_value(),
+            Some(PoseidonFunction::hash2(&a, &b).into())
+        );
+    }
+}
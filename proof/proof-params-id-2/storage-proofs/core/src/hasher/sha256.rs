@@ -12,6 +12,7 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use super::{Domain, HashFunction, Hasher};
+use crate::crypto::sha256_multi::sha256_many;
 use crate::crypto::sloth;
 use crate::error::*;
 use crate::gadgets::multipack;
@@ -56,6 +57,44 @@ impl StdHasher for Sha256Function {
     }
 }
 
+impl Sha256Function {
+    /// Hashes every group of `arity` consecutive `nodes` into its parent, as
+    /// `Algorithm::multi_node` does, but several groups at a time with
+    /// `sha256_many`.
+    pub fn hash_nodes(nodes: &[Sha256Domain], arity: usize) -> Vec<Sha256Domain> {
+        let bytes: Vec<u8> = nodes
+            .iter()
+            .flat_map(|node| node.0.iter().copied())
+            .collect();
+
+        Self::hash_node_bytes(&bytes, arity)
+    }
+
+    /// Like `hash_nodes`, over nodes given as consecutive 32 byte chunks.
+    pub fn hash_node_bytes(bytes: &[u8], arity: usize) -> Vec<Sha256Domain> {
+        let message_len = arity * Sha256Domain::byte_len();
+        assert!(
+            arity > 0 && bytes.len() % message_len == 0,
+            "{} bytes cannot be grouped by {} nodes",
+            bytes.len(),
+            arity
+        );
+
+        let messages: Vec<&[u8]> = bytes.chunks(message_len).collect();
+        let mut digests = vec![[0u8; 32]; messages.len()];
+        sha256_many(&messages, &mut digests);
+
+        digests
+            .into_iter()
+            .map(|digest| {
+                let mut node = Sha256Domain(digest);
+                node.trim_to_fr32();
+                node
+            })
+            .collect()
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize, Hash)]
 pub struct Sha256Domain(pub [u8; 32]);
 
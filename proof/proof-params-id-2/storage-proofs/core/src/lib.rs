@@ -1,3 +1,4 @@
+pub mod cache_key;
 pub mod crypto;
 pub mod compound_proof;
 pub mod drgraph;
@@ -7,6 +8,7 @@ pub mod gadgets;
 pub mod hasher;
 pub mod merkle;
 pub mod parameter_cache;
+pub mod piece_inclusion_proof;
 pub mod proof;
 pub mod util;
 
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::RwLock;
+
+use generic_array::typenum::Unsigned;
+use merkletree::hash::Algorithm;
+use rayon::prelude::*;
+
+use crate::hasher::Hasher;
+
+use super::{convert_domain, MerkleProofTrait};
+
+type Domain<P> = <<P as MerkleProofTrait>::Hasher as Hasher>::Domain;
+
+/// Position of a group of siblings: root of the tree, level, hashing height
+/// and index of their parent in its row.
+type GroupKey<D> = (D, usize, usize, usize);
+
+/// Verifies many merkle proofs in parallel.
+///
+/// Proofs against the same root usually share the upper part of their paths.
+/// Every group of siblings hashed while verifying a valid proof is remembered
+/// together with its parent, and reused when another proof presents exactly
+/// the same group at the same position. Results are therefore identical to
+/// calling `MerkleProofTrait::verify` on each proof, and one invalid proof
+/// never affects the result of another.
+///
+/// A verifier can be reused for several batches against the same trees; use
+/// `clear` to release the remembered groups.
+#[derive(Debug)]
+pub struct BatchVerifier<P: MerkleProofTrait> {
+    groups: RwLock<HashMap<GroupKey<Domain<P>>, (Vec<Domain<P>>, Domain<P>)>>,
+    _p: PhantomData<P>,
+}
+
+impl<P: MerkleProofTrait> Default for BatchVerifier<P> {
+    fn default() -> Self {
+        BatchVerifier {
+            groups: Default::default(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<P: MerkleProofTrait> BatchVerifier<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Verifies every proof, returning one result per proof.
+    pub fn verify(&self, proofs: &[P]) -> Vec<bool> {
+        proofs
+            .par_iter()
+            .map(|proof| self.verify_proof(proof))
+            .collect()
+    }
+
+    /// Verifies every proof and that it proves the matching challenge, as
+    /// `MerkleProofTrait::validate` does.
+    pub fn validate(&self, proofs: &[P], challenges: &[usize]) -> Vec<bool> {
+        assert_eq!(
+            proofs.len(),
+            challenges.len(),
+            "every proof needs a challenge"
+        );
+
+        proofs
+            .par_iter()
+            .zip(challenges.par_iter())
+            .map(|(proof, challenge)| {
+                proof.proves_challenge(*challenge) && self.verify_proof(proof)
+            })
+            .collect()
+    }
+
+    /// Number of sibling groups remembered from valid proofs.
+    pub fn cached_groups(&self) -> usize {
+        self.groups.read().expect("poisoned lock").len()
+    }
+
+    pub fn clear(&self) {
+        self.groups.write().expect("poisoned lock").clear();
+    }
+
+    fn verify_proof(&self, proof: &P) -> bool {
+        let root = proof.root();
+        let path = proof.path_refs();
+
+        // Heights restart at the sub and top trees, as in `MerkleProof::verify`.
+        let upper_levels =
+            (P::SubTreeArity::to_usize() > 0) as usize + (P::TopTreeArity::to_usize() > 0) as usize;
+        if path.len() < upper_levels {
+            return false;
+        }
+        let base_levels = path.len() - upper_levels;
+        let height = |level: usize| if level < base_levels { level } else { 0 };
+
+        let mut position = path
+            .iter()
+            .rev()
+            .fold(0, |acc, (hashes, index)| acc * (hashes.len() + 1) + index);
+
+        let mut a = <P::Hasher as Hasher>::Function::default();
+        let mut top = <P::TopHasher as Hasher>::Function::default();
+        let mut children = Vec::new();
+        let mut computed = Vec::new();
+        let mut node = proof.leaf();
+
+        for (level, (hashes, index)) in path.iter().enumerate() {
+            if *index > hashes.len() {
+                return false;
+            }
+
+            children.clear();
+            children.extend_from_slice(&hashes[..*index]);
+            children.push(node);
+            children.extend_from_slice(&hashes[*index..]);
+
+            position /= children.len();
+            let key = (root, level, height(level), position);
+
+            let cached = self
+                .groups
+                .read()
+                .expect("poisoned lock")
+                .get(&key)
+                .filter(|(group, _)| group == &children)
+                .map(|(_, parent)| *parent);
+
+            node = match cached {
+                Some(parent) => parent,
+                None => {
+                    let parent = if level < base_levels {
+                        a.reset();
+                        a.multi_node(&children, level)
+                    } else {
+                        // The sub and top levels are hashed with the top hasher.
+                        let top_children = children
+                            .iter()
+                            .map(convert_domain::<_, <P::TopHasher as Hasher>::Domain>)
+                            .collect::<Result<Vec<_>, _>>();
+                        let parent = top_children.and_then(|children| {
+                            top.reset();
+                            convert_domain(&top.multi_node(&children, 0))
+                        });
+                        match parent {
+                            Ok(parent) => parent,
+                            Err(_) => return false,
+                        }
+                    };
+                    computed.push((key, (children.clone(), parent)));
+                    parent
+                }
+            };
+        }
+
+        let valid = node == root;
+        if valid && !computed.is_empty() {
+            self.groups.write().expect("poisoned lock").extend(computed);
+        }
+
+        valid
+    }
+}
+
+/// Verifies `proofs` in parallel, see `BatchVerifier`.
+pub fn verify_batch<P: MerkleProofTrait>(proofs: &[P]) -> Vec<bool> {
+    BatchVerifier::new().verify(proofs)
+}
+
+/// Validates `proofs` against `challenges` in parallel, see `BatchVerifier`.
+pub fn validate_batch<P: MerkleProofTrait>(proofs: &[P], challenges: &[usize]) -> Vec<bool> {
+    BatchVerifier::new().validate(proofs, challenges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use generic_array::typenum::{U0, U2, U4, U8};
+
+    use crate::hasher::{Domain as _, PoseidonArity, Sha256Hasher};
+    use crate::merkle::{DiskStore, MerkleTreeTrait, MerkleTreeWrapper};
+
+    type H = Sha256Hasher;
+    type Store = DiskStore<<H as Hasher>::Domain>;
+
+    fn base_tree<U: 'static + PoseidonArity>(
+        leaves: usize,
+    ) -> MerkleTreeWrapper<H, Store, U, U0, U0> {
+        let mut rng = rand::thread_rng();
+        MerkleTreeWrapper::new((0..leaves).map(|_| <H as Hasher>::Domain::random(&mut rng)))
+            .unwrap()
+    }
+
+    fn check_batch<Tree: MerkleTreeTrait<Hasher = H>>(tree: &Tree) {
+        let mut rng = rand::thread_rng();
+        let challenges: Vec<usize> = (0..tree.leaves()).step_by(3).collect();
+        let mut proofs: Vec<_> = challenges
+            .iter()
+            .map(|c| tree.gen_proof(*c).unwrap())
+            .collect();
+
+        // Break every fifth proof.
+        for proof in proofs.iter_mut().step_by(5) {
+            proof.break_me(<H as Hasher>::Domain::random(&mut rng));
+        }
+        let expected: Vec<bool> = proofs.iter().map(|p| p.verify()).collect();
+        assert!(expected.iter().any(|valid| !valid));
+        assert!(expected.iter().any(|valid| *valid));
+
+        let verifier = BatchVerifier::new();
+        assert_eq!(verifier.verify(&proofs), expected);
+        assert!(verifier.cached_groups() > 0);
+
+        // A second pass is served from the cache and gives the same results.
+        let cached = verifier.cached_groups();
+        assert_eq!(verifier.verify(&proofs), expected);
+        assert_eq!(verifier.cached_groups(), cached);
+
+        let mut shifted = challenges.clone();
+        shifted[1] += 1;
+        let results = validate_batch(&proofs, &shifted);
+        for (i, valid) in results.iter().enumerate() {
+            assert_eq!(*valid, expected[i] && i != 1);
+        }
+    }
+
+    #[test]
+    fn batch_verify_single_2() {
+        check_batch(&base_tree::<U2>(256));
+    }
+
+    #[test]
+    fn batch_verify_single_8() {
+        check_batch(&base_tree::<U8>(512));
+    }
+
+    #[test]
+    fn batch_verify_sub_8_4() {
+        let trees = (0..4).map(|_| base_tree::<U8>(64)).collect();
+        check_batch(&MerkleTreeWrapper::<H, Store, U8, U4, U0>::from_trees(trees).unwrap());
+    }
+
+    #[test]
+    fn batch_verify_top_8_4_2() {
+        let sub_trees = (0..2)
+            .map(|_| {
+                let trees = (0..4).map(|_| base_tree::<U8>(64)).collect();
+                MerkleTreeWrapper::<H, Store, U8, U4, U0>::from_trees(trees).unwrap()
+            })
+            .collect();
+        check_batch(&MerkleTreeWrapper::<H, Store, U8, U4, U2>::from_sub_trees(sub_trees).unwrap());
+    }
+
+    #[test]
+    fn batch_verify_rejects_tampered_upper_path() {
+        let tree = base_tree::<U2>(64);
+        let verifier = BatchVerifier::new();
+        let proof = tree.gen_proof(0).unwrap();
+        assert_eq!(verifier.verify(&[proof]), vec![true]);
+
+        // A proof sharing the lower part of a cached path, but not its upper
+        // siblings, is still rejected.
+        let proof = tree.gen_proof(1).unwrap();
+        let mut serialized = serde_json::to_value(&proof).unwrap();
+        let single = &mut serialized["data"]["Single"]["path"]["path"];
+        let last = single.as_array().unwrap().len() - 1;
+        single[last]["hashes"][0] = serde_json::to_value(<H as Hasher>::Domain::default()).unwrap();
+        let tampered: <MerkleTreeWrapper<H, Store, U2> as MerkleTreeTrait>::Proof =
+            serde_json::from_value(serialized).unwrap();
+
+        assert!(!tampered.verify());
+        assert_eq!(verifier.verify(&[tampered]), vec![false]);
+    }
+}
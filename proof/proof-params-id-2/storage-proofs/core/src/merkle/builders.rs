@@ -0,0 +1,298 @@
+use std::borrow::Cow;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+
+use anyhow::{ensure, Context, Result};
+use generic_array::typenum::{Unsigned, U0};
+use merkletree::merkle::{get_merkle_tree_leafs, get_merkle_tree_len, Element};
+use merkletree::store::{ReplicaConfig, StoreConfig};
+use rayon::prelude::*;
+
+use crate::hasher::{PoseidonArity, Sha256Domain, Sha256Function, Sha256Hasher};
+
+use super::*;
+
+/// Number of parents hashed by one task while building a SHA-256 tree.
+const SHA256_NODES_PER_TASK: usize = 1024;
+
+/// Splits `config` into one config per base tree, named `<id>-0` to
+/// `<id>-<count - 1>`. A single tree keeps the config as is.
+pub fn split_config(config: StoreConfig, count: usize) -> Result<Vec<StoreConfig>> {
+    ensure!(count > 0, "cannot split a config into zero parts");
+    if count == 1 {
+        return Ok(vec![config]);
+    }
+
+    Ok((0..count)
+        .map(|i| StoreConfig::from_config(&config, format!("{}-{}", config.id, i), None))
+        .collect())
+}
+
+/// Number of base trees making up a tree of shape `Tree`.
+pub fn get_base_tree_count<Tree: MerkleTreeTrait>() -> usize {
+    let sub = Tree::SubTreeArity::to_usize();
+    let top = Tree::TopTreeArity::to_usize();
+
+    match (sub, top) {
+        (0, _) => 1,
+        (sub, 0) => sub,
+        (sub, top) => sub * top,
+    }
+}
+
+/// Opens a tree of shape `Tree` whose base trees have been persisted to
+/// `configs`, each holding `base_tree_len` nodes.
+pub fn create_disk_tree<Tree: MerkleTreeTrait>(
+    base_tree_len: usize,
+    configs: &[StoreConfig],
+) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>> {
+    ensure!(
+        configs.len() == get_base_tree_count::<Tree>(),
+        "expected {} configs, got {}",
+        get_base_tree_count::<Tree>(),
+        configs.len()
+    );
+    let base_tree_leafs = get_merkle_tree_leafs(base_tree_len, Tree::Arity::to_usize())?;
+
+    if Tree::TopTreeArity::to_usize() > 0 {
+        ensure!(
+            Tree::SubTreeArity::to_usize() > 0,
+            "invalid top arity specified without sub arity"
+        );
+        DiskTree::from_sub_tree_store_configs(base_tree_leafs, configs)
+    } else if Tree::SubTreeArity::to_usize() > 0 {
+        DiskTree::from_store_configs(base_tree_leafs, configs)
+    } else {
+        let store = DiskStore::new_from_disk(base_tree_len, Tree::Arity::to_usize(), &configs[0])?;
+        DiskTree::from_data_store(store, base_tree_leafs)
+    }
+}
+
+/// Builds a SHA-256 tree, such as tree-d, over the leaves in `data`, one
+/// node per 32 bytes, persisted to `config` if given.
+///
+/// Produces the same tree as `from_byte_slice_with_config`, but hashes each
+/// row with `Sha256Function::hash_node_bytes`, several nodes at a time,
+/// instead of one `Algorithm::multi_node` call per node. With a config, each
+/// row is written to the store as soon as it is hashed and only the previous
+/// row is kept in memory.
+pub fn create_sha256_tree<U: 'static + PoseidonArity>(
+    data: &[u8],
+    config: Option<StoreConfig>,
+) -> Result<MerkleTree<Sha256Hasher, U>> {
+    let node_size = Sha256Domain::byte_len();
+    ensure!(
+        data.len() % node_size == 0,
+        "{} bytes are not a whole number of nodes",
+        data.len()
+    );
+    let arity = U::to_usize();
+    let leafs = data.len() / node_size;
+    let len = get_merkle_tree_len(leafs, arity)?;
+
+    let config = match config {
+        Some(config) => config,
+        None => {
+            let mut tree = Vec::with_capacity(len * node_size);
+            tree.extend_from_slice(data);
+            let mut row_start = 0;
+            while tree.len() - row_start > node_size {
+                let row = hash_sha256_row(&tree[row_start..], arity);
+                row_start = tree.len();
+                tree.extend_from_slice(&row);
+            }
+            let store = DiskStore::new_from_slice(len, &tree)?;
+
+            return MerkleTree::from_data_store(store, leafs);
+        }
+    };
+
+    let path = StoreConfig::data_path(&config.path, &config.id);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .with_context(|| format!("could not create {:?}", path))?;
+    file.set_len((len * node_size) as u64)?;
+
+    let mut offset = 0;
+    let mut row = Cow::Borrowed(data);
+    loop {
+        file.write_all_at(&row, offset as u64)?;
+        offset += row.len();
+        if row.len() <= node_size {
+            break;
+        }
+        row = Cow::Owned(hash_sha256_row(&row, arity));
+    }
+    ensure!(
+        offset == len * node_size,
+        "wrote {} bytes, expected {}",
+        offset,
+        len * node_size
+    );
+
+    let store = DiskStore::new_from_disk(len, arity, &config)?;
+    MerkleTree::from_data_store(store, leafs)
+}
+
+/// Hashes one row of a SHA-256 tree into the bytes of the row above it.
+fn hash_sha256_row(row: &[u8], arity: usize) -> Vec<u8> {
+    row.par_chunks(arity * Sha256Domain::byte_len() * SHA256_NODES_PER_TASK)
+        .map(|nodes| {
+            let mut bytes = Vec::with_capacity(nodes.len() / arity);
+            for node in Sha256Function::hash_node_bytes(nodes, arity) {
+                bytes.extend_from_slice(node.as_ref());
+            }
+            bytes
+        })
+        .collect::<Vec<_>>()
+        .concat()
+}
+
+/// Opens a level cache tree of shape `Tree`, whose base data is read from
+/// `replica_config`.
+pub fn create_lc_tree<Tree: MerkleTreeTrait>(
+    base_tree_len: usize,
+    configs: &[StoreConfig],
+    replica_config: &ReplicaConfig,
+) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>> {
+    ensure!(
+        configs.len() == get_base_tree_count::<Tree>(),
+        "expected {} configs, got {}",
+        get_base_tree_count::<Tree>(),
+        configs.len()
+    );
+    let base_tree_leafs = get_merkle_tree_leafs(base_tree_len, Tree::Arity::to_usize())?;
+
+    if Tree::TopTreeArity::to_usize() > 0 {
+        ensure!(
+            Tree::SubTreeArity::to_usize() > 0,
+            "invalid top arity specified without sub arity"
+        );
+        LCTree::from_sub_tree_store_configs_and_replica(base_tree_leafs, configs, replica_config)
+    } else if Tree::SubTreeArity::to_usize() > 0 {
+        LCTree::from_store_configs_and_replica(base_tree_leafs, configs, replica_config)
+    } else {
+        let store = LCStore::new_from_disk_with_reader(
+            base_tree_len,
+            Tree::Arity::to_usize(),
+            &configs[0],
+            ExternalReader::new_from_config(replica_config, 0)?,
+        )?;
+        LCTree::from_data_store(store, base_tree_leafs)
+    }
+}
+
+/// Opens a level cache tree of shape `Tree` like `create_lc_tree`, but reads
+/// the base data of every base tree through the given `readers`, one per base
+/// tree, e.g. from a replica on another host.
+pub fn create_lc_tree_with_readers<Tree: MerkleTreeTrait, R: Read + Send + Sync>(
+    base_tree_len: usize,
+    configs: &[StoreConfig],
+    readers: Vec<ExternalReader<R>>,
+) -> Result<LCTreeWith<Tree::Hasher, R, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>> {
+    type BaseTree<T, R> =
+        LCTreeWith<<T as MerkleTreeTrait>::Hasher, R, <T as MerkleTreeTrait>::Arity, U0, U0>;
+    type SubTree<T, R> = LCTreeWith<
+        <T as MerkleTreeTrait>::Hasher,
+        R,
+        <T as MerkleTreeTrait>::Arity,
+        <T as MerkleTreeTrait>::SubTreeArity,
+        U0,
+    >;
+
+    ensure!(
+        configs.len() == get_base_tree_count::<Tree>(),
+        "expected {} configs, got {}",
+        get_base_tree_count::<Tree>(),
+        configs.len()
+    );
+    ensure!(
+        readers.len() == configs.len(),
+        "expected {} readers, got {}",
+        configs.len(),
+        readers.len()
+    );
+    let arity = Tree::Arity::to_usize();
+    let base_tree_leafs = get_merkle_tree_leafs(base_tree_len, arity)?;
+    let sub_tree_arity = Tree::SubTreeArity::to_usize();
+    let top_tree_arity = Tree::TopTreeArity::to_usize();
+
+    let mut stores = configs
+        .iter()
+        .zip(readers.into_iter())
+        .map(|(config, reader)| {
+            LCStoreWith::new_from_disk_with_reader(base_tree_len, arity, config, reader)
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter();
+
+    if sub_tree_arity == 0 {
+        let store = stores.next().expect("missing store");
+        return LCTreeWith::from_data_store(store, base_tree_leafs);
+    }
+
+    let mut base_trees = stores
+        .map(|store| BaseTree::<Tree, R>::from_data_store(store, base_tree_leafs))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter();
+
+    if top_tree_arity == 0 {
+        return LCTreeWith::from_trees(base_trees.collect());
+    }
+
+    let sub_trees = (0..top_tree_arity)
+        .map(|_| SubTree::<Tree, R>::from_trees(base_trees.by_ref().take(sub_tree_arity).collect()))
+        .collect::<Result<Vec<_>>>()?;
+
+    LCTreeWith::from_sub_trees(sub_trees)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use generic_array::typenum::{U2, U8};
+
+    use crate::hasher::Domain;
+
+    fn check_sha256_tree<U: 'static + PoseidonArity>(leafs: usize) {
+        let mut rng = rand::thread_rng();
+        let leaves: Vec<Sha256Domain> =
+            (0..leafs).map(|_| Sha256Domain::random(&mut rng)).collect();
+        let data: Vec<u8> = leaves.iter().flat_map(|leaf| leaf.into_bytes()).collect();
+
+        let expected = MerkleTree::<Sha256Hasher, U>::new(leaves).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let config = StoreConfig::new(dir.path(), "tree-d".to_string(), 0);
+
+        let trees = vec![
+            create_sha256_tree::<U>(&data, None).unwrap(),
+            create_sha256_tree::<U>(&data, Some(config.clone())).unwrap(),
+        ];
+        for tree in &trees {
+            assert_eq!(tree.root(), expected.root());
+            assert_eq!(tree.len(), expected.len());
+            for i in 0..tree.len() {
+                assert_eq!(tree.read_at(i).unwrap(), expected.read_at(i).unwrap());
+            }
+            assert!(tree.gen_proof(leafs - 1).unwrap().verify());
+        }
+
+        let stored = std::fs::read(StoreConfig::data_path(&config.path, &config.id)).unwrap();
+        assert_eq!(stored.len(), expected.len() * Sha256Domain::byte_len());
+    }
+
+    #[test]
+    fn sha256_tree_2() {
+        check_sha256_tree::<U2>(1 << 12);
+    }
+
+    #[test]
+    fn sha256_tree_8() {
+        check_sha256_tree::<U8>(1 << 12);
+    }
+}
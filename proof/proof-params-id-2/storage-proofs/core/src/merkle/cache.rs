@@ -0,0 +1,448 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use generic_array::typenum::{Unsigned, U0};
+use memmap::MmapOptions;
+use merkletree::merkle::{get_merkle_tree_cache_size, get_merkle_tree_len, Element};
+use merkletree::store::{ReplicaConfig, StoreConfig, StoreConfigDataVersion};
+
+use crate::cache_key::CacheKey;
+use crate::error::Error;
+use crate::hasher::{Hasher, PoseidonArity};
+
+use super::*;
+
+/// How a tree is laid out on disk.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StoreKind {
+    /// All rows of the tree, as written by `DiskStore`.
+    Disk,
+    /// Only the cached upper rows, as written by compacting to a level cache
+    /// store. The base row is read from the replica.
+    LevelCache,
+}
+
+/// The cache directory of a sector, in which its trees and auxiliary data are
+/// stored under the names given by `CacheKey`.
+///
+/// Trees made of several base trees are stored one file per base tree, with
+/// `-0` to `-N` appended to the key. The number of rows discarded from level
+/// cache trees is derived from the base tree size, unless set explicitly with
+/// `with_rows_to_discard`; a tree must be reopened with the same setting it
+/// was persisted with.
+#[derive(Debug, Clone)]
+pub struct CacheDir {
+    path: PathBuf,
+    rows_to_discard: Option<usize>,
+}
+
+impl CacheDir {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        CacheDir {
+            path: path.into(),
+            rows_to_discard: None,
+        }
+    }
+
+    /// Uses `rows_to_discard` for all trees instead of the per-arity default.
+    pub fn with_rows_to_discard(mut self, rows_to_discard: usize) -> Self {
+        self.rows_to_discard = Some(rows_to_discard);
+        self
+    }
+
+    /// Rows to discard from level cache trees of the given arity.
+    pub fn rows_to_discard(&self, base_tree_leafs: usize, arity: usize) -> usize {
+        self.rows_to_discard
+            .unwrap_or_else(|| StoreConfig::default_rows_to_discard(base_tree_leafs, arity))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path of a non-tree entry, such as `CacheKey::PAux`.
+    pub fn file_path(&self, key: CacheKey) -> PathBuf {
+        self.path.join(key.to_string())
+    }
+
+    /// Config of the single store named by `key`.
+    pub fn store_config(&self, key: CacheKey, rows_to_discard: usize) -> StoreConfig {
+        StoreConfig::new(&self.path, key.to_string(), rows_to_discard)
+    }
+
+    /// Configs of all base trees of a `Tree` stored under `key`, where each
+    /// base tree has `base_tree_leafs` leaves.
+    pub fn tree_configs<Tree: MerkleTreeTrait>(
+        &self,
+        key: CacheKey,
+        base_tree_leafs: usize,
+    ) -> Result<Vec<StoreConfig>> {
+        let rows_to_discard = self.rows_to_discard(base_tree_leafs, Tree::Arity::to_usize());
+
+        split_config(
+            self.store_config(key, rows_to_discard),
+            get_base_tree_count::<Tree>(),
+        )
+    }
+
+    /// Builds all base trees of a `Tree` from `base_leafs`, one entry per base
+    /// tree, and persists them under `key`.
+    pub fn persist_disk_tree<Tree: MerkleTreeTrait>(
+        &self,
+        key: CacheKey,
+        base_leafs: Vec<Vec<<Tree::Hasher as Hasher>::Domain>>,
+    ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>> {
+        let base_tree_leafs = self.persist_base_trees::<Tree>(key, base_leafs, StoreKind::Disk)?;
+
+        self.open_disk_tree::<Tree>(key, base_tree_leafs)
+    }
+
+    /// Like `persist_disk_tree`, but only keeps the cached rows on disk. The
+    /// base rows must be readable from `replica_config`.
+    pub fn persist_lc_tree<Tree: MerkleTreeTrait>(
+        &self,
+        key: CacheKey,
+        base_leafs: Vec<Vec<<Tree::Hasher as Hasher>::Domain>>,
+        replica_config: &ReplicaConfig,
+    ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>> {
+        let base_tree_leafs =
+            self.persist_base_trees::<Tree>(key, base_leafs, StoreKind::LevelCache)?;
+
+        self.open_lc_tree::<Tree>(key, base_tree_leafs, replica_config)
+    }
+
+    /// Like `persist_lc_tree`, but reads the leaves of every base tree of
+    /// `base_tree_leafs` leaves from its slice of the replica in
+    /// `replica_config`. The replica is mapped rather than read and base trees
+    /// are built one at a time, so memory use does not grow with the sector.
+    pub fn persist_lc_tree_from_replica<Tree: MerkleTreeTrait>(
+        &self,
+        key: CacheKey,
+        base_tree_leafs: usize,
+        replica_config: &ReplicaConfig,
+    ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>> {
+        let configs = self.tree_configs::<Tree>(key, base_tree_leafs)?;
+        ensure!(
+            replica_config.offsets.len() == configs.len(),
+            "expected {} replica offsets, got {}",
+            configs.len(),
+            replica_config.offsets.len()
+        );
+
+        let file = File::open(&replica_config.path)
+            .with_context(|| format!("could not open {:?}", replica_config.path))?;
+        let len = base_tree_leafs * <Tree::Hasher as Hasher>::Domain::byte_len();
+        for (config, offset) in configs.into_iter().zip(&replica_config.offsets) {
+            let data = unsafe {
+                MmapOptions::new()
+                    .offset(*offset as u64)
+                    .len(len)
+                    .map(&file)
+            }
+            .with_context(|| format!("could not map {:?}", replica_config.path))?;
+            let tree = DiskTree::<Tree::Hasher, Tree::Arity, U0, U0>::from_byte_slice_with_config(
+                &data,
+                config.clone(),
+            )?;
+            persist_base_tree(tree, config, StoreKind::LevelCache)?;
+        }
+
+        self.open_lc_tree::<Tree>(key, base_tree_leafs, replica_config)
+    }
+
+    /// Reopens a tree persisted by `persist_disk_tree`.
+    pub fn open_disk_tree<Tree: MerkleTreeTrait>(
+        &self,
+        key: CacheKey,
+        base_tree_leafs: usize,
+    ) -> Result<DiskTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>> {
+        let configs = self.check_tree::<Tree>(key, base_tree_leafs, StoreKind::Disk)?;
+        let base_tree_len = get_merkle_tree_len(base_tree_leafs, Tree::Arity::to_usize())?;
+
+        create_disk_tree::<Tree>(base_tree_len, &configs)
+    }
+
+    /// Reopens a tree persisted by `persist_lc_tree`.
+    pub fn open_lc_tree<Tree: MerkleTreeTrait>(
+        &self,
+        key: CacheKey,
+        base_tree_leafs: usize,
+        replica_config: &ReplicaConfig,
+    ) -> Result<LCTree<Tree::Hasher, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>> {
+        let configs = self.check_tree::<Tree>(key, base_tree_leafs, StoreKind::LevelCache)?;
+        let base_tree_len = get_merkle_tree_len(base_tree_leafs, Tree::Arity::to_usize())?;
+
+        create_lc_tree::<Tree>(base_tree_len, &configs, replica_config)
+    }
+
+    /// Reopens a tree persisted by `persist_lc_tree`, reading the base data
+    /// through `readers`, one per base tree.
+    pub fn open_lc_tree_with_readers<Tree: MerkleTreeTrait, R: Read + Send + Sync>(
+        &self,
+        key: CacheKey,
+        base_tree_leafs: usize,
+        readers: Vec<ExternalReader<R>>,
+    ) -> Result<LCTreeWith<Tree::Hasher, R, Tree::Arity, Tree::SubTreeArity, Tree::TopTreeArity>>
+    {
+        let configs = self.check_tree::<Tree>(key, base_tree_leafs, StoreKind::LevelCache)?;
+        let base_tree_len = get_merkle_tree_len(base_tree_leafs, Tree::Arity::to_usize())?;
+
+        create_lc_tree_with_readers::<Tree, R>(base_tree_len, &configs, readers)
+    }
+
+    /// Checks that all files of the tree stored under `key` exist and have
+    /// the expected size, returning their configs.
+    ///
+    /// Fails with `Error::MissingCacheFile` or `Error::TruncatedCacheFile`
+    /// for the first offending file.
+    pub fn check_tree<Tree: MerkleTreeTrait>(
+        &self,
+        key: CacheKey,
+        base_tree_leafs: usize,
+        kind: StoreKind,
+    ) -> Result<Vec<StoreConfig>> {
+        let configs = self.tree_configs::<Tree>(key, base_tree_leafs)?;
+        for config in &configs {
+            self.check_base_tree::<Tree>(config, base_tree_leafs, kind)?;
+        }
+
+        Ok(configs)
+    }
+
+    /// Like `check_tree`, for the single base tree stored at `config`.
+    pub fn check_base_tree<Tree: MerkleTreeTrait>(
+        &self,
+        config: &StoreConfig,
+        base_tree_leafs: usize,
+        kind: StoreKind,
+    ) -> Result<()> {
+        let arity = Tree::Arity::to_usize();
+        let elements = match kind {
+            StoreKind::Disk => get_merkle_tree_len(base_tree_leafs, arity)?,
+            StoreKind::LevelCache => {
+                get_merkle_tree_cache_size(base_tree_leafs, arity, config.rows_to_discard)?
+            }
+        };
+        let expected = (elements * <Tree::Hasher as Hasher>::Domain::byte_len()) as u64;
+
+        let path = StoreConfig::data_path(&config.path, &config.id);
+        let found = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Err(Error::MissingCacheFile {
+                    key: config.id.clone(),
+                    path,
+                }
+                .into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if found != expected {
+            return Err(Error::TruncatedCacheFile {
+                key: config.id.clone(),
+                path,
+                expected,
+                found,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Writes the base trees and returns the number of leaves of each.
+    fn persist_base_trees<Tree: MerkleTreeTrait>(
+        &self,
+        key: CacheKey,
+        base_leafs: Vec<Vec<<Tree::Hasher as Hasher>::Domain>>,
+        kind: StoreKind,
+    ) -> Result<usize> {
+        ensure!(
+            base_leafs.len() == get_base_tree_count::<Tree>(),
+            "expected {} base trees, got {}",
+            get_base_tree_count::<Tree>(),
+            base_leafs.len()
+        );
+        let base_tree_leafs = base_leafs[0].len();
+        ensure!(
+            base_leafs
+                .iter()
+                .all(|leafs| leafs.len() == base_tree_leafs),
+            "base trees must have the same number of leaves"
+        );
+
+        let configs = self.tree_configs::<Tree>(key, base_tree_leafs)?;
+        for (leafs, config) in base_leafs.into_iter().zip(configs) {
+            let tree = DiskTree::<Tree::Hasher, Tree::Arity, U0, U0>::from_par_iter_with_config(
+                leafs,
+                config.clone(),
+            )?;
+            persist_base_tree(tree, config, kind)?;
+        }
+
+        Ok(base_tree_leafs)
+    }
+}
+
+/// Keeps the rows of a base tree built at `config` that `kind` stores.
+fn persist_base_tree<H: Hasher, U: PoseidonArity>(
+    mut tree: DiskTree<H, U, U0, U0>,
+    config: StoreConfig,
+    kind: StoreKind,
+) -> Result<()> {
+    if kind == StoreKind::LevelCache {
+        tree.compact(config, StoreConfigDataVersion::Two as u32)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use generic_array::typenum::{U2, U8};
+
+    use crate::hasher::{Domain, Sha256Hasher};
+
+    type H = Sha256Hasher;
+    type D = <H as Hasher>::Domain;
+
+    fn random_leafs(count: usize, leafs: usize) -> Vec<Vec<D>> {
+        let mut rng = rand::thread_rng();
+        (0..count)
+            .map(|_| (0..leafs).map(|_| D::random(&mut rng)).collect())
+            .collect()
+    }
+
+    fn write_replica(path: &Path, base_leafs: &[Vec<D>]) -> ReplicaConfig {
+        let mut file = std::fs::File::create(path).unwrap();
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        for leafs in base_leafs {
+            offsets.push(offset);
+            for leaf in leafs {
+                file.write_all(leaf.as_ref()).unwrap();
+                offset += D::byte_len();
+            }
+        }
+
+        ReplicaConfig::new(path, offsets)
+    }
+
+    fn check_roundtrip<Tree: MerkleTreeTrait<Hasher = H>>(leafs: usize) {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+        let count = get_base_tree_count::<Tree>();
+
+        let base_leafs = random_leafs(count, leafs);
+        let tree_d = cache
+            .persist_disk_tree::<Tree>(CacheKey::CommDTree, base_leafs.clone())
+            .unwrap();
+        let root = tree_d.root();
+        drop(tree_d);
+
+        let reopened = cache
+            .open_disk_tree::<Tree>(CacheKey::CommDTree, leafs)
+            .unwrap();
+        assert_eq!(reopened.root(), root);
+        assert_eq!(reopened.leaves(), count * leafs);
+
+        let replica_config = write_replica(&dir.path().join("replica"), &base_leafs);
+        let tree_r_last = cache
+            .persist_lc_tree::<Tree>(CacheKey::CommRLastTree, base_leafs, &replica_config)
+            .unwrap();
+        assert_eq!(tree_r_last.root(), root);
+        drop(tree_r_last);
+
+        let reopened = cache
+            .open_lc_tree::<Tree>(CacheKey::CommRLastTree, leafs, &replica_config)
+            .unwrap();
+        assert_eq!(reopened.root(), root);
+
+        // Building from the replica file yields the same tree.
+        let from_replica = cache
+            .persist_lc_tree_from_replica::<Tree>(CacheKey::CommCTree, leafs, &replica_config)
+            .unwrap();
+        assert_eq!(from_replica.root(), root);
+
+        let rows_to_discard = cache.rows_to_discard(leafs, Tree::Arity::to_usize());
+        let proof = reopened
+            .gen_cached_proof(count * leafs - 1, Some(rows_to_discard))
+            .unwrap();
+        assert!(proof.verify());
+    }
+
+    #[test]
+    fn cache_dir_roundtrip_single() {
+        check_roundtrip::<DiskTree<H, U8, U0, U0>>(64);
+    }
+
+    #[test]
+    fn cache_dir_roundtrip_sub() {
+        check_roundtrip::<DiskTree<H, U8, U2, U0>>(64);
+    }
+
+    #[test]
+    fn cache_dir_roundtrip_top() {
+        check_roundtrip::<DiskTree<H, U8, U8, U2>>(64);
+    }
+
+    #[test]
+    fn cache_dir_reports_missing_and_truncated_files() {
+        type Tree = DiskTree<H, U2, U2, U0>;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+        cache
+            .persist_disk_tree::<Tree>(CacheKey::CommCTree, random_leafs(2, 32))
+            .unwrap();
+
+        let configs = cache.tree_configs::<Tree>(CacheKey::CommCTree, 32).unwrap();
+        assert_eq!(configs[0].id, "tree-c-0");
+        assert_eq!(configs[1].id, "tree-c-1");
+
+        let path = StoreConfig::data_path(&configs[1].path, &configs[1].id);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(100).unwrap();
+
+        let err = cache
+            .open_disk_tree::<Tree>(CacheKey::CommCTree, 32)
+            .unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::TruncatedCacheFile {
+                key,
+                expected,
+                found,
+                ..
+            }) => {
+                assert_eq!(key, "tree-c-1");
+                assert_eq!(*expected, 63 * 32);
+                assert_eq!(*found, 100);
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        let err = cache
+            .open_disk_tree::<Tree>(CacheKey::CommCTree, 32)
+            .unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::MissingCacheFile { key, path: missing }) => {
+                assert_eq!(key, "tree-c-1");
+                assert_eq!(missing, &path);
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        assert!(cache
+            .open_disk_tree::<Tree>(CacheKey::CommRLastTree, 32)
+            .is_err());
+    }
+}
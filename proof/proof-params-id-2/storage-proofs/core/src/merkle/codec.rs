@@ -0,0 +1,381 @@
+use generic_array::typenum::Unsigned;
+use merkletree::merkle::Element;
+
+use crate::hasher::{Domain, Hasher, PoseidonArity};
+
+use super::{compound_path_length, InclusionPath, MerkleProof, MerkleProofTrait, PathElement};
+
+/// Current version of the binary proof encoding.
+pub const PROOF_CODEC_VERSION: u8 = 1;
+
+const SHAPE_SINGLE: u8 = 0;
+const SHAPE_SUB: u8 = 1;
+const SHAPE_TOP: u8 = 2;
+
+/// Size of the version, shape and path index header.
+const HEADER_LEN: usize = 1 + 1 + 8;
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ProofCodecError {
+    #[error("unsupported proof encoding version {0}")]
+    UnsupportedVersion(u8),
+    #[error("proof shape {found} does not match the expected shape {expected}")]
+    ShapeMismatch { expected: u8, found: u8 },
+    #[error("a tree of {0} leaves is not valid for this proof shape")]
+    InvalidLeaves(usize),
+    #[error("encoded proof has {found} bytes, expected {expected}")]
+    InvalidLength { expected: usize, found: usize },
+    #[error("path index {index} is out of range for {leaves} leaves")]
+    InvalidPathIndex { index: u64, leaves: usize },
+    #[error("invalid hash at byte offset {0}")]
+    InvalidDomain(usize),
+    #[error("proofs with a top tree but no sub tree are not supported")]
+    InvalidShape,
+}
+
+/// Compact binary encoding of `MerkleProof`.
+///
+/// Layout, all integers little endian:
+///
+/// ```text
+/// version: u8 | shape: u8 | path index: u64 | leaf | root | siblings
+/// ```
+///
+/// The shape is 0 for single, 1 for sub and 2 for top tree proofs. Siblings
+/// are written level by level from the leaf up, `arity - 1` hashes per level;
+/// the position of the path at every level is recovered from the path index.
+/// The number of levels is implied by the number of leaves of the tree, which
+/// the decoder therefore needs to know.
+impl<
+        H: Hasher,
+        BaseArity: 'static + PoseidonArity,
+        SubTreeArity: 'static + PoseidonArity,
+        TopTreeArity: 'static + PoseidonArity,
+        G: Hasher,
+    > MerkleProof<H, BaseArity, SubTreeArity, TopTreeArity, G>
+{
+    /// Length in bytes of the encoding of any proof into a tree of `leaves` leaves.
+    pub fn encoded_len(leaves: usize) -> Result<usize, ProofCodecError> {
+        let siblings: usize = Self::level_arities(leaves)?
+            .iter()
+            .map(|arity| arity - 1)
+            .sum();
+
+        Ok(HEADER_LEN + (siblings + 2) * H::Domain::byte_len())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let path = self.path();
+        let node_size = H::Domain::byte_len();
+        let siblings: usize = path.iter().map(|(hashes, _)| hashes.len()).sum();
+
+        let mut out = Vec::with_capacity(HEADER_LEN + (siblings + 2) * node_size);
+        out.push(PROOF_CODEC_VERSION);
+        out.push(Self::shape());
+        out.extend_from_slice(&(self.path_index() as u64).to_le_bytes());
+        out.extend_from_slice(self.leaf().as_ref());
+        out.extend_from_slice(self.root().as_ref());
+        for (hashes, _) in &path {
+            for hash in hashes {
+                out.extend_from_slice(hash.as_ref());
+            }
+        }
+
+        out
+    }
+
+    /// Decodes a proof into a tree of `leaves` leaves, validating the shape,
+    /// the length and the path index.
+    pub fn decode(bytes: &[u8], leaves: usize) -> Result<Self, ProofCodecError> {
+        let arities = Self::level_arities(leaves)?;
+        let expected = Self::encoded_len(leaves)?;
+
+        if bytes.len() < HEADER_LEN {
+            return Err(ProofCodecError::InvalidLength {
+                expected,
+                found: bytes.len(),
+            });
+        }
+        if bytes[0] != PROOF_CODEC_VERSION {
+            return Err(ProofCodecError::UnsupportedVersion(bytes[0]));
+        }
+        if bytes[1] != Self::shape() {
+            return Err(ProofCodecError::ShapeMismatch {
+                expected: Self::shape(),
+                found: bytes[1],
+            });
+        }
+        if bytes.len() != expected {
+            return Err(ProofCodecError::InvalidLength {
+                expected,
+                found: bytes.len(),
+            });
+        }
+
+        let mut index_bytes = [0u8; 8];
+        index_bytes.copy_from_slice(&bytes[2..HEADER_LEN]);
+        let path_index = u64::from_le_bytes(index_bytes);
+        if path_index >= leaves as u64 {
+            return Err(ProofCodecError::InvalidPathIndex {
+                index: path_index,
+                leaves,
+            });
+        }
+
+        let node_size = H::Domain::byte_len();
+        let mut offset = HEADER_LEN;
+        let mut read_domain = || {
+            let start = offset;
+            offset += node_size;
+            H::Domain::try_from_bytes(&bytes[start..offset])
+                .map_err(|_| ProofCodecError::InvalidDomain(start))
+        };
+
+        let leaf = read_domain()?;
+        let root = read_domain()?;
+
+        let mut position = path_index as usize;
+        let mut levels = Vec::with_capacity(arities.len());
+        for arity in &arities {
+            let hashes = (1..*arity)
+                .map(|_| read_domain())
+                .collect::<Result<Vec<_>, _>>()?;
+            levels.push((hashes, position % arity));
+            position /= arity;
+        }
+
+        let base_len = Self::base_levels(arities.len());
+        let mut levels = levels.into_iter();
+        let base_path = Self::inclusion_path(levels.by_ref().take(base_len));
+        let sub_path = if SubTreeArity::to_usize() > 0 {
+            Some(Self::inclusion_path(levels.by_ref().take(1)))
+        } else {
+            None
+        };
+        let top_path = if TopTreeArity::to_usize() > 0 {
+            Some(Self::inclusion_path(levels.take(1)))
+        } else {
+            None
+        };
+
+        Self::from_parts(leaf, root, base_path, sub_path, top_path)
+            .map_err(|_| ProofCodecError::InvalidShape)
+    }
+
+    fn shape() -> u8 {
+        if TopTreeArity::to_usize() > 0 {
+            SHAPE_TOP
+        } else if SubTreeArity::to_usize() > 0 {
+            SHAPE_SUB
+        } else {
+            SHAPE_SINGLE
+        }
+    }
+
+    fn base_levels(levels: usize) -> usize {
+        let mut base = levels;
+        if SubTreeArity::to_usize() > 0 {
+            base -= 1;
+        }
+        if TopTreeArity::to_usize() > 0 {
+            base -= 1;
+        }
+        base
+    }
+
+    /// Arities of all levels, from the leaves up, for a tree of `leaves` leaves.
+    fn level_arities(leaves: usize) -> Result<Vec<usize>, ProofCodecError> {
+        let base_arity = BaseArity::to_usize();
+        let sub_arity = SubTreeArity::to_usize();
+        let top_arity = TopTreeArity::to_usize();
+        if top_arity > 0 && sub_arity == 0 {
+            return Err(ProofCodecError::InvalidShape);
+        }
+
+        let base_trees = sub_arity.max(1) * top_arity.max(1);
+        if leaves == 0 || leaves % base_trees != 0 {
+            return Err(ProofCodecError::InvalidLeaves(leaves));
+        }
+
+        // Equivalent to `base_path_length`, without assuming valid input.
+        let mut base_leaves = leaves / base_trees;
+        let mut arities = Vec::new();
+        while base_leaves > 1 {
+            if base_leaves % base_arity != 0 {
+                return Err(ProofCodecError::InvalidLeaves(leaves));
+            }
+            base_leaves /= base_arity;
+            arities.push(base_arity);
+        }
+        if arities.is_empty() {
+            return Err(ProofCodecError::InvalidLeaves(leaves));
+        }
+
+        if sub_arity > 0 {
+            arities.push(sub_arity);
+        }
+        if top_arity > 0 {
+            arities.push(top_arity);
+        }
+        debug_assert_eq!(
+            arities.len(),
+            compound_path_length::<BaseArity, SubTreeArity, TopTreeArity>(leaves)
+        );
+
+        Ok(arities)
+    }
+
+    fn inclusion_path<A: PoseidonArity>(
+        levels: impl Iterator<Item = (Vec<H::Domain>, usize)>,
+    ) -> InclusionPath<H, A> {
+        levels
+            .map(|(hashes, index)| PathElement::new(hashes, index))
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use generic_array::typenum::{U0, U2, U4, U8};
+
+    use crate::hasher::Sha256Hasher;
+    use crate::merkle::{DiskStore, MerkleTreeTrait, MerkleTreeWrapper};
+
+    type H = Sha256Hasher;
+    type Store = DiskStore<<H as Hasher>::Domain>;
+
+    fn base_tree<U: PoseidonArity>(leaves: usize) -> MerkleTreeWrapper<H, Store, U, U0, U0> {
+        let mut rng = rand::thread_rng();
+        MerkleTreeWrapper::new((0..leaves).map(|_| <H as Hasher>::Domain::random(&mut rng)))
+            .unwrap()
+    }
+
+    fn roundtrip<U, V, W>(tree: &MerkleTreeWrapper<H, Store, U, V, W>)
+    where
+        U: 'static + PoseidonArity,
+        V: 'static + PoseidonArity,
+        W: 'static + PoseidonArity,
+    {
+        type Proof<U, V, W> = MerkleProof<H, U, V, W>;
+
+        let leaves = tree.leaves();
+        let node_size = <H as Hasher>::Domain::byte_len();
+
+        for i in &[0, 1, leaves / 2, leaves - 1] {
+            let proof = tree.gen_proof(*i).unwrap();
+            let encoded = proof.encode();
+            assert_eq!(
+                encoded.len(),
+                Proof::<U, V, W>::encoded_len(leaves).unwrap()
+            );
+
+            let decoded = Proof::<U, V, W>::decode(&encoded, leaves).unwrap();
+            assert!(decoded.verify());
+            assert!(decoded.validate(*i));
+            assert_eq!(decoded.leaf(), proof.leaf());
+            assert_eq!(decoded.root(), proof.root());
+            assert_eq!(decoded.path(), proof.path());
+            assert_eq!(
+                decoded.path().len(),
+                compound_path_length::<U, V, W>(leaves)
+            );
+
+            // Truncated and extended inputs are rejected, not panicked on.
+            for len in &[0, 1, HEADER_LEN, encoded.len() - 1] {
+                assert!(matches!(
+                    Proof::<U, V, W>::decode(&encoded[..*len], leaves),
+                    Err(ProofCodecError::InvalidLength { .. })
+                ));
+            }
+            let mut extended = encoded.clone();
+            extended.extend_from_slice(&vec![0u8; node_size]);
+            assert!(matches!(
+                Proof::<U, V, W>::decode(&extended, leaves),
+                Err(ProofCodecError::InvalidLength { .. })
+            ));
+
+            let mut bad = encoded.clone();
+            bad[0] = PROOF_CODEC_VERSION + 1;
+            assert_eq!(
+                Proof::<U, V, W>::decode(&bad, leaves).unwrap_err(),
+                ProofCodecError::UnsupportedVersion(PROOF_CODEC_VERSION + 1)
+            );
+
+            let mut bad = encoded.clone();
+            bad[1] = 7;
+            assert!(matches!(
+                Proof::<U, V, W>::decode(&bad, leaves),
+                Err(ProofCodecError::ShapeMismatch { found: 7, .. })
+            ));
+
+            let mut bad = encoded.clone();
+            bad[2..HEADER_LEN].copy_from_slice(&(leaves as u64).to_le_bytes());
+            assert!(matches!(
+                Proof::<U, V, W>::decode(&bad, leaves),
+                Err(ProofCodecError::InvalidPathIndex { .. })
+            ));
+
+            // A corrupted sibling decodes, but no longer verifies.
+            let mut bad = encoded.clone();
+            let last = bad.len() - 1;
+            bad[last] ^= 1;
+            assert!(!Proof::<U, V, W>::decode(&bad, leaves).unwrap().verify());
+
+            assert!(Proof::<U, V, W>::decode(&encoded, leaves * U::to_usize()).is_err());
+        }
+    }
+
+    #[test]
+    fn codec_single_2() {
+        roundtrip(&base_tree::<U2>(64));
+    }
+
+    #[test]
+    fn codec_single_8() {
+        roundtrip(&base_tree::<U8>(64));
+    }
+
+    #[test]
+    fn codec_sub_8_2() {
+        let trees = (0..2).map(|_| base_tree::<U8>(64)).collect();
+        roundtrip(&MerkleTreeWrapper::<H, Store, U8, U2, U0>::from_trees(trees).unwrap());
+    }
+
+    #[test]
+    fn codec_top_8_4_2() {
+        let sub_trees = (0..2)
+            .map(|_| {
+                let trees = (0..4).map(|_| base_tree::<U8>(64)).collect();
+                MerkleTreeWrapper::<H, Store, U8, U4, U0>::from_trees(trees).unwrap()
+            })
+            .collect();
+        roundtrip(&MerkleTreeWrapper::<H, Store, U8, U4, U2>::from_sub_trees(sub_trees).unwrap());
+    }
+
+    #[test]
+    fn codec_rejects_invalid_leaves() {
+        for leaves in &[0, 1, 12, 63] {
+            assert_eq!(
+                MerkleProof::<H, U8>::encoded_len(*leaves),
+                Err(ProofCodecError::InvalidLeaves(*leaves))
+            );
+        }
+    }
+
+    #[test]
+    fn codec_rejects_invalid_shape() {
+        type Proof = MerkleProof<H, U2, U0, U2>;
+
+        let mut bytes = vec![0u8; HEADER_LEN + 4 * <H as Hasher>::Domain::byte_len()];
+        bytes[0] = PROOF_CODEC_VERSION;
+        bytes[1] = SHAPE_TOP;
+        assert_eq!(Proof::encoded_len(4), Err(ProofCodecError::InvalidShape));
+        assert_eq!(
+            Proof::decode(&bytes, 4).map(|_| ()),
+            Err(ProofCodecError::InvalidShape)
+        );
+    }
+}
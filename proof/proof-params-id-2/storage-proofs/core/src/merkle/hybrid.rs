@@ -0,0 +1,396 @@
+use std::marker::PhantomData;
+
+use anyhow::{bail, ensure, Result};
+use generic_array::typenum::{self, Unsigned};
+use merkletree::hash::Algorithm;
+use merkletree::merkle;
+
+use crate::hasher::{Domain, Hasher, PoseidonArity};
+
+use super::*;
+
+/// Converts a node between the domains of two hashers, keeping its bytes.
+///
+/// Fails if the bytes are not a valid `B`, e.g. not a field element.
+pub fn convert_domain<A: Domain, B: Domain>(node: &A) -> Result<B> {
+    B::try_from_bytes(node.as_ref())
+}
+
+/// A merkle tree whose base trees are hashed with `H`, while the sub and top
+/// tree levels above them are hashed with `G`.
+///
+/// This allows e.g. SHA-256 at the leaves for interoperability, with a circuit
+/// friendly hash near the root. Nodes of the upper levels are stored and
+/// proven as `H::Domain`, carrying the bytes of the `G::Domain` values.
+///
+/// Only the base trees are persisted, using `S`; the few nodes above them are
+/// kept in memory.
+pub struct HybridMerkleTree<
+    H: Hasher,
+    G: Hasher,
+    S: Store<<H as Hasher>::Domain>,
+    U: PoseidonArity,
+    V: PoseidonArity,
+    W: PoseidonArity = typenum::U0,
+> {
+    base_trees: Vec<MerkleTreeWrapper<H, S, U, typenum::U0, typenum::U0>>,
+    base_roots: Vec<H::Domain>,
+    /// Roots of the sub trees, one for every `V` base trees.
+    sub_roots: Vec<H::Domain>,
+    root: H::Domain,
+    _g: PhantomData<G>,
+    _v: PhantomData<V>,
+    _w: PhantomData<W>,
+}
+
+impl<
+        H: 'static + Hasher,
+        G: 'static + Hasher,
+        S: Store<<H as Hasher>::Domain>,
+        U: 'static + PoseidonArity,
+        V: 'static + PoseidonArity,
+        W: 'static + PoseidonArity,
+    > MerkleTreeTrait for HybridMerkleTree<H, G, S, U, V, W>
+{
+    type Arity = U;
+    type SubTreeArity = V;
+    type TopTreeArity = W;
+    type Hasher = H;
+    type TopHasher = G;
+    type Store = S;
+    type Proof = MerkleProof<H, U, V, W, G>;
+
+    fn display() -> String {
+        format!(
+            "merkletree-{}+{}-{}-{}-{}",
+            H::name(),
+            G::name(),
+            U::to_usize(),
+            V::to_usize(),
+            W::to_usize()
+        )
+    }
+
+    fn root(&self) -> H::Domain {
+        self.root
+    }
+
+    fn gen_proof(&self, i: usize) -> Result<Self::Proof> {
+        let (tree, j) = self.locate(i)?;
+        let base_proof = self.base_trees[tree].gen_proof(j)?;
+
+        self.complete_proof(tree, base_proof)
+    }
+
+    fn gen_cached_proof(&self, i: usize, rows_to_discard: Option<usize>) -> Result<Self::Proof> {
+        let (tree, j) = self.locate(i)?;
+        let base_proof = self.base_trees[tree].gen_cached_proof(j, rows_to_discard)?;
+
+        self.complete_proof(tree, base_proof)
+    }
+
+    fn row_count(&self) -> usize {
+        let upper_rows = if W::to_usize() > 0 { 2 } else { 1 };
+        self.base_trees[0].row_count() + upper_rows
+    }
+
+    fn leaves(&self) -> usize {
+        self.base_trees[0].leaves() * self.base_trees.len()
+    }
+
+    fn from_merkle(
+        _tree: merkle::MerkleTree<
+            <Self::Hasher as Hasher>::Domain,
+            <Self::Hasher as Hasher>::Function,
+            Self::Store,
+            Self::Arity,
+            Self::SubTreeArity,
+            Self::TopTreeArity,
+        >,
+    ) -> Result<Self> {
+        bail!("hybrid trees are built from their base trees, see `from_trees`")
+    }
+}
+
+impl<
+        H: 'static + Hasher,
+        G: 'static + Hasher,
+        S: Store<<H as Hasher>::Domain>,
+        U: 'static + PoseidonArity,
+        V: 'static + PoseidonArity,
+        W: 'static + PoseidonArity,
+    > HybridMerkleTree<H, G, S, U, V, W>
+{
+    /// Builds the sub and top levels with `G` over the given base trees, of
+    /// which there must be `V`, or `V * W` for top trees.
+    pub fn from_trees(
+        trees: Vec<MerkleTreeWrapper<H, S, U, typenum::U0, typenum::U0>>,
+    ) -> Result<Self> {
+        let sub_arity = V::to_usize();
+        ensure!(sub_arity > 0, "hybrid trees need a sub tree arity");
+        ensure!(
+            trees.len() == get_base_tree_count::<Self>(),
+            "expected {} base trees, got {}",
+            get_base_tree_count::<Self>(),
+            trees.len()
+        );
+        let leafs = trees[0].leaves();
+        ensure!(
+            trees.iter().all(|tree| tree.leaves() == leafs),
+            "base trees must have the same number of leaves"
+        );
+
+        let base_roots: Vec<_> = trees.iter().map(|tree| tree.root()).collect();
+        let sub_roots = base_roots
+            .chunks(sub_arity)
+            .map(Self::hash_nodes)
+            .collect::<Result<Vec<_>>>()?;
+        let root = if W::to_usize() > 0 {
+            Self::hash_nodes(&sub_roots)?
+        } else {
+            sub_roots[0]
+        };
+
+        Ok(HybridMerkleTree {
+            base_trees: trees,
+            base_roots,
+            sub_roots,
+            root,
+            _g: PhantomData,
+            _v: PhantomData,
+            _w: PhantomData,
+        })
+    }
+
+    pub fn base_trees(&self) -> &[MerkleTreeWrapper<H, S, U, typenum::U0, typenum::U0>] {
+        &self.base_trees
+    }
+
+    /// Index of the base tree holding leaf `i`, and the index of the leaf
+    /// within it.
+    fn locate(&self, i: usize) -> Result<(usize, usize)> {
+        ensure!(
+            i < self.leaves(),
+            "index {} out of bounds for {} leaves",
+            i,
+            self.leaves()
+        );
+        let base_leafs = self.base_trees[0].leaves();
+
+        Ok((i / base_leafs, i % base_leafs))
+    }
+
+    /// Extends a proof of base tree `tree` with the sub and top levels.
+    fn complete_proof(
+        &self,
+        tree: usize,
+        base_proof: <MerkleTreeWrapper<H, S, U, typenum::U0, typenum::U0> as MerkleTreeTrait>::Proof,
+    ) -> Result<MerkleProof<H, U, V, W, G>> {
+        let sub_arity = V::to_usize();
+        let base_path: InclusionPath<H, U> = base_proof
+            .path()
+            .into_iter()
+            .map(|(hashes, index)| PathElement::new(hashes, index))
+            .collect::<Vec<_>>()
+            .into();
+
+        let group = tree / sub_arity;
+        let sub_path = Self::level_path::<V>(
+            &self.base_roots[group * sub_arity..(group + 1) * sub_arity],
+            tree % sub_arity,
+        );
+        let top_path = if W::to_usize() > 0 {
+            Some(Self::level_path::<W>(&self.sub_roots, group))
+        } else {
+            None
+        };
+
+        MerkleProof::from_parts(
+            base_proof.leaf(),
+            self.root,
+            base_path,
+            Some(sub_path),
+            top_path,
+        )
+    }
+
+    /// Single level path for the node at `index` among `nodes`.
+    fn level_path<A: PoseidonArity>(nodes: &[H::Domain], index: usize) -> InclusionPath<H, A> {
+        let siblings = nodes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != index)
+            .map(|(_, node)| *node)
+            .collect();
+
+        vec![PathElement::new(siblings, index)].into()
+    }
+
+    /// Hashes `nodes` into their parent with `G`. Heights restart at the sub
+    /// and top trees, as in `MerkleProof::verify`.
+    fn hash_nodes(nodes: &[H::Domain]) -> Result<H::Domain> {
+        let nodes = nodes
+            .iter()
+            .map(convert_domain::<H::Domain, G::Domain>)
+            .collect::<Result<Vec<_>>>()?;
+        let mut a = G::Function::default();
+
+        convert_domain(&a.multi_node(&nodes, 0))
+    }
+}
+
+impl<
+        H: Hasher,
+        G: Hasher,
+        S: Store<<H as Hasher>::Domain>,
+        U: PoseidonArity,
+        V: PoseidonArity,
+        W: PoseidonArity,
+    > std::fmt::Debug for HybridMerkleTree<H, G, S, U, V, W>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HybridMerkleTree")
+            .field("base_trees", &self.base_trees)
+            .field("root", &self.root)
+            .field("Hasher", &H::name())
+            .field("TopHasher", &G::name())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use generic_array::typenum::{U0, U2, U4, U8};
+
+    use crate::hasher::{PoseidonHasher, Sha256Hasher};
+
+    type H = Sha256Hasher;
+    type Store = DiskStore<<H as Hasher>::Domain>;
+
+    type D = <H as Hasher>::Domain;
+
+    fn random_leafs(count: usize, leaves: usize) -> Vec<Vec<D>> {
+        let mut rng = rand::thread_rng();
+        (0..count)
+            .map(|_| (0..leaves).map(|_| D::random(&mut rng)).collect())
+            .collect()
+    }
+
+    fn base_trees(leafs: &[Vec<D>]) -> Vec<MerkleTreeWrapper<H, Store, U8, U0, U0>> {
+        leafs
+            .iter()
+            .map(|leafs| MerkleTreeWrapper::new(leafs.iter().copied()).unwrap())
+            .collect()
+    }
+
+    fn check_proofs<Tree: MerkleTreeTrait<Hasher = H>>(tree: &Tree) {
+        let mut rng = rand::thread_rng();
+        for i in (0..tree.leaves()).step_by(7) {
+            let proof = tree.gen_proof(i).unwrap();
+            assert!(proof.verify());
+            assert!(proof.validate(i));
+            assert_eq!(proof.root(), tree.root());
+
+            let json = serde_json::to_string(&proof).unwrap();
+            let decoded: Tree::Proof = serde_json::from_str(&json).unwrap();
+            assert!(decoded.verify());
+
+            let mut broken = proof.clone();
+            broken.break_me(D::random(&mut rng));
+            assert!(!broken.verify());
+        }
+        assert!(tree.gen_proof(tree.leaves()).is_err());
+    }
+
+    #[test]
+    fn hybrid_sub_tree_matches_single_hasher_tree() {
+        let leafs = random_leafs(4, 64);
+        let hybrid =
+            HybridMerkleTree::<H, H, Store, U8, U4>::from_trees(base_trees(&leafs)).unwrap();
+        let plain =
+            MerkleTreeWrapper::<H, Store, U8, U4, U0>::from_trees(base_trees(&leafs)).unwrap();
+
+        assert_eq!(hybrid.root(), plain.root());
+        assert_eq!(hybrid.leaves(), plain.leaves());
+        assert_eq!(hybrid.row_count(), plain.row_count());
+        for i in &[0, 100, 255] {
+            assert_eq!(
+                hybrid.gen_proof(*i).unwrap().path(),
+                plain.gen_proof(*i).unwrap().path()
+            );
+        }
+        check_proofs(&hybrid);
+    }
+
+    #[test]
+    fn hybrid_top_tree_matches_single_hasher_tree() {
+        let leafs = random_leafs(8, 64);
+        let hybrid =
+            HybridMerkleTree::<H, H, Store, U8, U4, U2>::from_trees(base_trees(&leafs)).unwrap();
+        let sub_trees = leafs
+            .chunks(4)
+            .map(|leafs| {
+                MerkleTreeWrapper::<H, Store, U8, U4, U0>::from_trees(base_trees(leafs)).unwrap()
+            })
+            .collect();
+        let plain = MerkleTreeWrapper::<H, Store, U8, U4, U2>::from_sub_trees(sub_trees).unwrap();
+
+        assert_eq!(hybrid.root(), plain.root());
+        assert_eq!(hybrid.row_count(), plain.row_count());
+        check_proofs(&hybrid);
+    }
+
+    #[test]
+    fn hybrid_tree_with_poseidon_upper_levels() {
+        type G = PoseidonHasher;
+
+        let leafs = random_leafs(8, 64);
+        let hybrid =
+            HybridMerkleTree::<H, G, Store, U8, U4, U2>::from_trees(base_trees(&leafs)).unwrap();
+
+        // The sub and top levels are hashed with Poseidon over the roots below.
+        let mut a = <G as Hasher>::Function::default();
+        let mut hash = |nodes: &[D]| -> D {
+            let nodes: Vec<<G as Hasher>::Domain> = nodes
+                .iter()
+                .map(|node| convert_domain(node).unwrap())
+                .collect();
+            a.reset();
+            convert_domain(&a.multi_node(&nodes, 0)).unwrap()
+        };
+        let base_roots: Vec<D> = base_trees(&leafs).iter().map(|tree| tree.root()).collect();
+        let sub_roots: Vec<D> = base_roots.chunks(4).map(&mut hash).collect();
+        assert_eq!(hybrid.root(), hash(&sub_roots));
+
+        let sub_trees = leafs
+            .chunks(4)
+            .map(|leafs| {
+                MerkleTreeWrapper::<H, Store, U8, U4, U0>::from_trees(base_trees(leafs)).unwrap()
+            })
+            .collect();
+        let plain = MerkleTreeWrapper::<H, Store, U8, U4, U2>::from_sub_trees(sub_trees).unwrap();
+        assert_ne!(hybrid.root(), plain.root());
+
+        check_proofs(&hybrid);
+    }
+
+    #[test]
+    fn hybrid_tree_requires_all_base_trees() {
+        let trees = base_trees(&random_leafs(3, 64));
+        assert!(HybridMerkleTree::<H, H, Store, U8, U4>::from_trees(trees).is_err());
+
+        let mut trees = base_trees(&random_leafs(1, 64));
+        trees.extend(base_trees(&random_leafs(1, 8)));
+        assert!(HybridMerkleTree::<H, H, Store, U8, U2>::from_trees(trees).is_err());
+    }
+
+    #[test]
+    fn hybrid_tree_display() {
+        assert_eq!(
+            HybridMerkleTree::<H, H, Store, U8, U4, U2>::display(),
+            "merkletree-sha256_hasher+sha256_hasher-8-4-2"
+        );
+    }
+}
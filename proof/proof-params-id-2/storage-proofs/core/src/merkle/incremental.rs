@@ -0,0 +1,315 @@
+use std::marker::PhantomData;
+
+use anyhow::{ensure, Result};
+use generic_array::typenum::Unsigned;
+use merkletree::hash::Algorithm;
+use merkletree::merkle::{get_merkle_tree_len, get_merkle_tree_row_count, Element};
+use merkletree::store::StoreConfig;
+
+use crate::hasher::{Hasher, PoseidonArity};
+
+use super::*;
+
+/// Number of nodes buffered per row before they are written to the store.
+const WRITE_BUFFER_NODES: usize = 1024;
+
+/// A merkle tree which is built while its leaves arrive, in order.
+///
+/// Only the frontier, the nodes of each row whose siblings have not all been
+/// seen yet, is kept in memory. Every other node is written to a `DiskStore`
+/// as soon as it is known, so once all leaves are pushed the store holds the
+/// complete tree and `finalize` does not need to hash anything.
+#[derive(Debug)]
+pub struct IncrementalMerkleTree<H: Hasher, U: PoseidonArity> {
+    leafs: usize,
+    pushed: usize,
+    /// Pending nodes of every row, fewer than the arity each.
+    frontier: Vec<Vec<H::Domain>>,
+    /// Root of a subtree of only default leaves, for every row.
+    empty_roots: Vec<H::Domain>,
+    row_starts: Vec<usize>,
+    /// Nodes not yet written to the store and number of nodes written, per row.
+    buffers: Vec<Vec<u8>>,
+    written: Vec<usize>,
+    store: DiskStore<H::Domain>,
+    _u: PhantomData<U>,
+}
+
+impl<H: 'static + Hasher, U: 'static + PoseidonArity> IncrementalMerkleTree<H, U> {
+    /// Creates a tree of `leafs` leaves, backed by a temporary store.
+    pub fn new(leafs: usize) -> Result<Self> {
+        let len = get_merkle_tree_len(leafs, U::to_usize())?;
+        Self::from_store(leafs, DiskStore::new(len)?)
+    }
+
+    /// Creates a tree of `leafs` leaves, persisted at `config`.
+    pub fn new_with_config(leafs: usize, config: StoreConfig) -> Result<Self> {
+        let len = get_merkle_tree_len(leafs, U::to_usize())?;
+        let path = StoreConfig::data_path(&config.path, &config.id);
+        ensure!(!path.exists(), "{:?} already exists", path);
+
+        Self::from_store(
+            leafs,
+            DiskStore::new_with_config(len, U::to_usize(), config)?,
+        )
+    }
+
+    fn from_store(leafs: usize, store: DiskStore<H::Domain>) -> Result<Self> {
+        let arity = U::to_usize();
+        ensure!(arity > 1, "invalid arity {}", arity);
+        let row_count = get_merkle_tree_row_count(leafs, arity);
+        ensure!(
+            arity.pow(row_count as u32 - 1) == leafs,
+            "{} leaves do not make a full tree of arity {}",
+            leafs,
+            arity
+        );
+
+        let mut a = H::Function::default();
+        let mut empty_roots = vec![H::Domain::default()];
+        let mut row_starts = vec![0];
+        for row in 1..row_count {
+            let children = vec![empty_roots[row - 1]; arity];
+            a.reset();
+            empty_roots.push(a.multi_node(&children, row - 1));
+            row_starts.push(row_starts[row - 1] + leafs / arity.pow(row as u32 - 1));
+        }
+
+        Ok(IncrementalMerkleTree {
+            leafs,
+            pushed: 0,
+            frontier: vec![Vec::with_capacity(arity); row_count],
+            empty_roots,
+            row_starts,
+            buffers: vec![Vec::new(); row_count],
+            written: vec![0; row_count],
+            store,
+            _u: PhantomData,
+        })
+    }
+
+    /// Number of leaves the finished tree has.
+    pub fn capacity(&self) -> usize {
+        self.leafs
+    }
+
+    /// Number of leaves pushed so far.
+    pub fn leaves(&self) -> usize {
+        self.pushed
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.pushed == self.leafs
+    }
+
+    /// Appends the next leaf.
+    pub fn push(&mut self, leaf: H::Domain) -> Result<()> {
+        ensure!(!self.is_full(), "tree of {} leaves is full", self.leafs);
+
+        let arity = U::to_usize();
+        let top = self.frontier.len() - 1;
+        let mut a = H::Function::default();
+        let mut node = leaf;
+        self.pushed += 1;
+
+        for row in 0..=top {
+            self.write(row, node)?;
+            if row == top {
+                break;
+            }
+
+            self.frontier[row].push(node);
+            if self.frontier[row].len() < arity {
+                break;
+            }
+
+            a.reset();
+            node = a.multi_node(&self.frontier[row], row);
+            self.frontier[row].clear();
+        }
+
+        Ok(())
+    }
+
+    /// Appends all leaves of `leaves`, in order.
+    pub fn extend<I: IntoIterator<Item = H::Domain>>(&mut self, leaves: I) -> Result<()> {
+        for leaf in leaves {
+            self.push(leaf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Root of the tree as if all leaves not pushed yet were
+    /// `H::Domain::default()`. Once the tree is full, this is its root.
+    pub fn root(&self) -> H::Domain {
+        let top = self.frontier.len() - 1;
+        if self.pushed == 0 {
+            return self.empty_roots[top];
+        }
+        if self.is_full() {
+            return self.last_written(top);
+        }
+
+        let arity = U::to_usize();
+        let mut a = H::Function::default();
+        let mut carry = None;
+        for row in 0..top {
+            let mut nodes = self.frontier[row].clone();
+            nodes.extend(carry);
+            if nodes.is_empty() {
+                continue;
+            }
+            nodes.resize(arity, self.empty_roots[row]);
+
+            a.reset();
+            carry = Some(a.multi_node(&nodes, row));
+        }
+
+        carry.expect("partial tree without pending nodes")
+    }
+
+    /// Writes out the remaining nodes and returns the complete tree.
+    pub fn finalize(mut self) -> Result<MerkleTree<H, U>> {
+        ensure!(
+            self.is_full(),
+            "only {} of {} leaves were pushed",
+            self.pushed,
+            self.leafs
+        );
+
+        for row in 0..self.buffers.len() {
+            self.flush(row)?;
+        }
+        self.store.sync()?;
+
+        MerkleTree::from_data_store(self.store, self.leafs)
+    }
+
+    fn write(&mut self, row: usize, node: H::Domain) -> Result<()> {
+        self.buffers[row].extend_from_slice(node.as_ref());
+        if self.buffers[row].len() >= WRITE_BUFFER_NODES * H::Domain::byte_len() {
+            self.flush(row)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self, row: usize) -> Result<()> {
+        if self.buffers[row].is_empty() {
+            return Ok(());
+        }
+
+        let start = self.row_starts[row] + self.written[row];
+        self.store.copy_from_slice(&self.buffers[row], start)?;
+        self.written[row] += self.buffers[row].len() / H::Domain::byte_len();
+        self.buffers[row].clear();
+
+        Ok(())
+    }
+
+    /// The last node written to `row`, which may still be buffered.
+    fn last_written(&self, row: usize) -> H::Domain {
+        let elem_len = H::Domain::byte_len();
+        let buffer = &self.buffers[row];
+        if buffer.is_empty() {
+            let index = self.row_starts[row] + self.written[row] - 1;
+            self.store.read_at(index).expect("failed to read node")
+        } else {
+            H::Domain::from_slice(&buffer[buffer.len() - elem_len..])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use generic_array::typenum::{U0, U2, U8};
+
+    use crate::cache_key::CacheKey;
+    use crate::hasher::{Domain, Sha256Hasher};
+
+    type H = Sha256Hasher;
+    type D = <H as Hasher>::Domain;
+
+    fn check_incremental<U: 'static + PoseidonArity>(leafs: usize) {
+        let mut rng = rand::thread_rng();
+        let data: Vec<D> = (0..leafs).map(|_| D::random(&mut rng)).collect();
+
+        let mut tree = IncrementalMerkleTree::<H, U>::new(leafs).unwrap();
+        for (i, leaf) in data.iter().enumerate() {
+            if i % 7 == 0 || i == leafs - 1 {
+                let padded = data[..i]
+                    .iter()
+                    .copied()
+                    .chain(std::iter::repeat(D::default()))
+                    .take(leafs);
+                let expected = MerkleTree::<H, U>::new(padded).unwrap();
+                assert_eq!(
+                    tree.root(),
+                    expected.root(),
+                    "wrong root after {} leaves",
+                    i
+                );
+            }
+            tree.push(*leaf).unwrap();
+        }
+        assert!(tree.push(data[0]).is_err());
+
+        let expected = MerkleTree::<H, U>::new(data.iter().copied()).unwrap();
+        assert_eq!(tree.root(), expected.root());
+
+        let tree = tree.finalize().unwrap();
+        assert_eq!(tree.root(), expected.root());
+        assert_eq!(tree.len(), expected.len());
+        for i in 0..tree.len() {
+            assert_eq!(tree.read_at(i).unwrap(), expected.read_at(i).unwrap());
+        }
+        for i in &[0, leafs / 2, leafs - 1] {
+            assert!(tree.gen_proof(*i).unwrap().verify());
+        }
+    }
+
+    #[test]
+    fn incremental_tree_2() {
+        check_incremental::<U2>(1 << 12);
+    }
+
+    #[test]
+    fn incremental_tree_8() {
+        check_incremental::<U8>(1 << 12);
+    }
+
+    #[test]
+    fn incremental_tree_persisted() {
+        let mut rng = rand::thread_rng();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+        let config = cache.store_config(CacheKey::CommDTree, 0);
+
+        assert!(IncrementalMerkleTree::<H, U2>::new(100).is_err());
+
+        let mut tree =
+            IncrementalMerkleTree::<H, U8>::new_with_config(512, config.clone()).unwrap();
+        tree.extend((0..512).map(|_| D::random(&mut rng))).unwrap();
+        let root = tree.finalize().unwrap().root();
+
+        // An existing tree is never overwritten.
+        assert!(IncrementalMerkleTree::<H, U8>::new_with_config(512, config).is_err());
+
+        let reopened = cache
+            .open_disk_tree::<DiskTree<H, U8, U0, U0>>(CacheKey::CommDTree, 512)
+            .unwrap();
+        assert_eq!(reopened.root(), root);
+    }
+
+    #[test]
+    fn incremental_tree_requires_all_leaves() {
+        let mut rng = rand::thread_rng();
+        let mut tree = IncrementalMerkleTree::<H, U8>::new(64).unwrap();
+        tree.extend((0..63).map(|_| D::random(&mut rng))).unwrap();
+        assert!(!tree.is_full());
+        assert!(tree.finalize().is_err());
+    }
+}
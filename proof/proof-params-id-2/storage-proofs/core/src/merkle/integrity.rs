@@ -0,0 +1,369 @@
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+
+use anyhow::{ensure, Context, Result};
+use generic_array::typenum::Unsigned;
+use log::warn;
+use merkletree::hash::Algorithm;
+use merkletree::merkle::{get_merkle_tree_cache_size, get_merkle_tree_len, Element};
+use merkletree::store::{ReplicaConfig, StoreConfig};
+
+use crate::cache_key::CacheKey;
+use crate::hasher::Hasher;
+
+use super::*;
+
+/// Number of nodes read from a store at once while verifying it.
+const VERIFY_CHUNK_NODES: usize = 1 << 16;
+
+/// A run of consecutive nodes of one row of a stored tree which do not match
+/// the hash of their children.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptRange {
+    /// Id of the store config the nodes belong to.
+    pub id: String,
+    /// Row of the nodes, the leaves being row 0.
+    pub row: usize,
+    /// Index of the first bad node in the store.
+    pub start: usize,
+    /// Index one past the last bad node in the store.
+    pub end: usize,
+}
+
+/// Verifies every stored row of a base tree of shape `Tree` against its
+/// children, hashing with the tree's hasher.
+///
+/// `store` holds a tree of `leafs` leaves persisted at `config`, laid out as
+/// given by `kind`. Rows discarded from a level cache store are recomputed
+/// from the leaves. The leaves themselves are taken as the source of truth.
+///
+/// Returns the first range of bad nodes found, if any. With `repair` set, all
+/// bad nodes are overwritten with the recomputed values, and the rows above
+/// are checked against the repaired ones.
+pub fn verify_tree_store<Tree, S>(
+    store: &S,
+    config: &StoreConfig,
+    leafs: usize,
+    kind: StoreKind,
+    repair: bool,
+) -> Result<Option<CorruptRange>>
+where
+    Tree: MerkleTreeTrait,
+    S: Store<<Tree::Hasher as Hasher>::Domain>,
+{
+    let arity = Tree::Arity::to_usize();
+    let tree_len = get_merkle_tree_len(leafs, arity)?;
+    ensure!(
+        store.len() == tree_len,
+        "store of {} nodes does not hold a tree of {} leaves",
+        store.len(),
+        leafs
+    );
+
+    let mut row_lens = vec![leafs];
+    while *row_lens.last().expect("no rows") > 1 {
+        let next = row_lens.last().expect("no rows") / arity;
+        row_lens.push(next);
+    }
+    let row_starts: Vec<usize> = row_lens
+        .iter()
+        .scan(0, |start, len| {
+            let row_start = *start;
+            *start += len;
+            Some(row_start)
+        })
+        .collect();
+
+    // Rows between the leaves and the cached rows are not on disk.
+    let (rows_discarded, file_start) = match kind {
+        StoreKind::Disk => (0, 0),
+        StoreKind::LevelCache => (
+            config.rows_to_discard,
+            tree_len - get_merkle_tree_cache_size(leafs, arity, config.rows_to_discard)?,
+        ),
+    };
+    let is_stored = |row: usize| row == 0 || row > rows_discarded;
+
+    let elem_len = <Tree::Hasher as Hasher>::Domain::byte_len();
+    let mut file = None;
+    let mut first: Option<CorruptRange> = None;
+    let mut a = <Tree::Hasher as Hasher>::Function::default();
+
+    for row in (1..row_lens.len()).filter(|row| is_stored(*row)) {
+        // The closest stored row below, from which this row is computed.
+        let base_row = if is_stored(row - 1) { row - 1 } else { 0 };
+        let span = arity.pow((row - base_row) as u32);
+        let chunk = std::cmp::max(1, VERIFY_CHUNK_NODES / span);
+
+        for offset in (0..row_lens[row]).step_by(chunk) {
+            let count = std::cmp::min(chunk, row_lens[row] - offset);
+
+            let children_start = row_starts[base_row] + offset * span;
+            let mut computed = store.read_range(children_start..children_start + count * span)?;
+            for height in base_row..row {
+                computed = computed
+                    .chunks(arity)
+                    .map(|children| {
+                        a.reset();
+                        a.multi_node(children, height)
+                    })
+                    .collect();
+            }
+
+            let start = row_starts[row] + offset;
+            let stored = store.read_range(start..start + count)?;
+
+            for (i, (expected, found)) in computed.iter().zip(stored.iter()).enumerate() {
+                let index = start + i;
+                if expected == found {
+                    continue;
+                }
+
+                if let Some(range) = first.as_mut() {
+                    if range.row == row && range.end == index {
+                        range.end += 1;
+                    } else if !repair {
+                        return Ok(first);
+                    }
+                } else {
+                    first = Some(CorruptRange {
+                        id: config.id.clone(),
+                        row,
+                        start: index,
+                        end: index + 1,
+                    });
+                }
+
+                if repair {
+                    if file.is_none() {
+                        file = Some(
+                            OpenOptions::new()
+                                .write(true)
+                                .open(StoreConfig::data_path(&config.path, &config.id))?,
+                        );
+                    }
+                    let file = file.as_ref().expect("file not opened");
+                    file.write_all_at(expected.as_ref(), ((index - file_start) * elem_len) as u64)?;
+                }
+            }
+
+            // A closed range can not grow any more.
+            if !repair {
+                if let Some(range) = &first {
+                    if range.row != row || range.end < start + count {
+                        return Ok(first);
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(file) = file {
+        file.sync_all()?;
+    }
+
+    Ok(first)
+}
+
+/// Verifies all base trees of a `Tree` stored in `cache` under `key`, as
+/// `verify_tree_store` does for a single store. Level cache trees read their
+/// leaves from `replica_config`, which is required for them.
+///
+/// Missing or truncated files are reported as errors, see `CacheDir::check_tree`.
+/// Returns the first bad range of every corrupted base tree.
+pub fn verify_cached_tree<Tree: MerkleTreeTrait>(
+    cache: &CacheDir,
+    key: CacheKey,
+    base_tree_leafs: usize,
+    kind: StoreKind,
+    replica_config: Option<&ReplicaConfig>,
+    repair: bool,
+) -> Result<Vec<CorruptRange>> {
+    let arity = Tree::Arity::to_usize();
+    let base_tree_len = get_merkle_tree_len(base_tree_leafs, arity)?;
+    let configs = cache.check_tree::<Tree>(key, base_tree_leafs, kind)?;
+
+    let mut corrupted = Vec::new();
+    for (i, config) in configs.iter().enumerate() {
+        let range = match kind {
+            StoreKind::Disk => {
+                let store = DiskStore::new_from_disk(base_tree_len, arity, config)?;
+                verify_tree_store::<Tree, _>(&store, config, base_tree_leafs, kind, repair)?
+            }
+            StoreKind::LevelCache => {
+                let replica_config = replica_config
+                    .with_context(|| format!("a replica is required to verify {}", config.id))?;
+                let store = LCStore::new_from_disk_with_reader(
+                    base_tree_len,
+                    arity,
+                    config,
+                    ExternalReader::new_from_config(replica_config, i)?,
+                )?;
+                verify_tree_store::<Tree, _>(&store, config, base_tree_leafs, kind, repair)?
+            }
+        };
+
+        if let Some(range) = range {
+            warn!(
+                "{} is corrupted in row {}, nodes {}..{}{}",
+                range.id,
+                range.row,
+                range.start,
+                range.end,
+                if repair { ", repaired" } else { "" }
+            );
+            corrupted.push(range);
+        }
+    }
+
+    Ok(corrupted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use generic_array::typenum::{U0, U2, U8};
+
+    use crate::hasher::{Domain, Sha256Hasher};
+
+    type H = Sha256Hasher;
+    type D = <H as Hasher>::Domain;
+
+    fn random_leafs(count: usize, leafs: usize) -> Vec<Vec<D>> {
+        let mut rng = rand::thread_rng();
+        (0..count)
+            .map(|_| (0..leafs).map(|_| D::random(&mut rng)).collect())
+            .collect()
+    }
+
+    fn corrupt(config: &StoreConfig, offset: usize, len: usize) {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(StoreConfig::data_path(&config.path, &config.id))
+            .unwrap();
+        file.write_all_at(
+            &vec![0xff; len * D::byte_len()],
+            (offset * D::byte_len()) as u64,
+        )
+        .unwrap();
+    }
+
+    fn check_disk<Tree: MerkleTreeTrait<Hasher = H>>(leafs: usize) {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+        let count = get_base_tree_count::<Tree>();
+        let root = cache
+            .persist_disk_tree::<Tree>(CacheKey::CommCTree, random_leafs(count, leafs))
+            .unwrap()
+            .root();
+
+        let verify = |repair| {
+            verify_cached_tree::<Tree>(
+                &cache,
+                CacheKey::CommCTree,
+                leafs,
+                StoreKind::Disk,
+                None,
+                repair,
+            )
+            .unwrap()
+        };
+        assert!(verify(false).is_empty());
+
+        // Three nodes in row 1 of the last base tree.
+        let configs = cache
+            .tree_configs::<Tree>(CacheKey::CommCTree, leafs)
+            .unwrap();
+        let config = configs.last().unwrap();
+        corrupt(config, leafs + 2, 3);
+
+        let expected = CorruptRange {
+            id: config.id.clone(),
+            row: 1,
+            start: leafs + 2,
+            end: leafs + 5,
+        };
+        assert_eq!(verify(false), vec![expected.clone()]);
+        assert_eq!(verify(true), vec![expected]);
+        assert!(verify(false).is_empty());
+
+        let tree = cache
+            .open_disk_tree::<Tree>(CacheKey::CommCTree, leafs)
+            .unwrap();
+        assert_eq!(tree.root(), root);
+    }
+
+    #[test]
+    fn verify_tree_store_disk_single() {
+        check_disk::<DiskTree<H, U8, U0, U0>>(512);
+    }
+
+    #[test]
+    fn verify_tree_store_disk_sub() {
+        check_disk::<DiskTree<H, U2, U2, U0>>(64);
+    }
+
+    #[test]
+    fn verify_tree_store_disk_top() {
+        check_disk::<DiskTree<H, U8, U8, U2>>(64);
+    }
+
+    #[test]
+    fn verify_tree_store_level_cache() {
+        type Tree = DiskTree<H, U8, U2, U0>;
+        let leafs = 512;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path()).with_rows_to_discard(1);
+        let base_leafs = random_leafs(2, leafs);
+
+        let replica_path = dir.path().join("replica");
+        let mut replica = std::fs::File::create(&replica_path).unwrap();
+        for leaf in base_leafs.iter().flatten() {
+            replica.write_all(leaf.as_ref()).unwrap();
+        }
+        let replica_config = ReplicaConfig::new(&replica_path, vec![0, leafs * D::byte_len()]);
+
+        let root = cache
+            .persist_lc_tree::<Tree>(CacheKey::CommRLastTree, base_leafs, &replica_config)
+            .unwrap()
+            .root();
+
+        let verify = |repair| {
+            verify_cached_tree::<Tree>(
+                &cache,
+                CacheKey::CommRLastTree,
+                leafs,
+                StoreKind::LevelCache,
+                Some(&replica_config),
+                repair,
+            )
+            .unwrap()
+        };
+        assert!(verify(false).is_empty());
+
+        // The file starts at row 2, which is computed from the leaves.
+        let configs = cache
+            .tree_configs::<Tree>(CacheKey::CommRLastTree, leafs)
+            .unwrap();
+        corrupt(&configs[0], 1, 1);
+
+        let row_2_start = leafs + leafs / 8;
+        let expected = CorruptRange {
+            id: configs[0].id.clone(),
+            row: 2,
+            start: row_2_start + 1,
+            end: row_2_start + 2,
+        };
+        assert_eq!(verify(true), vec![expected]);
+        assert!(verify(false).is_empty());
+
+        let tree = cache
+            .open_lc_tree::<Tree>(CacheKey::CommRLastTree, leafs, &replica_config)
+            .unwrap();
+        assert_eq!(tree.root(), root);
+    }
+}
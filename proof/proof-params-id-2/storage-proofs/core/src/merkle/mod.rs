@@ -6,20 +6,44 @@ use crate::hasher::Hasher;
 
 mod tree;
 mod proof;
+mod multiproof;
+mod codec;
+mod builders;
+mod cache;
+mod integrity;
+mod incremental;
+mod batch;
+mod hybrid;
+mod reader;
+mod streaming;
 
 pub use tree::*;
 pub use proof::*;
+pub use multiproof::*;
+pub use codec::*;
+pub use builders::*;
+pub use cache::*;
+pub use integrity::*;
+pub use incremental::*;
+pub use batch::*;
+pub use hybrid::*;
+pub use reader::*;
+pub use streaming::*;
 
 // Reexport here, so we don't depend on merkletree directly in other places.
 pub use merkletree::store::{ExternalReader, Store};
 
 pub type DiskStore<E> = merkletree::store::DiskStore<E>;
 pub type LCStore<E> = merkletree::store::LevelCacheStore<E, std::fs::File>;
+/// Level cache store reading its base data through `R`, see `ReplicaReader`.
+pub type LCStoreWith<E, R> = merkletree::store::LevelCacheStore<E, R>;
 
 pub type MerkleStore<T> = DiskStore<T>;
 
 pub type DiskTree<H, U, V, W> = MerkleTreeWrapper<H, DiskStore<<H as Hasher>::Domain>, U, V, W>;
 pub type LCTree<H, U, V, W> = MerkleTreeWrapper<H, LCStore<<H as Hasher>::Domain>, U, V, W>;
+pub type LCTreeWith<H, R, U, V, W> =
+    MerkleTreeWrapper<H, LCStoreWith<<H as Hasher>::Domain, R>, U, V, W>;
 
 pub type MerkleTree<H, U> = DiskTree<H, U, U0, U0>;
 pub type LCMerkleTree<H, U> = LCTree<H, U, U0, U0>;
@@ -0,0 +1,438 @@
+#![allow(clippy::len_without_is_empty)]
+
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+
+use anyhow::{ensure, Context, Result};
+use generic_array::typenum::{Unsigned, U0};
+use merkletree::hash::Algorithm;
+use serde::{Deserialize, Serialize};
+
+use crate::hasher::{Hasher, PoseidonArity};
+
+use super::{convert_domain, MerkleProofTrait, MerkleTreeTrait};
+
+/// A merkle proof for a set of leaves of the same tree.
+///
+/// Where the individual `MerkleProof`s of several leaves overlap, every node
+/// is stored at most once: nodes that can be computed from the proven leaves
+/// are omitted entirely, and each remaining sibling is stored a single time.
+/// Works for the same Single/Sub/Top shapes as `MerkleProof`, including hybrid
+/// trees whose sub and top levels are hashed with `G`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultiProof<
+    H: Hasher,
+    BaseArity: PoseidonArity,
+    SubTreeArity: PoseidonArity = U0,
+    TopTreeArity: PoseidonArity = U0,
+    G: Hasher = H,
+> {
+    /// Indices of the proven leaves, strictly increasing.
+    indices: Vec<usize>,
+    /// The proven leaves, in the order of `indices`.
+    #[serde(bound(
+        serialize = "H::Domain: Serialize",
+        deserialize = "H::Domain: Deserialize<'de>"
+    ))]
+    leaves: Vec<H::Domain>,
+    /// Sibling nodes which cannot be derived from the leaves, ordered by level
+    /// and then by position.
+    #[serde(bound(
+        serialize = "H::Domain: Serialize",
+        deserialize = "H::Domain: Deserialize<'de>"
+    ))]
+    siblings: Vec<H::Domain>,
+    /// Number of levels between the leaves and the root.
+    levels: usize,
+    /// Root of the merkle tree.
+    #[serde(bound(
+        serialize = "H::Domain: Serialize",
+        deserialize = "H::Domain: Deserialize<'de>"
+    ))]
+    root: H::Domain,
+    #[serde(skip)]
+    _arity: PhantomData<(BaseArity, SubTreeArity, TopTreeArity, G)>,
+}
+
+impl<
+        H: Hasher,
+        BaseArity: 'static + PoseidonArity,
+        SubTreeArity: 'static + PoseidonArity,
+        TopTreeArity: 'static + PoseidonArity,
+        G: Hasher,
+    > MultiProof<H, BaseArity, SubTreeArity, TopTreeArity, G>
+{
+    /// Generates a multiproof for the leaves at `indices` of `tree`.
+    pub fn generate<Tree>(tree: &Tree, indices: &[usize]) -> Result<Self>
+    where
+        Tree: MerkleTreeTrait<
+            Hasher = H,
+            TopHasher = G,
+            Arity = BaseArity,
+            SubTreeArity = SubTreeArity,
+            TopTreeArity = TopTreeArity,
+        >,
+    {
+        let proofs = indices
+            .iter()
+            .map(|i| tree.gen_proof(*i))
+            .collect::<Result<Vec<_>>>()?;
+
+        Self::from_proofs(&proofs)
+    }
+
+    /// Merges individual proofs against the same root into a multiproof.
+    pub fn from_proofs<P>(proofs: &[P]) -> Result<Self>
+    where
+        P: MerkleProofTrait<
+            Hasher = H,
+            TopHasher = G,
+            Arity = BaseArity,
+            SubTreeArity = SubTreeArity,
+            TopTreeArity = TopTreeArity,
+        >,
+    {
+        ensure!(
+            !proofs.is_empty(),
+            "cannot build a multiproof without proofs"
+        );
+
+        let root = proofs[0].root();
+        let levels = proofs[0].path().len();
+        let shape = Self::shape(levels)?;
+
+        // All nodes the individual proofs know about, by (level, position).
+        let mut nodes = BTreeMap::new();
+        let mut leaves = BTreeMap::new();
+        for proof in proofs {
+            ensure!(proof.root() == root, "proofs are for different roots");
+            let path = proof.path();
+            ensure!(path.len() == levels, "proofs are for different tree shapes");
+
+            let index = proof.path_index();
+            if let Some(leaf) = leaves.insert(index, proof.leaf()) {
+                ensure!(
+                    leaf == proof.leaf(),
+                    "conflicting leaves for index {}",
+                    index
+                );
+            }
+
+            let mut position = index;
+            for (level, ((hashes, path_index), &(arity, _, _))) in
+                path.iter().zip(&shape).enumerate()
+            {
+                ensure!(
+                    *path_index == position % arity && hashes.len() == arity - 1,
+                    "malformed proof for index {}",
+                    index
+                );
+
+                let first = position - path_index;
+                let siblings = (0..arity).filter(|j| j != path_index);
+                for (j, hash) in siblings.zip(hashes.iter()) {
+                    nodes.insert((level, first + j), *hash);
+                }
+                position /= arity;
+            }
+        }
+
+        let indices: Vec<usize> = leaves.keys().copied().collect();
+        let mut siblings = Vec::new();
+        let mut known = indices.clone();
+        for (level, &(arity, _, _)) in shape.iter().enumerate() {
+            let mut parents = Vec::new();
+            let mut i = 0;
+            while i < known.len() {
+                let parent = known[i] / arity;
+                let children = children_of(parent, arity)
+                    .with_context(|| format!("index {} is out of range", known[i]))?;
+                for child in children {
+                    if i < known.len() && known[i] == child {
+                        i += 1;
+                    } else {
+                        let node = nodes.get(&(level, child)).with_context(|| {
+                            format!("missing node {} at level {}", child, level)
+                        })?;
+                        siblings.push(*node);
+                    }
+                }
+                parents.push(parent);
+            }
+            known = parents;
+        }
+
+        Ok(MultiProof {
+            indices,
+            leaves: leaves.values().copied().collect(),
+            siblings,
+            levels,
+            root,
+            _arity: PhantomData,
+        })
+    }
+
+    /// Verifies all leaves against the root in a single pass over the tree.
+    pub fn verify(&self) -> bool {
+        let shape = match Self::shape(self.levels) {
+            Ok(shape) => shape,
+            Err(_) => return false,
+        };
+        if self.indices.is_empty()
+            || self.indices.len() != self.leaves.len()
+            || self.indices.windows(2).any(|w| w[0] >= w[1])
+        {
+            return false;
+        }
+
+        let mut nodes: Vec<(usize, H::Domain)> = self
+            .indices
+            .iter()
+            .copied()
+            .zip(self.leaves.iter().copied())
+            .collect();
+        let mut siblings = self.siblings.iter();
+        let mut a = H::Function::default();
+        let mut top = G::Function::default();
+        let mut children = Vec::new();
+
+        for (arity, height, upper) in shape {
+            let mut parents = Vec::with_capacity(nodes.len());
+            let mut i = 0;
+            while i < nodes.len() {
+                let parent = nodes[i].0 / arity;
+                let range = match children_of(parent, arity) {
+                    Some(range) => range,
+                    None => return false,
+                };
+                children.clear();
+                for child in range {
+                    if i < nodes.len() && nodes[i].0 == child {
+                        children.push(nodes[i].1);
+                        i += 1;
+                    } else {
+                        match siblings.next() {
+                            Some(sibling) => children.push(*sibling),
+                            None => return false,
+                        }
+                    }
+                }
+
+                let node = if upper {
+                    match Self::hash_upper(&mut top, &children) {
+                        Ok(node) => node,
+                        Err(_) => return false,
+                    }
+                } else {
+                    a.reset();
+                    a.multi_node(&children, height)
+                };
+                parents.push((parent, node));
+            }
+            nodes = parents;
+        }
+
+        siblings.next().is_none() && nodes.len() == 1 && nodes[0] == (0, self.root)
+    }
+
+    /// Returns true if this proof covers exactly the given challenges.
+    pub fn proves_challenges(&self, challenges: &[usize]) -> bool {
+        let mut challenges = challenges.to_vec();
+        challenges.sort_unstable();
+        challenges.dedup();
+
+        challenges == self.indices
+    }
+
+    pub fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    pub fn leaves(&self) -> &[H::Domain] {
+        &self.leaves
+    }
+
+    pub fn root(&self) -> H::Domain {
+        self.root
+    }
+
+    /// Returns the leaf proven for `index`, if any.
+    pub fn leaf(&self, index: usize) -> Option<H::Domain> {
+        self.indices
+            .binary_search(&index)
+            .ok()
+            .map(|i| self.leaves[i])
+    }
+
+    /// Number of hashes stored in this proof, including leaves and root.
+    pub fn len(&self) -> usize {
+        self.leaves.len() + self.siblings.len() + 1
+    }
+
+    /// Hashes the children of a sub or top level node with `G`.
+    fn hash_upper(a: &mut G::Function, children: &[H::Domain]) -> Result<H::Domain> {
+        let children = children
+            .iter()
+            .map(convert_domain::<H::Domain, G::Domain>)
+            .collect::<Result<Vec<_>>>()?;
+        a.reset();
+
+        convert_domain(&a.multi_node(&children, 0))
+    }
+
+    /// Arity and hashing height of every level, from the leaves up, and
+    /// whether it is a sub or top level. Heights restart at each sub/top tree,
+    /// matching `MerkleProof::verify`.
+    fn shape(levels: usize) -> Result<Vec<(usize, usize, bool)>> {
+        let sub = (SubTreeArity::to_usize() > 0) as usize;
+        let top = (TopTreeArity::to_usize() > 0) as usize;
+        ensure!(levels > sub + top, "invalid number of levels: {}", levels);
+
+        let mut shape: Vec<_> = (0..levels - sub - top)
+            .map(|height| (BaseArity::to_usize(), height, false))
+            .collect();
+        if sub > 0 {
+            shape.push((SubTreeArity::to_usize(), 0, true));
+        }
+        if top > 0 {
+            shape.push((TopTreeArity::to_usize(), 0, true));
+        }
+
+        Ok(shape)
+    }
+}
+
+/// Indices of the `arity` children of `parent`, or `None` if they overflow.
+fn children_of(parent: usize, arity: usize) -> Option<std::ops::Range<usize>> {
+    let first = parent.checked_mul(arity)?;
+    let end = first.checked_add(arity)?;
+
+    Some(first..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use generic_array::typenum::{U2, U4, U8};
+
+    use crate::hasher::{Domain, PoseidonHasher, Sha256Hasher};
+    use crate::merkle::{DiskStore, HybridMerkleTree, MerkleTreeWrapper};
+
+    type H = Sha256Hasher;
+    type Store = DiskStore<<H as Hasher>::Domain>;
+
+    fn base_tree<U: PoseidonArity>(leaves: usize) -> MerkleTreeWrapper<H, Store, U, U0, U0> {
+        let mut rng = rand::thread_rng();
+        MerkleTreeWrapper::new((0..leaves).map(|_| <H as Hasher>::Domain::random(&mut rng)))
+            .unwrap()
+    }
+
+    type TreeMultiProof<Tree> = MultiProof<
+        <Tree as MerkleTreeTrait>::Hasher,
+        <Tree as MerkleTreeTrait>::Arity,
+        <Tree as MerkleTreeTrait>::SubTreeArity,
+        <Tree as MerkleTreeTrait>::TopTreeArity,
+        <Tree as MerkleTreeTrait>::TopHasher,
+    >;
+
+    fn check_multiproof<Tree>(tree: &Tree)
+    where
+        Tree: MerkleTreeTrait,
+    {
+        let leaves = tree.leaves();
+        let challenges = vec![0, 1, 3, leaves / 2, leaves / 2 + 1, leaves - 1, 3];
+
+        let proof = TreeMultiProof::<Tree>::generate(tree, &challenges).unwrap();
+
+        assert!(proof.verify(), "failed to verify multiproof");
+        assert!(proof.proves_challenges(&challenges));
+        assert_eq!(proof.root(), tree.root());
+
+        let individual: usize = proof
+            .indices()
+            .iter()
+            .map(|i| {
+                let p = tree.gen_proof(*i).unwrap();
+                assert_eq!(proof.leaf(*i), Some(p.leaf()));
+                p.path()
+                    .iter()
+                    .map(|(hashes, _)| hashes.len())
+                    .sum::<usize>()
+                    + 2
+            })
+            .sum();
+        assert!(proof.len() < individual, "multiproof is not smaller");
+
+        let serialized = serde_json::to_string(&proof).unwrap();
+        let decoded: TreeMultiProof<Tree> = serde_json::from_str(&serialized).unwrap();
+        assert!(decoded.verify());
+
+        let mut broken = proof.clone();
+        broken.leaves[1] = broken.leaves[0];
+        assert!(!broken.verify(), "verified a modified leaf");
+
+        let mut broken = proof.clone();
+        broken.siblings.pop();
+        assert!(!broken.verify(), "verified with a missing sibling");
+
+        let mut broken = proof.clone();
+        broken.indices.swap(0, 1);
+        assert!(!broken.verify(), "verified with unordered indices");
+
+        // Indices whose children overflow are rejected instead of panicking.
+        let mut broken = proof;
+        *broken.indices.last_mut().unwrap() = usize::MAX;
+        assert!(!broken.verify(), "verified an out of range index");
+    }
+
+    #[test]
+    fn multiproof_single_2() {
+        check_multiproof(&base_tree::<U2>(64));
+    }
+
+    #[test]
+    fn multiproof_single_8() {
+        check_multiproof(&base_tree::<U8>(64));
+    }
+
+    #[test]
+    fn multiproof_sub_8_2() {
+        let trees = (0..2).map(|_| base_tree::<U8>(64)).collect();
+        let tree = MerkleTreeWrapper::<H, Store, U8, U2, U0>::from_trees(trees).unwrap();
+        check_multiproof(&tree);
+    }
+
+    #[test]
+    fn multiproof_top_8_4_2() {
+        let sub_trees = (0..2)
+            .map(|_| {
+                let trees = (0..4).map(|_| base_tree::<U8>(64)).collect();
+                MerkleTreeWrapper::<H, Store, U8, U4, U0>::from_trees(trees).unwrap()
+            })
+            .collect();
+        let tree = MerkleTreeWrapper::<H, Store, U8, U4, U2>::from_sub_trees(sub_trees).unwrap();
+        check_multiproof(&tree);
+    }
+
+    #[test]
+    fn multiproof_hybrid_top_8_4_2() {
+        let trees = (0..8).map(|_| base_tree::<U8>(64)).collect();
+        let tree =
+            HybridMerkleTree::<H, PoseidonHasher, Store, U8, U4, U2>::from_trees(trees).unwrap();
+        check_multiproof(&tree);
+
+        // Hashing the upper levels with `H` does not lead to the root.
+        let hybrid =
+            MultiProof::<H, U8, U4, U2, PoseidonHasher>::generate(&tree, &[0, 100, 511]).unwrap();
+        let plain = MultiProof::<H, U8, U4, U2> {
+            indices: hybrid.indices.clone(),
+            leaves: hybrid.leaves.clone(),
+            siblings: hybrid.siblings.clone(),
+            levels: hybrid.levels,
+            root: hybrid.root,
+            _arity: PhantomData,
+        };
+        assert!(!plain.verify());
+    }
+}
@@ -12,11 +12,16 @@ use serde::{Deserialize, Serialize};
 use crate::drgraph::graph_height;
 use crate::hasher::{Hasher, PoseidonArity};
 
+use super::convert_domain;
+
 /// Trait to abstract over the concept of Merkle Proof.
 pub trait MerkleProofTrait:
     Clone + Serialize + serde::de::DeserializeOwned + std::fmt::Debug + Sync + Send
 {
     type Hasher: Hasher;
+    /// Hasher of the sub and top tree levels. Equal to `Hasher` unless the
+    /// tree was built with a different hasher above its base trees.
+    type TopHasher: Hasher;
     type Arity: 'static + PoseidonArity;
     type SubTreeArity: 'static + PoseidonArity;
     type TopTreeArity: 'static + PoseidonArity;
@@ -85,6 +90,8 @@ pub trait MerkleProofTrait:
     fn root(&self) -> <Self::Hasher as Hasher>::Domain;
     fn len(&self) -> usize;
     fn path(&self) -> Vec<(Vec<<Self::Hasher as Hasher>::Domain>, usize)>;
+    /// Like `path`, but borrows the sibling hashes instead of copying them.
+    fn path_refs(&self) -> Vec<(&[<Self::Hasher as Hasher>::Domain], usize)>;
 
     fn path_index(&self) -> usize {
         self.path()
@@ -187,18 +194,54 @@ impl<H: Hasher, Arity: PoseidonArity> From<Vec<PathElement<H, Arity>>> for Inclu
 impl<H: Hasher, Arity: PoseidonArity> InclusionPath<H, Arity> {
     /// Calculate the root of this path, given the leaf as input.
     pub fn root(&self, leaf: H::Domain) -> H::Domain {
+        self.root_from_height(leaf, 0)
+    }
+
+    /// Calculate the root of this path, given a node at `height` as input.
+    ///
+    /// Used for paths which start above the leaves, e.g. from the root of
+    /// an aligned subtree.
+    pub fn root_from_height(&self, node: H::Domain, height: usize) -> H::Domain {
         let mut a = H::Function::default();
-        (0..self.path.len()).fold(leaf, |h, height| {
+        let mut nodes = Vec::with_capacity(Arity::to_usize());
+        self.path.iter().enumerate().fold(node, |h, (i, element)| {
             a.reset();
 
-            let index = self.path[height].index;
-            let mut nodes = self.path[height].hashes.clone();
-            nodes.insert(index, h);
+            nodes.clear();
+            nodes.extend_from_slice(&element.hashes[..element.index]);
+            nodes.push(h);
+            nodes.extend_from_slice(&element.hashes[element.index..]);
 
-            a.multi_node(&nodes, height)
+            a.multi_node(&nodes, height + i)
         })
     }
 
+    /// Calculate the root of this path from `node`, hashing with `G` instead
+    /// of `H`. Used for the sub and top levels of hybrid trees.
+    ///
+    /// Fails if a node is not a valid `G::Domain`.
+    pub fn root_with_hasher<G: Hasher>(&self, node: H::Domain) -> Result<H::Domain> {
+        let mut a = G::Function::default();
+        let mut nodes = Vec::with_capacity(Arity::to_usize());
+        self.path
+            .iter()
+            .enumerate()
+            .try_fold(node, |h, (i, element)| {
+                a.reset();
+
+                nodes.clear();
+                for hash in &element.hashes[..element.index] {
+                    nodes.push(convert_domain::<H::Domain, G::Domain>(hash)?);
+                }
+                nodes.push(convert_domain::<H::Domain, G::Domain>(&h)?);
+                for hash in &element.hashes[element.index..] {
+                    nodes.push(convert_domain::<H::Domain, G::Domain>(hash)?);
+                }
+
+                convert_domain(&a.multi_node(&nodes, i))
+            })
+    }
+
     pub fn len(&self) -> usize {
         self.path.len()
     }
@@ -231,6 +274,26 @@ pub struct PathElement<H: Hasher, Arity: PoseidonArity> {
     _arity: PhantomData<Arity>,
 }
 
+impl<H: Hasher, Arity: PoseidonArity> PathElement<H, Arity> {
+    pub fn new(hashes: Vec<H::Domain>, index: usize) -> Self {
+        PathElement {
+            hashes,
+            index,
+            _arity: Default::default(),
+        }
+    }
+
+    /// The sibling hashes at this level, excluding the node on the path.
+    pub fn hashes(&self) -> &[H::Domain] {
+        &self.hashes
+    }
+
+    /// Position of the node on the path among its siblings.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
 /// Representation of a merkle proof.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MerkleProof<
@@ -238,12 +301,13 @@ pub struct MerkleProof<
     BaseArity: PoseidonArity,
     SubTreeArity: PoseidonArity = U0,
     TopTreeArity: PoseidonArity = U0,
+    G: Hasher = H,
 > {
     #[serde(bound(
         serialize = "H::Domain: Serialize",
         deserialize = "H::Domain: Deserialize<'de>"
     ))]
-    data: ProofData<H, BaseArity, SubTreeArity, TopTreeArity>,
+    data: ProofData<H, BaseArity, SubTreeArity, TopTreeArity, G>,
 }
 
 impl<
@@ -251,13 +315,17 @@ impl<
         Arity: 'static + PoseidonArity,
         SubTreeArity: 'static + PoseidonArity,
         TopTreeArity: 'static + PoseidonArity,
-    > MerkleProofTrait for MerkleProof<H, Arity, SubTreeArity, TopTreeArity>
+        G: Hasher,
+    > MerkleProofTrait for MerkleProof<H, Arity, SubTreeArity, TopTreeArity, G>
 {
     type Hasher = H;
+    type TopHasher = G;
     type Arity = Arity;
     type SubTreeArity = SubTreeArity;
     type TopTreeArity = TopTreeArity;
 
+    /// merkletree proofs are hashed with a single hasher throughout, so the
+    /// result only verifies if `G` is `H`.
     fn try_from_proof(
         p: proof::Proof<<Self::Hasher as Hasher>::Domain, Self::Arity>,
     ) -> Result<Self> {
@@ -295,6 +363,10 @@ impl<
     fn path(&self) -> Vec<(Vec<H::Domain>, usize)> {
         forward_method!(self.data, path)
     }
+
+    fn path_refs(&self) -> Vec<(&[H::Domain], usize)> {
+        forward_method!(self.data, path_refs)
+    }
     fn path_index(&self) -> usize {
         forward_method!(self.data, path_index)
     }
@@ -322,6 +394,7 @@ enum ProofData<
     BaseArity: PoseidonArity,
     SubTreeArity: PoseidonArity,
     TopTreeArity: PoseidonArity,
+    G: Hasher,
 > {
     #[serde(bound(
         serialize = "H::Domain: Serialize",
@@ -332,12 +405,12 @@ enum ProofData<
         serialize = "H::Domain: Serialize",
         deserialize = "H::Domain: Deserialize<'de>"
     ))]
-    Sub(SubProof<H, BaseArity, SubTreeArity>),
+    Sub(SubProof<H, BaseArity, SubTreeArity, G>),
     #[serde(bound(
         serialize = "H::Domain: Serialize",
         deserialize = "H::Domain: Deserialize<'de>"
     ))]
-    Top(TopProof<H, BaseArity, SubTreeArity, TopTreeArity>),
+    Top(TopProof<H, BaseArity, SubTreeArity, TopTreeArity, G>),
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -369,7 +442,7 @@ impl<H: Hasher, Arity: PoseidonArity> SingleProof<H, Arity> {
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
-struct SubProof<H: Hasher, BaseArity: PoseidonArity, SubTreeArity: PoseidonArity> {
+struct SubProof<H: Hasher, BaseArity: PoseidonArity, SubTreeArity: PoseidonArity, G: Hasher> {
     #[serde(bound(
         serialize = "H::Domain: Serialize",
         deserialize = "H::Domain: Deserialize<'de>"
@@ -392,10 +465,12 @@ struct SubProof<H: Hasher, BaseArity: PoseidonArity, SubTreeArity: PoseidonArity
         deserialize = "H::Domain: Deserialize<'de>"
     ))]
     leaf: H::Domain,
+    #[serde(skip)]
+    _g: PhantomData<G>,
 }
 
-impl<H: Hasher, BaseArity: PoseidonArity, SubTreeArity: PoseidonArity>
-    SubProof<H, BaseArity, SubTreeArity>
+impl<H: Hasher, BaseArity: PoseidonArity, SubTreeArity: PoseidonArity, G: Hasher>
+    SubProof<H, BaseArity, SubTreeArity, G>
 {
     pub fn new(
         base_proof: InclusionPath<H, BaseArity>,
@@ -408,6 +483,7 @@ impl<H: Hasher, BaseArity: PoseidonArity, SubTreeArity: PoseidonArity>
             sub_proof,
             root,
             leaf,
+            _g: PhantomData,
         }
     }
 }
@@ -418,6 +494,7 @@ struct TopProof<
     BaseArity: PoseidonArity,
     SubTreeArity: PoseidonArity,
     TopTreeArity: PoseidonArity,
+    G: Hasher,
 > {
     #[serde(bound(
         serialize = "H::Domain: Serialize",
@@ -446,6 +523,8 @@ struct TopProof<
         deserialize = "H::Domain: Deserialize<'de>"
     ))]
     leaf: H::Domain,
+    #[serde(skip)]
+    _g: PhantomData<G>,
 }
 
 impl<
@@ -453,7 +532,8 @@ impl<
         BaseArity: PoseidonArity,
         SubTreeArity: PoseidonArity,
         TopTreeArity: PoseidonArity,
-    > TopProof<H, BaseArity, SubTreeArity, TopTreeArity>
+        G: Hasher,
+    > TopProof<H, BaseArity, SubTreeArity, TopTreeArity, G>
 {
     pub fn new(
         base_proof: InclusionPath<H, BaseArity>,
@@ -468,6 +548,7 @@ impl<
             top_proof,
             root,
             leaf,
+            _g: PhantomData,
         }
     }
 }
@@ -477,7 +558,8 @@ impl<
         BaseArity: PoseidonArity,
         SubTreeArity: PoseidonArity,
         TopTreeArity: PoseidonArity,
-    > MerkleProof<H, BaseArity, SubTreeArity, TopTreeArity>
+        G: Hasher,
+    > MerkleProof<H, BaseArity, SubTreeArity, TopTreeArity, G>
 {
     pub fn new(n: usize) -> Self {
         let root = Default::default();
@@ -494,6 +576,38 @@ impl<
     }
 }
 
+impl<
+        H: Hasher,
+        BaseArity: PoseidonArity,
+        SubTreeArity: PoseidonArity,
+        TopTreeArity: PoseidonArity,
+        G: Hasher,
+    > MerkleProof<H, BaseArity, SubTreeArity, TopTreeArity, G>
+{
+    /// Assembles a proof from its paths. `sub_path` must be present for sub and
+    /// top proofs, `top_path` only for top proofs.
+    pub(crate) fn from_parts(
+        leaf: H::Domain,
+        root: H::Domain,
+        base_path: InclusionPath<H, BaseArity>,
+        sub_path: Option<InclusionPath<H, SubTreeArity>>,
+        top_path: Option<InclusionPath<H, TopTreeArity>>,
+    ) -> Result<Self> {
+        let data = match (sub_path, top_path) {
+            (None, None) => ProofData::Single(SingleProof::new(base_path, root, leaf)),
+            (Some(sub_path), None) => {
+                ProofData::Sub(SubProof::new(base_path, sub_path, root, leaf))
+            }
+            (Some(sub_path), Some(top_path)) => {
+                ProofData::Top(TopProof::new(base_path, sub_path, top_path, root, leaf))
+            }
+            (None, Some(_)) => anyhow::bail!("cannot build a top proof without a sub path"),
+        };
+
+        Ok(MerkleProof { data })
+    }
+}
+
 /// Converts a merkle_light proof to a SingleProof
 fn proof_to_single<H: Hasher, Arity: PoseidonArity, TargetArity: PoseidonArity>(
     proof: &proof::Proof<H::Domain, Arity>,
@@ -561,13 +675,21 @@ impl<H: Hasher, Arity: 'static + PoseidonArity> SingleProof<H, Arity> {
             .collect::<Vec<_>>()
     }
 
+    fn path_refs(&self) -> Vec<(&[H::Domain], usize)> {
+        self.path.iter().map(|x| (&x.hashes[..], x.index)).collect()
+    }
+
     fn path_index(&self) -> usize {
         self.path.path_index()
     }
 }
 
-impl<H: Hasher, Arity: 'static + PoseidonArity, SubTreeArity: 'static + PoseidonArity>
-    SubProof<H, Arity, SubTreeArity>
+impl<
+        H: Hasher,
+        Arity: 'static + PoseidonArity,
+        SubTreeArity: 'static + PoseidonArity,
+        G: Hasher,
+    > SubProof<H, Arity, SubTreeArity, G>
 {
     fn try_from_proof(p: proof::Proof<<H as Hasher>::Domain, Arity>) -> Result<Self> {
         ensure!(
@@ -591,9 +713,9 @@ impl<H: Hasher, Arity: 'static + PoseidonArity, SubTreeArity: 'static + Poseidon
 
     fn verify(&self) -> bool {
         let sub_leaf = self.base_proof.root(self.leaf);
-        let calculated_root = self.sub_proof.root(sub_leaf);
+        let calculated_root = self.sub_proof.root_with_hasher::<G>(sub_leaf);
 
-        self.root == calculated_root
+        calculated_root.map_or(false, |root| root == self.root)
     }
 
     fn leaf(&self) -> H::Domain {
@@ -616,6 +738,14 @@ impl<H: Hasher, Arity: 'static + PoseidonArity, SubTreeArity: 'static + Poseidon
             .collect()
     }
 
+    fn path_refs(&self) -> Vec<(&[H::Domain], usize)> {
+        self.base_proof
+            .iter()
+            .map(|x| (&x.hashes[..], x.index))
+            .chain(self.sub_proof.iter().map(|x| (&x.hashes[..], x.index)))
+            .collect()
+    }
+
     fn path_index(&self) -> usize {
         let mut base_proof_leaves = 1;
         for _i in 0..self.base_proof.len() {
@@ -633,7 +763,8 @@ impl<
         Arity: 'static + PoseidonArity,
         SubTreeArity: 'static + PoseidonArity,
         TopTreeArity: 'static + PoseidonArity,
-    > TopProof<H, Arity, SubTreeArity, TopTreeArity>
+        G: Hasher,
+    > TopProof<H, Arity, SubTreeArity, TopTreeArity, G>
 {
     fn try_from_proof(p: proof::Proof<<H as Hasher>::Domain, Arity>) -> Result<Self> {
         ensure!(
@@ -669,10 +800,12 @@ impl<
 
     fn verify(&self) -> bool {
         let sub_leaf = self.base_proof.root(self.leaf);
-        let top_leaf = self.sub_proof.root(sub_leaf);
-        let calculated_root = self.top_proof.root(top_leaf);
+        let calculated_root = self
+            .sub_proof
+            .root_with_hasher::<G>(sub_leaf)
+            .and_then(|top_leaf| self.top_proof.root_with_hasher::<G>(top_leaf));
 
-        self.root == calculated_root
+        calculated_root.map_or(false, |root| root == self.root)
     }
 
     fn leaf(&self) -> H::Domain {
@@ -696,6 +829,15 @@ impl<
             .collect()
     }
 
+    fn path_refs(&self) -> Vec<(&[H::Domain], usize)> {
+        self.base_proof
+            .iter()
+            .map(|x| (&x.hashes[..], x.index))
+            .chain(self.sub_proof.iter().map(|x| (&x.hashes[..], x.index)))
+            .chain(self.top_proof.iter().map(|x| (&x.hashes[..], x.index)))
+            .collect()
+    }
+
     fn path_index(&self) -> usize {
         let mut base_proof_leaves = 1;
         for _i in 0..self.base_proof.len() {
@@ -0,0 +1,449 @@
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, ensure, Context, Result};
+use memmap::Mmap;
+
+use super::ExternalReader;
+
+/// Default size of the reads issued by `PreadReader`.
+pub const DEFAULT_READ_BLOCK_SIZE: usize = 1 << 20;
+
+/// A source of replica bytes for the base rows of level cache trees.
+///
+/// Readers are cheap handles: every base tree gets its own clone, turned into
+/// an `ExternalReader` at the offset of its slice of the replica.
+pub trait ReplicaReader: Read + Clone + Send + Sync + Sized {
+    /// Fills `buf` with the bytes starting at `start`.
+    fn read_at(&self, start: usize, buf: &mut [u8]) -> Result<()>;
+
+    /// An `ExternalReader` reading from this replica, starting at `offset`.
+    fn into_external(self, offset: usize) -> ExternalReader<Self> {
+        ExternalReader {
+            offset,
+            source: self,
+            read_fn: |start, end, buf, source: &Self| {
+                source.read_at(start, &mut buf[..end - start])?;
+
+                Ok(end - start)
+            },
+        }
+    }
+
+    /// One `ExternalReader` per base tree, for the given replica offsets.
+    fn external_readers(&self, offsets: &[usize]) -> Vec<ExternalReader<Self>> {
+        offsets
+            .iter()
+            .map(|offset| self.clone().into_external(*offset))
+            .collect()
+    }
+}
+
+/// Reads the replica with positioned reads of at most `block_size` bytes,
+/// aligned to multiples of `block_size`.
+#[derive(Debug, Clone)]
+pub struct PreadReader {
+    file: Arc<File>,
+    block_size: usize,
+    position: u64,
+}
+
+impl PreadReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("could not open {:?}", path))?;
+
+        Ok(PreadReader {
+            file: Arc::new(file),
+            block_size: DEFAULT_READ_BLOCK_SIZE,
+            position: 0,
+        })
+    }
+
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        assert!(block_size > 0, "block size must not be zero");
+        self.block_size = block_size;
+        self
+    }
+}
+
+impl ReplicaReader for PreadReader {
+    fn read_at(&self, start: usize, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = start + done;
+            let len = cmp::min(self.block_size - pos % self.block_size, buf.len() - done);
+            self.file
+                .read_exact_at(&mut buf[done..done + len], pos as u64)?;
+            done += len;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for PreadReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.position)?;
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+/// Reads the replica through a shared read-only memory map.
+#[derive(Debug, Clone)]
+pub struct MmapReader {
+    map: Arc<Mmap>,
+    position: usize,
+}
+
+impl MmapReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("could not open {:?}", path))?;
+        let map =
+            unsafe { Mmap::map(&file) }.with_context(|| format!("could not map {:?}", path))?;
+
+        Ok(MmapReader {
+            map: Arc::new(map),
+            position: 0,
+        })
+    }
+}
+
+impl ReplicaReader for MmapReader {
+    fn read_at(&self, start: usize, buf: &mut [u8]) -> Result<()> {
+        ensure!(
+            start + buf.len() <= self.map.len(),
+            "range {}..{} out of bounds for a replica of {} bytes",
+            start,
+            start + buf.len(),
+            self.map.len()
+        );
+        buf.copy_from_slice(&self.map[start..start + buf.len()]);
+
+        Ok(())
+    }
+}
+
+impl Read for MmapReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = cmp::min(self.position, self.map.len());
+        let read = (&self.map[start..]).read(buf)?;
+        self.position += read;
+
+        Ok(read)
+    }
+}
+
+/// Byte range access to a replica stored elsewhere, e.g. on another host or
+/// storage tier.
+pub trait RemoteRange: std::fmt::Debug + Clone + Send + Sync {
+    /// Fills `buf` with the bytes starting at `start`.
+    fn read_range(&self, start: u64, buf: &mut [u8]) -> Result<()>;
+}
+
+/// Adapts a `RemoteRange` to a `ReplicaReader`.
+#[derive(Debug, Clone)]
+pub struct RangeReader<T: RemoteRange> {
+    remote: T,
+    len: u64,
+    position: u64,
+}
+
+impl<T: RemoteRange> RangeReader<T> {
+    /// Reads from `remote`, which holds `len` bytes.
+    pub fn new(remote: T, len: u64) -> Self {
+        RangeReader {
+            remote,
+            len,
+            position: 0,
+        }
+    }
+}
+
+impl<T: RemoteRange> ReplicaReader for RangeReader<T> {
+    fn read_at(&self, start: usize, buf: &mut [u8]) -> Result<()> {
+        self.remote.read_range(start as u64, buf)
+    }
+}
+
+impl<T: RemoteRange> Read for RangeReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.position);
+        let len = cmp::min(buf.len() as u64, remaining) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        self.remote
+            .read_range(self.position, &mut buf[..len])
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+/// A minimal HTTP/1.1 client fetching byte ranges of `path` from `host`,
+/// which is given as `address:port`. Every range is a separate request.
+#[derive(Debug, Clone)]
+pub struct HttpRange {
+    host: String,
+    path: String,
+}
+
+impl HttpRange {
+    pub fn new<S: Into<String>, P: Into<String>>(host: S, path: P) -> Self {
+        HttpRange {
+            host: host.into(),
+            path: path.into(),
+        }
+    }
+}
+
+impl RemoteRange for HttpRange {
+    fn read_range(&self, start: u64, buf: &mut [u8]) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let mut stream = TcpStream::connect(&self.host)
+            .with_context(|| format!("could not connect to {}", self.host))?;
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nRange: bytes={}-{}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            start,
+            start + buf.len() as u64 - 1
+        )?;
+
+        let mut response = BufReader::new(stream);
+        let mut line = String::new();
+        response.read_line(&mut line)?;
+        let status = line.split_whitespace().nth(1).unwrap_or_default();
+        if status != "206" {
+            bail!(
+                "range request to {}{} failed: {}",
+                self.host,
+                self.path,
+                line.trim()
+            );
+        }
+
+        let mut content_length = None;
+        loop {
+            line.clear();
+            ensure!(
+                response.read_line(&mut line)? > 0,
+                "truncated response headers"
+            );
+            let header = line.trim();
+            if header.is_empty() {
+                break;
+            }
+            let mut parts = header.splitn(2, ':');
+            let name = parts.next().unwrap_or_default();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = Some(parts.next().unwrap_or_default().trim().parse::<usize>()?);
+            }
+        }
+        ensure!(
+            content_length == Some(buf.len()),
+            "expected {} bytes, server sent {:?}",
+            buf.len(),
+            content_length
+        );
+
+        response.read_exact(buf)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    use generic_array::typenum::{U0, U2, U8};
+    use merkletree::merkle::Element;
+    use merkletree::store::ReplicaConfig;
+
+    use crate::cache_key::CacheKey;
+    use crate::hasher::{Domain, Hasher, Sha256Hasher};
+    use crate::merkle::{CacheDir, DiskTree, MerkleProofTrait, MerkleTreeTrait};
+
+    type H = Sha256Hasher;
+    type D = <H as Hasher>::Domain;
+
+    /// Serves `data` to range requests for any path, one connection at a time.
+    fn serve(data: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = BufReader::new(stream.try_clone().unwrap());
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    request.read_line(&mut line).unwrap();
+                    let line = line.trim();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if line.starts_with("Range: bytes=") {
+                        let mut bounds = line["Range: bytes=".len()..]
+                            .split('-')
+                            .map(|b| b.parse::<usize>().unwrap());
+                        range = Some((bounds.next().unwrap(), bounds.next().unwrap()));
+                    }
+                }
+
+                match range {
+                    Some((start, end)) if end < data.len() => {
+                        let body = &data[start..=end];
+                        write!(
+                            stream,
+                            "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n",
+                            body.len()
+                        )
+                        .unwrap();
+                        stream.write_all(body).unwrap();
+                    }
+                    _ => {
+                        write!(
+                            stream,
+                            "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Length: 0\r\n\r\n"
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+        });
+
+        host
+    }
+
+    fn check_readers<R: ReplicaReader>(reader: &R, data: &[u8]) {
+        for &(start, len) in &[(0, 1), (3, 100), (1000, 4000), (data.len() - 64, 64)] {
+            let mut buf = vec![0u8; len];
+            reader.read_at(start, &mut buf).unwrap();
+            assert_eq!(&buf[..], &data[start..start + len]);
+        }
+        assert!(reader.read_at(data.len() - 1, &mut [0u8; 2]).is_err());
+
+        let mut external = reader.clone().into_external(100);
+        let mut buf = vec![0u8; 32];
+        assert_eq!(external.read(0, 32, &mut buf).unwrap(), 32);
+        assert_eq!(&buf[..], &data[100..132]);
+
+        let mut all = Vec::new();
+        external.source.read_to_end(&mut all).unwrap();
+        assert_eq!(&all[..], data);
+    }
+
+    #[test]
+    fn replica_readers_read_ranges() {
+        let mut rng = rand::thread_rng();
+        let data: Vec<u8> = (0..8192)
+            .flat_map(|_| D::random(&mut rng).into_bytes())
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replica");
+        std::fs::write(&path, &data).unwrap();
+
+        check_readers(
+            &PreadReader::open(&path).unwrap().with_block_size(100),
+            &data,
+        );
+        check_readers(&MmapReader::open(&path).unwrap(), &data);
+
+        let remote = RangeReader::new(
+            HttpRange::new(serve(data.clone()), "/replica"),
+            data.len() as u64,
+        );
+        check_readers(&remote, &data);
+    }
+
+    #[test]
+    fn reopen_lc_tree_with_readers() {
+        type Tree = DiskTree<H, U8, U2, U0>;
+        let leafs = 512;
+        let mut rng = rand::thread_rng();
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path()).with_rows_to_discard(1);
+        let base_leafs: Vec<Vec<D>> = (0..2)
+            .map(|_| (0..leafs).map(|_| D::random(&mut rng)).collect())
+            .collect();
+
+        let replica_path = dir.path().join("replica");
+        let data: Vec<u8> = base_leafs
+            .iter()
+            .flatten()
+            .flat_map(|leaf| leaf.into_bytes())
+            .collect();
+        std::fs::write(&replica_path, &data).unwrap();
+        let len = data.len() as u64;
+        let offsets = vec![0, leafs * D::byte_len()];
+        let replica_config = ReplicaConfig::new(&replica_path, offsets.clone());
+
+        let tree = cache
+            .persist_lc_tree::<Tree>(CacheKey::CommRLastTree, base_leafs, &replica_config)
+            .unwrap();
+
+        fn check<R: ReplicaReader>(
+            cache: &CacheDir,
+            reader: R,
+            offsets: &[usize],
+            expected: &impl MerkleTreeTrait<Hasher = H>,
+        ) {
+            let tree = cache
+                .open_lc_tree_with_readers::<Tree, _>(
+                    CacheKey::CommRLastTree,
+                    512,
+                    reader.external_readers(offsets),
+                )
+                .unwrap();
+            assert_eq!(tree.root(), expected.root());
+
+            for i in &[0, 511, 512, 1023] {
+                let proof = tree.gen_cached_proof(*i, Some(1)).unwrap();
+                let expected = expected.gen_cached_proof(*i, Some(1)).unwrap();
+                assert!(proof.validate(*i));
+                assert_eq!(proof.path(), expected.path());
+            }
+        }
+
+        check(
+            &cache,
+            PreadReader::open(&replica_path).unwrap(),
+            &offsets,
+            &tree,
+        );
+        check(
+            &cache,
+            MmapReader::open(&replica_path).unwrap(),
+            &offsets,
+            &tree,
+        );
+        check(
+            &cache,
+            RangeReader::new(HttpRange::new(serve(data), "/replica"), len),
+            &offsets,
+            &tree,
+        );
+    }
+}
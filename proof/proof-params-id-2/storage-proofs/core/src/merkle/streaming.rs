@@ -0,0 +1,719 @@
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::marker::PhantomData;
+use std::os::unix::fs::FileExt;
+
+use anyhow::{ensure, Context, Result};
+use ff::Field;
+use generic_array::typenum::Unsigned;
+use log::info;
+use merkletree::merkle::{
+    get_merkle_tree_cache_size, get_merkle_tree_len, get_merkle_tree_row_count,
+};
+use merkletree::store::StoreConfig;
+use neptune::poseidon::Poseidon;
+use paired::bls12_381::Fr;
+use rayon::prelude::*;
+
+use crate::hasher::{Domain, Hasher, PoseidonArity, PoseidonHasher};
+use crate::util::NODE_SIZE;
+
+use super::*;
+
+/// Default number of preimages handed to a `PoseidonBatchHasher` at once.
+pub const DEFAULT_POSEIDON_BATCH_SIZE: usize = 1 << 16;
+
+/// Hashes batches of preimages of arity `A` with Poseidon, on its own pool
+/// of threads.
+#[derive(Debug)]
+pub struct PoseidonBatchHasher<A: PoseidonArity> {
+    pool: rayon::ThreadPool,
+    _a: PhantomData<A>,
+}
+
+impl<A: PoseidonArity> PoseidonBatchHasher<A> {
+    pub fn new(threads: usize) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()?;
+
+        Ok(PoseidonBatchHasher {
+            pool,
+            _a: PhantomData,
+        })
+    }
+
+    /// Hashes `preimages`, `A` elements each, laid out one after the other.
+    pub fn hash(&self, preimages: &[Fr]) -> Result<Vec<Fr>> {
+        let arity = A::to_usize();
+        ensure!(
+            preimages.len() % arity == 0,
+            "{} elements are not a whole number of preimages of arity {}",
+            preimages.len(),
+            arity
+        );
+
+        Ok(self.pool.install(|| {
+            preimages
+                .par_chunks(arity)
+                .map(|preimage| Poseidon::new_with_preimage(preimage, A::PARAMETERS()).hash())
+                .collect()
+        }))
+    }
+}
+
+/// Roots of a tree built by `PoseidonTreeBuilder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoseidonTreeRoots<D: Domain> {
+    /// Root of every base tree, in order.
+    pub base: Vec<D>,
+    /// Root of every sub tree, empty for trees without sub trees.
+    pub sub: Vec<D>,
+    pub root: D,
+}
+
+/// Builds Poseidon trees, such as tree-r-last and tree-c, in bounded memory.
+///
+/// Every node is hashed with Poseidon, so only trees over `PoseidonHasher`
+/// can be built.
+///
+/// Leaves are read in chunks of at most `arity * batch_size` leaves and every
+/// chunk is reduced to its root before the next one is read, writing each
+/// row of it that belongs to the store as it is hashed. Only the roots of the
+/// chunks are kept until the base tree is complete. The stores are written
+/// to the given configs, one per base tree as returned by `split_config` or
+/// `CacheDir::tree_configs`, so the result opens like any persisted tree,
+/// e.g. with `create_lc_tree` or `create_disk_tree`.
+#[derive(Debug, Clone)]
+pub struct PoseidonTreeBuilder {
+    batch_size: usize,
+    threads: usize,
+}
+
+impl Default for PoseidonTreeBuilder {
+    fn default() -> Self {
+        PoseidonTreeBuilder {
+            batch_size: DEFAULT_POSEIDON_BATCH_SIZE,
+            threads: rayon::current_num_threads(),
+        }
+    }
+}
+
+impl PoseidonTreeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hashes at most `batch_size` preimages at once.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Hashes on `threads` threads.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Builds tree-r-last over `replica`, the leaves of all base trees one
+    /// after the other, and writes the cached rows of every base tree as a
+    /// level cache store to `configs`.
+    pub fn build_tree_r_last<Tree: MerkleTreeTrait<Hasher = PoseidonHasher>, R: Read>(
+        &self,
+        replica: R,
+        base_tree_leafs: usize,
+        configs: &[StoreConfig],
+    ) -> Result<PoseidonTreeRoots<<Tree::Hasher as Hasher>::Domain>> {
+        self.resume_tree_r_last::<Tree, _, _>(replica, base_tree_leafs, configs, &[], |_, _| Ok(()))
+    }
+
+    /// Like `build_tree_r_last`, continuing a build interrupted after the base
+    /// trees whose roots are `completed`. Their stores are left untouched and
+    /// `replica` must start with the leaves of the first base tree to build.
+    ///
+    /// `on_base_tree` is called with the index and the root of every base tree
+    /// once its store is written, to record the progress.
+    pub fn resume_tree_r_last<Tree, R, P>(
+        &self,
+        mut replica: R,
+        base_tree_leafs: usize,
+        configs: &[StoreConfig],
+        completed: &[<Tree::Hasher as Hasher>::Domain],
+        on_base_tree: P,
+    ) -> Result<PoseidonTreeRoots<<Tree::Hasher as Hasher>::Domain>>
+    where
+        Tree: MerkleTreeTrait<Hasher = PoseidonHasher>,
+        R: Read,
+        P: FnMut(usize, <Tree::Hasher as Hasher>::Domain) -> Result<()>,
+    {
+        info!(
+            "building tree-r-last of {} base trees, {} already built",
+            configs.len(),
+            completed.len()
+        );
+        let mut buf = Vec::new();
+
+        self.build::<Tree, _, _>(
+            base_tree_leafs,
+            configs,
+            StoreKind::LevelCache,
+            completed,
+            on_base_tree,
+            |count, leaves| {
+                buf.resize(count * NODE_SIZE, 0);
+                replica
+                    .read_exact(&mut buf)
+                    .context("failed to read replica")?;
+                for node in buf.chunks(NODE_SIZE) {
+                    leaves.push(<Tree::Hasher as Hasher>::Domain::try_from_bytes(node)?.into());
+                }
+
+                Ok(())
+            },
+        )
+    }
+
+    /// Builds tree-c over the columns made of the nodes at the same position
+    /// in every one of `layers`. Each column is hashed with arity `C` into a
+    /// leaf, and every base tree is written as a complete store to `configs`.
+    pub fn build_tree_c<Tree, C, R>(
+        &self,
+        layers: Vec<R>,
+        base_tree_leafs: usize,
+        configs: &[StoreConfig],
+    ) -> Result<PoseidonTreeRoots<<Tree::Hasher as Hasher>::Domain>>
+    where
+        Tree: MerkleTreeTrait<Hasher = PoseidonHasher>,
+        C: PoseidonArity,
+        R: Read,
+    {
+        self.resume_tree_c::<Tree, C, _, _>(layers, base_tree_leafs, configs, &[], |_, _| Ok(()))
+    }
+
+    /// Like `build_tree_c`, continuing a build interrupted after the base trees
+    /// whose roots are `completed`, see `resume_tree_r_last`. Every one of
+    /// `layers` must start with the nodes of the first base tree to build.
+    pub fn resume_tree_c<Tree, C, R, P>(
+        &self,
+        mut layers: Vec<R>,
+        base_tree_leafs: usize,
+        configs: &[StoreConfig],
+        completed: &[<Tree::Hasher as Hasher>::Domain],
+        on_base_tree: P,
+    ) -> Result<PoseidonTreeRoots<<Tree::Hasher as Hasher>::Domain>>
+    where
+        Tree: MerkleTreeTrait<Hasher = PoseidonHasher>,
+        C: PoseidonArity,
+        R: Read,
+        P: FnMut(usize, <Tree::Hasher as Hasher>::Domain) -> Result<()>,
+    {
+        ensure!(
+            layers.len() == C::to_usize(),
+            "expected {} layers, got {}",
+            C::to_usize(),
+            layers.len()
+        );
+        info!(
+            "building tree-c of {} base trees over {} layers, {} already built",
+            configs.len(),
+            layers.len(),
+            completed.len()
+        );
+        let column_hasher = PoseidonBatchHasher::<C>::new(self.threads)?;
+        let mut buf = Vec::new();
+        let mut columns = Vec::new();
+
+        self.build::<Tree, _, _>(
+            base_tree_leafs,
+            configs,
+            StoreKind::Disk,
+            completed,
+            on_base_tree,
+            |count, leaves| {
+                columns.clear();
+                columns.resize(count * layers.len(), Fr::zero());
+                buf.resize(count * NODE_SIZE, 0);
+
+                for (l, layer) in layers.iter_mut().enumerate() {
+                    layer
+                        .read_exact(&mut buf)
+                        .with_context(|| format!("failed to read layer {}", l + 1))?;
+                    for (i, node) in buf.chunks(NODE_SIZE).enumerate() {
+                        columns[i * C::to_usize() + l] =
+                            <Tree::Hasher as Hasher>::Domain::try_from_bytes(node)?.into();
+                    }
+                }
+
+                for batch in columns.chunks(self.batch_size * C::to_usize()) {
+                    leaves.extend(column_hasher.hash(batch)?);
+                }
+
+                Ok(())
+            },
+        )
+    }
+
+    fn build<Tree, P, F>(
+        &self,
+        base_tree_leafs: usize,
+        configs: &[StoreConfig],
+        kind: StoreKind,
+        completed: &[<Tree::Hasher as Hasher>::Domain],
+        mut on_base_tree: P,
+        mut next_leaves: F,
+    ) -> Result<PoseidonTreeRoots<<Tree::Hasher as Hasher>::Domain>>
+    where
+        Tree: MerkleTreeTrait<Hasher = PoseidonHasher>,
+        P: FnMut(usize, <Tree::Hasher as Hasher>::Domain) -> Result<()>,
+        F: FnMut(usize, &mut Vec<Fr>) -> Result<()>,
+    {
+        ensure!(
+            configs.len() == get_base_tree_count::<Tree>(),
+            "expected {} configs, got {}",
+            get_base_tree_count::<Tree>(),
+            configs.len()
+        );
+        ensure!(
+            completed.len() <= configs.len(),
+            "{} base trees completed out of {}",
+            completed.len(),
+            configs.len()
+        );
+        ensure!(self.batch_size > 0, "batch size must not be zero");
+
+        let hasher = PoseidonBatchHasher::<Tree::Arity>::new(self.threads)?;
+        let mut base: Vec<Fr> = completed.iter().map(|root| (*root).into()).collect();
+        for (i, config) in configs.iter().enumerate().skip(completed.len()) {
+            let root = self.build_base_tree::<Tree, _>(
+                &hasher,
+                base_tree_leafs,
+                config,
+                kind,
+                &mut next_leaves,
+            )?;
+            on_base_tree(i, root.into())?;
+            base.push(root);
+        }
+
+        // The sub and top rows are short, they are hashed directly.
+        let sub_tree_arity = Tree::SubTreeArity::to_usize();
+        let top_tree_arity = Tree::TopTreeArity::to_usize();
+        let (sub, root) = if sub_tree_arity == 0 {
+            (Vec::new(), base[0])
+        } else {
+            let sub: Vec<Fr> = base
+                .chunks(sub_tree_arity)
+                .map(|roots| {
+                    Poseidon::new_with_preimage(roots, Tree::SubTreeArity::PARAMETERS()).hash()
+                })
+                .collect();
+            let root = if top_tree_arity == 0 {
+                sub[0]
+            } else {
+                Poseidon::new_with_preimage(&sub, Tree::TopTreeArity::PARAMETERS()).hash()
+            };
+            (sub, root)
+        };
+
+        Ok(PoseidonTreeRoots {
+            base: base.into_iter().map(Into::into).collect(),
+            sub: sub.into_iter().map(Into::into).collect(),
+            root: root.into(),
+        })
+    }
+
+    /// Builds a single base tree, writing the rows `kind` keeps to `config`,
+    /// and returns its root.
+    fn build_base_tree<Tree, F>(
+        &self,
+        hasher: &PoseidonBatchHasher<Tree::Arity>,
+        leafs: usize,
+        config: &StoreConfig,
+        kind: StoreKind,
+        next_leaves: &mut F,
+    ) -> Result<Fr>
+    where
+        Tree: MerkleTreeTrait<Hasher = PoseidonHasher>,
+        F: FnMut(usize, &mut Vec<Fr>) -> Result<()>,
+    {
+        let arity = Tree::Arity::to_usize();
+        let row_count = get_merkle_tree_row_count(leafs, arity);
+        ensure!(
+            arity.pow(row_count as u32 - 1) == leafs,
+            "{} leaves do not make a full tree of arity {}",
+            leafs,
+            arity
+        );
+
+        // Rows below `first_row` are not part of the store, and every row
+        // starts right after the previous one.
+        let (first_row, len) = match kind {
+            StoreKind::Disk => (0, get_merkle_tree_len(leafs, arity)?),
+            StoreKind::LevelCache => (
+                config.rows_to_discard + 1,
+                get_merkle_tree_cache_size(leafs, arity, config.rows_to_discard)?,
+            ),
+        };
+        let mut row_starts = vec![0; row_count];
+        for row in first_row + 1..row_count {
+            row_starts[row] = row_starts[row - 1] + leafs / arity.pow(row as u32 - 1);
+        }
+
+        let path = StoreConfig::data_path(&config.path, &config.id);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .with_context(|| format!("could not create {:?}", path))?;
+        file.set_len((len * NODE_SIZE) as u64)?;
+
+        let write_row = |row: usize, offset: usize, nodes: &[Fr]| -> Result<()> {
+            if row < first_row {
+                return Ok(());
+            }
+            let mut bytes = Vec::with_capacity(nodes.len() * NODE_SIZE);
+            for node in nodes {
+                bytes.extend_from_slice(<Tree::Hasher as Hasher>::Domain::from(*node).as_ref());
+            }
+            file.write_all_at(&bytes, ((row_starts[row] + offset) * NODE_SIZE) as u64)?;
+
+            Ok(())
+        };
+
+        // Largest subtree whose lowest row fits in a batch.
+        let mut chunk_leafs = arity;
+        while chunk_leafs < leafs && chunk_leafs <= self.batch_size {
+            chunk_leafs *= arity;
+        }
+
+        let mut roots = Vec::with_capacity(leafs / chunk_leafs);
+        let mut leaves = Vec::with_capacity(chunk_leafs);
+        let mut chunk_height = 0;
+        for chunk in 0..leafs / chunk_leafs {
+            leaves.clear();
+            next_leaves(chunk_leafs, &mut leaves)?;
+            ensure!(
+                leaves.len() == chunk_leafs,
+                "expected {} leaves, got {}",
+                chunk_leafs,
+                leaves.len()
+            );
+
+            write_row(0, chunk * chunk_leafs, &leaves)?;
+            let mut nodes = self.hash_row(hasher, &leaves)?;
+            let mut row = 1;
+            loop {
+                write_row(row, chunk * nodes.len(), &nodes)?;
+                if nodes.len() == 1 {
+                    break;
+                }
+                nodes = self.hash_row(hasher, &nodes)?;
+                row += 1;
+            }
+
+            chunk_height = row;
+            roots.push(nodes[0]);
+        }
+
+        let mut nodes = roots;
+        let mut row = chunk_height;
+        while nodes.len() > 1 {
+            nodes = self.hash_row(hasher, &nodes)?;
+            row += 1;
+            write_row(row, 0, &nodes)?;
+        }
+        file.sync_all()?;
+
+        Ok(nodes[0])
+    }
+
+    fn hash_row<A: PoseidonArity>(
+        &self,
+        hasher: &PoseidonBatchHasher<A>,
+        nodes: &[Fr],
+    ) -> Result<Vec<Fr>> {
+        let mut parents = Vec::with_capacity(nodes.len() / A::to_usize());
+        for batch in nodes.chunks(self.batch_size * A::to_usize()) {
+            parents.extend(hasher.hash(batch)?);
+        }
+
+        Ok(parents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use generic_array::typenum::{U0, U11, U2, U8};
+
+    use merkletree::store::ReplicaConfig;
+
+    use crate::cache_key::CacheKey;
+
+    type H = PoseidonHasher;
+    type D = <H as Hasher>::Domain;
+
+    fn random_nodes(count: usize) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        (0..count)
+            .flat_map(|_| D::random(&mut rng).into_bytes())
+            .collect()
+    }
+
+    fn to_frs(bytes: &[u8]) -> Vec<Fr> {
+        bytes
+            .chunks(NODE_SIZE)
+            .map(|node| D::try_from_bytes(node).unwrap().into())
+            .collect()
+    }
+
+    /// All rows of the tree over `leaves`, hashed one node at a time.
+    fn naive_tree<A: PoseidonArity>(leaves: Vec<Fr>) -> Vec<Fr> {
+        let mut tree = leaves;
+        let mut row_start = 0;
+        while tree.len() - row_start > 1 {
+            let row: Vec<Fr> = tree[row_start..]
+                .chunks(A::to_usize())
+                .map(|preimage| Poseidon::new_with_preimage(preimage, A::PARAMETERS()).hash())
+                .collect();
+            row_start = tree.len();
+            tree.extend(row);
+        }
+        tree
+    }
+
+    fn store_frs(config: &StoreConfig) -> Vec<Fr> {
+        to_frs(&std::fs::read(StoreConfig::data_path(&config.path, &config.id)).unwrap())
+    }
+
+    fn check_tree_r_last<Tree: MerkleTreeTrait<Hasher = H>>(base_tree_leafs: usize) {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path()).with_rows_to_discard(1);
+        let configs = cache
+            .tree_configs::<Tree>(CacheKey::CommRLastTree, base_tree_leafs)
+            .unwrap();
+        let replica = random_nodes(base_tree_leafs * configs.len());
+
+        let roots = PoseidonTreeBuilder::new()
+            .with_batch_size(4)
+            .with_threads(2)
+            .build_tree_r_last::<Tree, _>(Cursor::new(&replica), base_tree_leafs, &configs)
+            .unwrap();
+        cache
+            .check_tree::<Tree>(
+                CacheKey::CommRLastTree,
+                base_tree_leafs,
+                StoreKind::LevelCache,
+            )
+            .unwrap();
+
+        let leaves = to_frs(&replica);
+        for (i, config) in configs.iter().enumerate() {
+            let expected = naive_tree::<Tree::Arity>(
+                leaves[i * base_tree_leafs..(i + 1) * base_tree_leafs].to_vec(),
+            );
+            let stored = store_frs(config);
+            assert_eq!(&expected[expected.len() - stored.len()..], &stored[..]);
+            assert_eq!(roots.base[i], D::from(expected[expected.len() - 1]));
+        }
+
+        // The batch size does not change the result.
+        let dir = tempfile::tempdir().unwrap();
+        let configs = CacheDir::new(dir.path())
+            .with_rows_to_discard(1)
+            .tree_configs::<Tree>(CacheKey::CommRLastTree, base_tree_leafs)
+            .unwrap();
+        let other = PoseidonTreeBuilder::new()
+            .build_tree_r_last::<Tree, _>(Cursor::new(&replica), base_tree_leafs, &configs)
+            .unwrap();
+        assert_eq!(roots, other);
+    }
+
+    #[test]
+    fn build_tree_r_last_single() {
+        check_tree_r_last::<DiskTree<H, U8, U0, U0>>(4096);
+    }
+
+    #[test]
+    fn build_tree_r_last_sub() {
+        check_tree_r_last::<DiskTree<H, U8, U2, U0>>(512);
+    }
+
+    #[test]
+    fn build_tree_r_last_top() {
+        check_tree_r_last::<DiskTree<H, U8, U8, U2>>(64);
+    }
+
+    #[test]
+    fn build_tree_c_top() {
+        type Tree = DiskTree<H, U8, U8, U2>;
+        let base_tree_leafs = 64;
+        let nodes = base_tree_leafs * get_base_tree_count::<Tree>();
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+        let configs = cache
+            .tree_configs::<Tree>(CacheKey::CommCTree, base_tree_leafs)
+            .unwrap();
+        let layers: Vec<Vec<u8>> = (0..11).map(|_| random_nodes(nodes)).collect();
+
+        let roots = PoseidonTreeBuilder::new()
+            .with_batch_size(16)
+            .build_tree_c::<Tree, U11, _>(
+                layers.iter().map(Cursor::new).collect(),
+                base_tree_leafs,
+                &configs,
+            )
+            .unwrap();
+        cache
+            .check_tree::<Tree>(CacheKey::CommCTree, base_tree_leafs, StoreKind::Disk)
+            .unwrap();
+
+        let layers: Vec<Vec<Fr>> = layers.iter().map(|layer| to_frs(layer)).collect();
+        let leaves: Vec<Fr> = (0..nodes)
+            .map(|i| {
+                let column: Vec<Fr> = layers.iter().map(|layer| layer[i]).collect();
+                Poseidon::new_with_preimage(&column, U11::PARAMETERS()).hash()
+            })
+            .collect();
+
+        let mut base = Vec::new();
+        for (i, config) in configs.iter().enumerate() {
+            let expected =
+                naive_tree::<U8>(leaves[i * base_tree_leafs..(i + 1) * base_tree_leafs].to_vec());
+            assert_eq!(store_frs(config), expected);
+            base.push(expected[expected.len() - 1]);
+        }
+        let sub: Vec<Fr> = base
+            .chunks(8)
+            .map(|roots| Poseidon::new_with_preimage(roots, U8::PARAMETERS()).hash())
+            .collect();
+        let root = Poseidon::new_with_preimage(&sub, U2::PARAMETERS()).hash();
+
+        assert_eq!(roots.sub, sub.into_iter().map(D::from).collect::<Vec<_>>());
+        assert_eq!(roots.root, D::from(root));
+    }
+
+    #[test]
+    fn resume_tree_c_after_some_base_trees() {
+        type Tree = DiskTree<H, U8, U8, U0>;
+        let base_tree_leafs = 64;
+        let nodes = base_tree_leafs * get_base_tree_count::<Tree>();
+        let layers: Vec<Vec<u8>> = (0..2).map(|_| random_nodes(nodes)).collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let configs = CacheDir::new(dir.path())
+            .tree_configs::<Tree>(CacheKey::CommCTree, base_tree_leafs)
+            .unwrap();
+        let mut built = Vec::new();
+        let expected = PoseidonTreeBuilder::new()
+            .resume_tree_c::<Tree, U2, _, _>(
+                layers.iter().map(Cursor::new).collect(),
+                base_tree_leafs,
+                &configs,
+                &[],
+                |i, root| {
+                    built.push((i, root));
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            built,
+            expected
+                .base
+                .iter()
+                .cloned()
+                .enumerate()
+                .collect::<Vec<_>>()
+        );
+        let stores: Vec<Vec<Fr>> = configs.iter().map(store_frs).collect();
+
+        // Interrupt after 3 base trees, losing the stores of the others.
+        for config in &configs[3..] {
+            std::fs::remove_file(StoreConfig::data_path(&config.path, &config.id)).unwrap();
+        }
+        let offset = 3 * base_tree_leafs * NODE_SIZE;
+        let mut resumed = Vec::new();
+        let roots = PoseidonTreeBuilder::new()
+            .with_batch_size(8)
+            .resume_tree_c::<Tree, U2, _, _>(
+                layers
+                    .iter()
+                    .map(|layer| Cursor::new(&layer[offset..]))
+                    .collect(),
+                base_tree_leafs,
+                &configs,
+                &expected.base[..3],
+                |i, _| {
+                    resumed.push(i);
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        assert_eq!(roots, expected);
+        assert_eq!(resumed, (3..8).collect::<Vec<_>>());
+        assert_eq!(configs.iter().map(store_frs).collect::<Vec<_>>(), stores);
+    }
+
+    #[test]
+    fn build_tree_r_last_rejects_short_replica() {
+        type Tree = DiskTree<H, U8, U2, U0>;
+        let dir = tempfile::tempdir().unwrap();
+        let configs = CacheDir::new(dir.path())
+            .tree_configs::<Tree>(CacheKey::CommRLastTree, 64)
+            .unwrap();
+
+        let replica = random_nodes(64 + 8);
+        assert!(PoseidonTreeBuilder::new()
+            .build_tree_r_last::<Tree, _>(Cursor::new(replica), 64, &configs)
+            .is_err());
+    }
+
+    #[test]
+    fn reopen_tree_r_last_and_prove() {
+        type Tree = DiskTree<H, U8, U8, U0>;
+        let base_tree_leafs = 64;
+        let base_tree_count = get_base_tree_count::<Tree>();
+
+        let dir = tempfile::tempdir().unwrap();
+        let configs = CacheDir::new(dir.path())
+            .with_rows_to_discard(1)
+            .tree_configs::<Tree>(CacheKey::CommRLastTree, base_tree_leafs)
+            .unwrap();
+        let replica = random_nodes(base_tree_leafs * base_tree_count);
+        let replica_path = dir.path().join("replica");
+        std::fs::write(&replica_path, &replica).unwrap();
+
+        let roots = PoseidonTreeBuilder::new()
+            .with_batch_size(4)
+            .build_tree_r_last::<Tree, _>(Cursor::new(&replica), base_tree_leafs, &configs)
+            .unwrap();
+
+        let offsets = (0..base_tree_count)
+            .map(|i| i * base_tree_leafs * NODE_SIZE)
+            .collect();
+        let replica_config = ReplicaConfig::new(&replica_path, offsets);
+        let tree = LCTree::<H, U8, U8, U0>::from_store_configs_and_replica(
+            base_tree_leafs,
+            &configs,
+            &replica_config,
+        )
+        .unwrap();
+        assert_eq!(tree.root(), roots.root);
+
+        let leafs = base_tree_leafs * base_tree_count;
+        for &i in &[0, 1, base_tree_leafs + 5, leafs - 1] {
+            let proof = tree.gen_proof(i).unwrap();
+            assert!(proof.verify());
+            assert!(proof.validate(i));
+            assert_eq!(proof.root(), roots.root);
+        }
+    }
+}
@@ -20,9 +20,12 @@ pub trait MerkleTreeTrait: Send + Sync + std::fmt::Debug {
     type SubTreeArity: 'static + PoseidonArity;
     type TopTreeArity: 'static + PoseidonArity;
     type Hasher: 'static + Hasher;
+    /// Hasher of the sub and top tree levels, see `HybridMerkleTree`.
+    type TopHasher: 'static + Hasher;
     type Store: Store<<Self::Hasher as Hasher>::Domain>;
     type Proof: MerkleProofTrait<
         Hasher = Self::Hasher,
+        TopHasher = Self::TopHasher,
         Arity = Self::Arity,
         SubTreeArity = Self::SubTreeArity,
         TopTreeArity = Self::TopTreeArity,
@@ -37,6 +40,9 @@ pub trait MerkleTreeTrait: Send + Sync + std::fmt::Debug {
     fn gen_cached_proof(&self, i: usize, rows_to_discard: Option<usize>) -> Result<Self::Proof>;
     fn row_count(&self) -> usize;
     fn leaves(&self) -> usize;
+
+    /// Wraps a tree built by merkletree. Fails for shapes merkletree cannot
+    /// build, such as hybrid trees.
     fn from_merkle(
         tree: merkle::MerkleTree<
             <Self::Hasher as Hasher>::Domain,
@@ -46,7 +52,7 @@ pub trait MerkleTreeTrait: Send + Sync + std::fmt::Debug {
             Self::SubTreeArity,
             Self::TopTreeArity,
         >,
-    ) -> Self;
+    ) -> Result<Self>;
 }
 
 pub struct MerkleTreeWrapper<
@@ -72,6 +78,7 @@ impl<
     type SubTreeArity = V;
     type TopTreeArity = W;
     type Hasher = H;
+    type TopHasher = H;
     type Store = S;
     type Proof = MerkleProof<Self::Hasher, Self::Arity, Self::SubTreeArity, Self::TopTreeArity>;
 
@@ -126,8 +133,8 @@ impl<
             Self::SubTreeArity,
             Self::TopTreeArity,
         >,
-    ) -> Self {
-        tree.into()
+    ) -> Result<Self> {
+        Ok(tree.into())
     }
 }
 
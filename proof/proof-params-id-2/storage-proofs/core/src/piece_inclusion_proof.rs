@@ -0,0 +1,267 @@
+use std::convert::TryFrom;
+
+use anyhow::{ensure, Result};
+use generic_array::typenum::{U0, U2};
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+use crate::hasher::Hasher;
+use crate::merkle::{InclusionPath, MerkleProofTrait, MerkleTreeTrait, PathElement};
+
+/// Proof that a piece commitment (comm_p) is the root of an aligned subtree of
+/// tree-d, i.e. that the piece is contained in the sector committed to by comm_d.
+///
+/// The piece covers `number_of_leaves` nodes starting at leaf `position`.
+/// `number_of_leaves` must be a power of two and `position` a multiple of it,
+/// which is what piece alignment in a sector guarantees.
+#[derive(Debug, Clone)]
+pub struct PieceInclusionProof<H: Hasher> {
+    position: usize,
+    number_of_leaves: usize,
+    /// The path from the root of the piece subtree to comm_d.
+    path: InclusionPath<H, U2>,
+}
+
+/// Wire format of a `PieceInclusionProof`. Path indices are fully determined by
+/// the position of the piece, so only the sibling hashes are stored.
+#[derive(Serialize, Deserialize)]
+struct CompactPieceInclusionProof<D> {
+    position: u64,
+    number_of_leaves: u64,
+    siblings: Vec<D>,
+}
+
+impl<H: Hasher> PieceInclusionProof<H> {
+    /// Generates the proof for the piece covering `number_of_leaves` leaves of
+    /// `tree` starting at leaf `position`.
+    pub fn generate<Tree>(tree: &Tree, position: usize, number_of_leaves: usize) -> Result<Self>
+    where
+        Tree: MerkleTreeTrait<Hasher = H, Arity = U2, SubTreeArity = U0, TopTreeArity = U0>,
+    {
+        ensure!(
+            is_aligned(position, number_of_leaves),
+            "piece of {} leaves at {} is not aligned",
+            number_of_leaves,
+            position
+        );
+        ensure!(
+            position
+                .checked_add(number_of_leaves)
+                .map_or(false, |end| end <= tree.leaves()),
+            "piece exceeds the tree ({} leaves)",
+            tree.leaves()
+        );
+
+        // The path of the first leaf of the piece passes through the root of
+        // the piece subtree, so its upper part is the path we want.
+        let subtree_height = log2(number_of_leaves);
+        let proof = tree.gen_proof(position)?;
+        let path = proof
+            .path()
+            .into_iter()
+            .skip(subtree_height)
+            .map(|(hashes, index)| PathElement::new(hashes, index))
+            .collect::<Vec<_>>();
+
+        Ok(PieceInclusionProof {
+            position,
+            number_of_leaves,
+            path: path.into(),
+        })
+    }
+
+    /// Verifies that `comm_p`, the commitment of a piece of `number_of_leaves`
+    /// leaves, is included in `comm_d`, the commitment of a sector of
+    /// `sector_leaves` leaves.
+    pub fn verify(
+        &self,
+        comm_d: &H::Domain,
+        comm_p: &H::Domain,
+        number_of_leaves: usize,
+        sector_leaves: usize,
+    ) -> bool {
+        if number_of_leaves != self.number_of_leaves
+            || !is_aligned(self.position, number_of_leaves)
+            || !sector_leaves.is_power_of_two()
+            || self
+                .position
+                .checked_add(number_of_leaves)
+                .map_or(true, |end| end > sector_leaves)
+        {
+            return false;
+        }
+
+        let subtree_height = log2(number_of_leaves);
+        if self.path.len() != log2(sector_leaves) - subtree_height {
+            return false;
+        }
+
+        // The path must lead to the claimed position, not just to comm_d.
+        if self.path.path_index() != self.position >> subtree_height {
+            return false;
+        }
+
+        self.path.root_from_height(*comm_p, subtree_height) == *comm_d
+    }
+
+    /// Index of the first leaf of the piece in tree-d.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Number of tree-d leaves covered by the piece.
+    pub fn number_of_leaves(&self) -> usize {
+        self.number_of_leaves
+    }
+
+    pub fn path(&self) -> &InclusionPath<H, U2> {
+        &self.path
+    }
+}
+
+impl<H: Hasher> Serialize for PieceInclusionProof<H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        CompactPieceInclusionProof {
+            position: self.position as u64,
+            number_of_leaves: self.number_of_leaves as u64,
+            siblings: self.path.iter().map(|el| el.hashes()[0]).collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, H: Hasher> Deserialize<'de> for PieceInclusionProof<H> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let compact = CompactPieceInclusionProof::<H::Domain>::deserialize(deserializer)?;
+        let position = usize::try_from(compact.position).map_err(de::Error::custom)?;
+        let number_of_leaves =
+            usize::try_from(compact.number_of_leaves).map_err(de::Error::custom)?;
+
+        if !is_aligned(position, number_of_leaves) {
+            return Err(de::Error::custom(format!(
+                "piece of {} leaves at {} is not aligned",
+                number_of_leaves, position
+            )));
+        }
+
+        let subtree_index = position >> log2(number_of_leaves);
+        let path = compact
+            .siblings
+            .into_iter()
+            .enumerate()
+            .map(|(height, sibling)| PathElement::new(vec![sibling], (subtree_index >> height) & 1))
+            .collect::<Vec<_>>();
+
+        Ok(PieceInclusionProof {
+            position,
+            number_of_leaves,
+            path: path.into(),
+        })
+    }
+}
+
+fn is_aligned(position: usize, number_of_leaves: usize) -> bool {
+    number_of_leaves.is_power_of_two() && position % number_of_leaves == 0
+}
+
+fn log2(n: usize) -> usize {
+    debug_assert!(n.is_power_of_two());
+    n.trailing_zeros() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::hasher::{Domain, Sha256Hasher};
+    use crate::merkle::BinaryMerkleTree;
+
+    type H = Sha256Hasher;
+
+    fn piece_commitment(leaves: &[<H as Hasher>::Domain]) -> <H as Hasher>::Domain {
+        if leaves.len() == 1 {
+            return leaves[0];
+        }
+        BinaryMerkleTree::<H>::new(leaves.iter().copied())
+            .expect("failed to build piece tree")
+            .root()
+    }
+
+    #[test]
+    fn piece_inclusion_proofs_at_offsets_and_sizes() {
+        let sector_leaves = 64;
+        let mut rng = rand::thread_rng();
+        let leaves: Vec<<H as Hasher>::Domain> = (0..sector_leaves)
+            .map(|_| <H as Hasher>::Domain::random(&mut rng))
+            .collect();
+        let tree = BinaryMerkleTree::<H>::new(leaves.iter().copied()).unwrap();
+        let comm_d = tree.root();
+
+        let pieces = [
+            (0, 1),
+            (5, 1),
+            (63, 1),
+            (0, 2),
+            (6, 2),
+            (4, 4),
+            (8, 8),
+            (48, 16),
+            (32, 32),
+            (0, 64),
+        ];
+        for &(position, number_of_leaves) in pieces.iter() {
+            let comm_p = piece_commitment(&leaves[position..position + number_of_leaves]);
+            let proof = PieceInclusionProof::generate(&tree, position, number_of_leaves).unwrap();
+
+            assert!(
+                proof.verify(&comm_d, &comm_p, number_of_leaves, sector_leaves),
+                "failed to verify piece at {} of {} leaves",
+                position,
+                number_of_leaves
+            );
+
+            // Any other piece commitment must be rejected.
+            let other = <H as Hasher>::Domain::random(&mut rng);
+            assert!(!proof.verify(&comm_d, &other, number_of_leaves, sector_leaves));
+            assert!(!proof.verify(&other, &comm_p, number_of_leaves, sector_leaves));
+
+            let serialized = serde_json::to_string(&proof).unwrap();
+            let decoded: PieceInclusionProof<H> = serde_json::from_str(&serialized).unwrap();
+            assert_eq!(decoded.position(), position);
+            assert_eq!(decoded.number_of_leaves(), number_of_leaves);
+            assert!(decoded.verify(&comm_d, &comm_p, number_of_leaves, sector_leaves));
+        }
+    }
+
+    #[test]
+    fn piece_inclusion_proof_rejects_misalignment() {
+        let mut rng = rand::thread_rng();
+        let leaves: Vec<<H as Hasher>::Domain> = (0..16)
+            .map(|_| <H as Hasher>::Domain::random(&mut rng))
+            .collect();
+        let tree = BinaryMerkleTree::<H>::new(leaves.iter().copied()).unwrap();
+
+        assert!(PieceInclusionProof::generate(&tree, 2, 4).is_err());
+        assert!(PieceInclusionProof::generate(&tree, 0, 3).is_err());
+        assert!(PieceInclusionProof::generate(&tree, 16, 4).is_err());
+
+        // A proof for one piece does not prove a same-sized piece elsewhere.
+        let comm_p = piece_commitment(&leaves[4..8]);
+        let proof = PieceInclusionProof::generate(&tree, 8, 4).unwrap();
+        assert!(!proof.verify(&tree.root(), &comm_p, 4, 16));
+
+        let bad = r#"{"position":2,"number_of_leaves":4,"siblings":[]}"#;
+        assert!(serde_json::from_str::<PieceInclusionProof<H>>(bad).is_err());
+
+        // A position close to the end of the address space must not overflow.
+        let number_of_leaves = 1usize << (usize::MAX.count_ones() - 2);
+        let far = format!(
+            r#"{{"position":{},"number_of_leaves":{},"siblings":[]}}"#,
+            3 * number_of_leaves,
+            number_of_leaves
+        );
+        let proof: PieceInclusionProof<H> = serde_json::from_str(&far).unwrap();
+        assert!(!proof.verify(&tree.root(), &comm_p, number_of_leaves, 16));
+    }
+}
@@ -0,0 +1,319 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context};
+use log::info;
+use memmap::Mmap;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use storage_proofs_core::{
+    drgraph::{par_parents, Graph},
+    error::Result,
+    hasher::Hasher,
+    parameter_cache::ParameterSetMetadata,
+};
+
+use super::graph::StackedGraph;
+
+/// Number of nodes whose parents are generated at once.
+const CACHE_BLOCK_NODES: usize = 1 << 14;
+
+const PARENT_BYTES: usize = 4;
+
+enum Data {
+    Memory(Vec<u32>),
+    /// Little-endian `u32`s.
+    Mapped(Mmap),
+}
+
+/// The parents of every node of a stacked graph, generated once and shared by all
+/// layers of all sectors using the graph.
+///
+/// For a 32GiB sector this is 56GiB, so large caches live in a file which is mapped
+/// into memory.
+pub struct ParentCache {
+    nodes: usize,
+    degree: usize,
+    data: Data,
+}
+
+impl std::fmt::Debug for ParentCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ParentCache")
+            .field("nodes", &self.nodes)
+            .field("degree", &self.degree)
+            .field("mapped", &matches!(self.data, Data::Mapped(_)))
+            .finish()
+    }
+}
+
+impl ParentCache {
+    /// Generates the cache of `graph` in memory.
+    pub fn new<H, G>(graph: &StackedGraph<H, G>) -> Result<Self>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Sync + Send,
+    {
+        let parents = par_parents::<H, _>(graph, 0..graph.size(), CACHE_BLOCK_NODES)
+            .map(|block| block.map(|block| block.parents))
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+
+        Ok(ParentCache {
+            nodes: graph.size(),
+            degree: graph.degree(),
+            data: Data::Memory(parents),
+        })
+    }
+
+    /// Maps the cache of `graph` stored in `dir`, generating it first if it does not
+    /// exist yet.
+    ///
+    /// Next to the cache is the SHA-256 digest it was generated with. An existing
+    /// cache without one is rejected, and with `verify` the whole cache is hashed
+    /// and must match it, which takes a while for large graphs.
+    pub fn open_or_generate<H, G>(
+        dir: &Path,
+        graph: &StackedGraph<H, G>,
+        verify: bool,
+    ) -> Result<Self>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Sync + Send,
+    {
+        let path = Self::path(dir, graph);
+        let len = (graph.size() * graph.degree() * PARENT_BYTES) as u64;
+
+        let generated = if path.exists() {
+            let found = fs::metadata(&path)
+                .with_context(|| format!("could not stat {:?}", path))?
+                .len();
+            ensure!(
+                found == len,
+                "parent cache {:?} has {} bytes, expected {}",
+                path,
+                found,
+                len
+            );
+            false
+        } else {
+            info!("generating parent cache {:?}", path);
+            Self::generate(&path, graph)?;
+            true
+        };
+
+        let digest_path = Self::digest_path(&path);
+        let expected = fs::read_to_string(&digest_path)
+            .with_context(|| format!("could not read the digest of parent cache {:?}", path))?;
+
+        let file = File::open(&path).with_context(|| format!("could not open {:?}", path))?;
+        let map =
+            unsafe { Mmap::map(&file) }.with_context(|| format!("could not map {:?}", path))?;
+
+        if verify && !generated {
+            info!("verifying parent cache {:?}", path);
+            let found = hex_digest(&Sha256::digest(&map[..]));
+            ensure!(
+                found == expected.trim(),
+                "parent cache {:?} has digest {}, expected {}",
+                path,
+                found,
+                expected.trim()
+            );
+        }
+
+        Ok(ParentCache {
+            nodes: graph.size(),
+            degree: graph.degree(),
+            data: Data::Mapped(map),
+        })
+    }
+
+    /// Path of the cache of `graph` in `dir`. It depends on the identifier and the
+    /// seed of the graph, so graphs with other parents never share a cache.
+    pub fn path<H, G>(dir: &Path, graph: &StackedGraph<H, G>) -> PathBuf
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Sync + Send,
+    {
+        let digest = Sha256::new()
+            .chain(graph.identifier())
+            .chain(&graph.seed()[..])
+            .result();
+
+        dir.join(format!("v1-sdr-parent-{}.cache", hex_digest(&digest)))
+    }
+
+    /// Path of the file holding the digest of the cache at `path`.
+    fn digest_path(path: &Path) -> PathBuf {
+        path.with_extension("digest")
+    }
+
+    fn generate<H, G>(path: &Path, graph: &StackedGraph<H, G>) -> Result<()>
+    where
+        H: Hasher,
+        G: Graph<H> + ParameterSetMetadata + Sync + Send,
+    {
+        // Write to a temporary file first, so an interrupted generation never leaves
+        // a truncated cache behind.
+        let tmp_path = path.with_extension("tmp");
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .with_context(|| format!("could not create {:?}", tmp_path))?;
+        let mut writer = BufWriter::new(file);
+        let mut hasher = Sha256::new();
+
+        // Generate a bounded number of blocks in parallel at a time.
+        let blocks_per_batch = rayon::current_num_threads() * 4;
+        let batch_nodes = CACHE_BLOCK_NODES * blocks_per_batch;
+        let mut start = 0;
+        while start < graph.size() {
+            let end = std::cmp::min(start + batch_nodes, graph.size());
+            let blocks = par_parents::<H, _>(graph, start..end, CACHE_BLOCK_NODES)
+                .map(|block| {
+                    block.map(|block| {
+                        block
+                            .parents
+                            .iter()
+                            .flat_map(|parent| parent.to_le_bytes().to_vec())
+                            .collect::<Vec<u8>>()
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for block in &blocks {
+                hasher.input(block);
+                writer.write_all(block)?;
+            }
+
+            start = end;
+        }
+
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+
+        // The digest is in place before the cache, so a cache is never found
+        // without the digest of its own data.
+        let digest_path = Self::digest_path(path);
+        let tmp_digest_path = digest_path.with_extension("digest.tmp");
+        fs::write(&tmp_digest_path, hex_digest(&hasher.result()))
+            .with_context(|| format!("could not write {:?}", tmp_digest_path))?;
+        fs::rename(&tmp_digest_path, &digest_path).with_context(|| {
+            format!("could not move {:?} to {:?}", tmp_digest_path, digest_path)
+        })?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("could not move {:?} to {:?}", tmp_path, path))?;
+
+        Ok(())
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.nodes
+    }
+
+    pub fn degree(&self) -> usize {
+        self.degree
+    }
+
+    /// Reads the parents of `node` into `parents`, which holds `self.degree()` entries.
+    #[inline]
+    pub fn read(&self, node: usize, parents: &mut [u32]) {
+        debug_assert!(node < self.nodes);
+        debug_assert_eq!(parents.len(), self.degree);
+
+        let start = node * self.degree;
+        match &self.data {
+            Data::Memory(cache) => parents.copy_from_slice(&cache[start..start + self.degree]),
+            Data::Mapped(map) => {
+                let bytes = &map[start * PARENT_BYTES..(start + self.degree) * PARENT_BYTES];
+                for (parent, bytes) in parents.iter_mut().zip(bytes.chunks_exact(PARENT_BYTES)) {
+                    let mut le = [0u8; PARENT_BYTES];
+                    le.copy_from_slice(bytes);
+                    *parent = u32::from_le_bytes(le);
+                }
+            }
+        }
+    }
+}
+
+fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use storage_proofs_core::drgraph::BASE_DEGREE;
+    use storage_proofs_core::hasher::Sha256Hasher;
+
+    use super::super::{StackedBucketGraph, EXP_DEGREE};
+
+    #[test]
+    fn parent_cache_matches_graph() {
+        let graph = StackedBucketGraph::<Sha256Hasher>::new_stacked(
+            3000,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [4u8; 32],
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        let memory = ParentCache::new(&graph).unwrap();
+        let mapped = ParentCache::open_or_generate(dir.path(), &graph, false).unwrap();
+        // Opening again maps the existing file.
+        let reopened = ParentCache::open_or_generate(dir.path(), &graph, true).unwrap();
+
+        let mut expected = vec![0u32; graph.degree()];
+        let mut parents = vec![0u32; graph.degree()];
+        for node in 0..graph.size() {
+            graph.parents(node, &mut expected).unwrap();
+            for cache in &[&memory, &mapped, &reopened] {
+                cache.read(node, &mut parents);
+                assert_eq!(parents, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn parent_cache_paths_differ_per_graph() {
+        let dir = Path::new("/tmp");
+        let a =
+            StackedBucketGraph::<Sha256Hasher>::new_stacked(64, BASE_DEGREE, EXP_DEGREE, [1; 32])
+                .unwrap();
+        let b =
+            StackedBucketGraph::<Sha256Hasher>::new_stacked(64, BASE_DEGREE, EXP_DEGREE, [2; 32])
+                .unwrap();
+        let c =
+            StackedBucketGraph::<Sha256Hasher>::new_stacked(64, BASE_DEGREE, 4, [1; 32]).unwrap();
+
+        assert_ne!(ParentCache::path(dir, &a), ParentCache::path(dir, &b));
+        assert_ne!(ParentCache::path(dir, &a), ParentCache::path(dir, &c));
+    }
+
+    #[test]
+    fn parent_cache_digest_is_checked() {
+        let graph =
+            StackedBucketGraph::<Sha256Hasher>::new_stacked(256, BASE_DEGREE, EXP_DEGREE, [5; 32])
+                .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        ParentCache::open_or_generate(dir.path(), &graph, true).unwrap();
+
+        // A corrupted cache is only detected when verifying.
+        let path = ParentCache::path(dir.path(), &graph);
+        let mut data = fs::read(&path).unwrap();
+        data[100] ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert!(ParentCache::open_or_generate(dir.path(), &graph, false).is_ok());
+        assert!(ParentCache::open_or_generate(dir.path(), &graph, true).is_err());
+
+        // A cache without its digest is rejected.
+        fs::remove_file(ParentCache::digest_path(&path)).unwrap();
+        assert!(ParentCache::open_or_generate(dir.path(), &graph, false).is_err());
+    }
+}
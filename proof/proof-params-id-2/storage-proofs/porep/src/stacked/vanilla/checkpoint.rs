@@ -0,0 +1,516 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context};
+use generic_array::typenum::Unsigned;
+use log::{info, warn};
+use merkletree::store::StoreConfig;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use storage_proofs_core::{
+    cache_key::CacheKey,
+    drgraph::Graph,
+    error::Result,
+    hasher::{Domain, Hasher, PoseidonArity, PoseidonHasher},
+    merkle::{
+        get_base_tree_count, CacheDir, MerkleTreeTrait, PoseidonTreeBuilder, PoseidonTreeRoots,
+        StoreKind,
+    },
+    parameter_cache::ParameterSetMetadata,
+    util::NODE_SIZE,
+};
+
+use super::cache::ParentCache;
+use super::create_label_multicore::{create_layer_labels_multicore, MulticoreConfig};
+use super::graph::StackedGraph;
+
+/// Progress of sealing a sector, stored in its cache directory under
+/// `CacheKey::SealManifest` so that sealing can continue after a crash.
+///
+/// Entries are only recorded once the files they describe are completely
+/// written, so the manifest never claims more than is on disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealManifest {
+    /// Replica id the layers are labeled for.
+    pub replica_id: Vec<u8>,
+    /// SHA-256 digest of every completed layer, in order. Layer `i`, counted
+    /// from 1, is stored at `layer_path(cache, i)`.
+    pub layers: Vec<[u8; 32]>,
+    /// Roots of the completed base trees of tree-c, in order.
+    pub tree_c: Vec<[u8; 32]>,
+    /// Roots of the completed base trees of tree-r-last, in order.
+    pub tree_r_last: Vec<[u8; 32]>,
+}
+
+impl SealManifest {
+    /// Loads the manifest stored in `cache`. Without one, or if it was written
+    /// for another replica id, sealing starts over with an empty manifest.
+    pub fn load(cache: &CacheDir, replica_id: &[u8]) -> Result<Self> {
+        let path = cache.file_path(CacheKey::SealManifest);
+        let empty = SealManifest {
+            replica_id: replica_id.to_vec(),
+            ..Default::default()
+        };
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(empty),
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not open {:?}", path));
+            }
+        };
+        let manifest: SealManifest = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("could not read {:?}", path))?;
+
+        if manifest.replica_id != replica_id {
+            warn!("{:?} belongs to another replica id, starting over", path);
+            return Ok(empty);
+        }
+
+        Ok(manifest)
+    }
+
+    /// Stores the manifest in `cache`, replacing the previous one atomically.
+    pub fn save(&self, cache: &CacheDir) -> Result<()> {
+        let path = cache.file_path(CacheKey::SealManifest);
+        write_atomically(&path, &serde_json::to_vec(self)?)
+    }
+}
+
+/// Config of layer `layer`, counted from 1, in `cache`, as recorded in
+/// `TemporaryAux::labels`.
+pub fn layer_config(cache: &CacheDir, layer: usize) -> StoreConfig {
+    StoreConfig::new(cache.path(), CacheKey::label_layer(layer), 0)
+}
+
+/// Path of layer `layer`, counted from 1, in `cache`.
+pub fn layer_path(cache: &CacheDir, layer: usize) -> PathBuf {
+    let config = layer_config(cache, layer);
+    StoreConfig::data_path(&config.path, &config.id)
+}
+
+/// Labels layers 1 to `layers` of a sector into `cache`, continuing after the
+/// layers recorded in its `SealManifest`.
+///
+/// Only the last recorded layer, the one the next layer is labeled from, is
+/// read back and checked against its digest. Earlier layers must still have
+/// the right size. Layers failing the checks are labeled again, which also
+/// discards the recorded trees built from them.
+pub fn label_layers_resumable<H, G>(
+    config: &MulticoreConfig,
+    graph: &StackedGraph<H, G>,
+    parent_cache: &ParentCache,
+    replica_id: &H::Domain,
+    layers: usize,
+    cache: &CacheDir,
+) -> Result<SealManifest>
+where
+    H: Hasher,
+    G: Graph<H> + ParameterSetMetadata + Sync + Send,
+{
+    let mut manifest = SealManifest::load(cache, replica_id.as_ref())?;
+    ensure!(
+        manifest.layers.len() <= layers,
+        "{} layers were labeled, expected at most {}",
+        manifest.layers.len(),
+        layers
+    );
+
+    let layer_len = graph.size() * NODE_SIZE;
+    let recorded = manifest.layers.len();
+    let valid = (1..=recorded)
+        .take_while(|layer| match fs::metadata(layer_path(cache, *layer)) {
+            Ok(metadata) => metadata.len() == layer_len as u64,
+            Err(_) => false,
+        })
+        .count();
+    manifest.layers.truncate(valid);
+
+    let mut exp_labels = None;
+    while let Some(digest) = manifest.layers.last() {
+        let layer = manifest.layers.len();
+        let labels = fs::read(layer_path(cache, layer))
+            .with_context(|| format!("could not read layer {}", layer))?;
+        if Sha256::digest(&labels)[..] == digest[..] {
+            exp_labels = Some(labels);
+            break;
+        }
+
+        warn!("layer {} does not match its digest", layer);
+        manifest.layers.pop();
+    }
+
+    if manifest.layers.len() < recorded {
+        warn!(
+            "labeling again from layer {}, {} layers were recorded",
+            manifest.layers.len() + 1,
+            recorded
+        );
+        manifest.tree_c.clear();
+        manifest.tree_r_last.clear();
+        manifest.save(cache)?;
+    }
+
+    for layer in manifest.layers.len() + 1..=layers {
+        info!("labeling layer {} of {}", layer, layers);
+        let mut labels = vec![0u8; layer_len];
+        create_layer_labels_multicore(
+            config,
+            graph,
+            parent_cache,
+            replica_id,
+            layer,
+            &mut labels,
+            exp_labels.as_deref(),
+        )?;
+
+        write_atomically(&layer_path(cache, layer), &labels)?;
+        manifest.layers.push(Sha256::digest(&labels).into());
+        manifest.save(cache)?;
+        exp_labels = Some(labels);
+    }
+
+    Ok(manifest)
+}
+
+/// Builds tree-c over the layers recorded in `manifest`, continuing after the
+/// base trees recorded in it whose stores are still complete.
+pub fn build_tree_c_resumable<Tree, C>(
+    builder: &PoseidonTreeBuilder,
+    cache: &CacheDir,
+    manifest: &mut SealManifest,
+    nodes: usize,
+) -> Result<PoseidonTreeRoots<<Tree::Hasher as Hasher>::Domain>>
+where
+    Tree: MerkleTreeTrait<Hasher = PoseidonHasher>,
+    C: PoseidonArity,
+{
+    ensure!(
+        manifest.layers.len() == C::to_usize(),
+        "tree-c needs {} layers, {} are labeled",
+        C::to_usize(),
+        manifest.layers.len()
+    );
+    let base_tree_leafs = base_tree_leafs::<Tree>(nodes)?;
+    let configs = cache.tree_configs::<Tree>(CacheKey::CommCTree, base_tree_leafs)?;
+    let completed = completed_base_trees::<Tree>(
+        cache,
+        &configs,
+        base_tree_leafs,
+        StoreKind::Disk,
+        &mut manifest.tree_c,
+    )?;
+
+    let offset = (completed.len() * base_tree_leafs * NODE_SIZE) as u64;
+    let layers = (1..=manifest.layers.len())
+        .map(|layer| open_at(&layer_path(cache, layer), offset))
+        .collect::<Result<Vec<_>>>()?;
+
+    builder.resume_tree_c::<Tree, C, _, _>(
+        layers,
+        base_tree_leafs,
+        &configs,
+        &completed,
+        |_, root| {
+            manifest.tree_c.push(to_bytes(&root));
+            manifest.save(cache)
+        },
+    )
+}
+
+/// Builds tree-r-last over the replica at `replica_path`, continuing after the
+/// base trees recorded in `manifest` whose stores are still complete.
+pub fn build_tree_r_last_resumable<Tree: MerkleTreeTrait<Hasher = PoseidonHasher>>(
+    builder: &PoseidonTreeBuilder,
+    cache: &CacheDir,
+    manifest: &mut SealManifest,
+    replica_path: &Path,
+    nodes: usize,
+) -> Result<PoseidonTreeRoots<<Tree::Hasher as Hasher>::Domain>> {
+    let base_tree_leafs = base_tree_leafs::<Tree>(nodes)?;
+    let configs = cache.tree_configs::<Tree>(CacheKey::CommRLastTree, base_tree_leafs)?;
+    let completed = completed_base_trees::<Tree>(
+        cache,
+        &configs,
+        base_tree_leafs,
+        StoreKind::LevelCache,
+        &mut manifest.tree_r_last,
+    )?;
+
+    let offset = (completed.len() * base_tree_leafs * NODE_SIZE) as u64;
+    let replica = open_at(replica_path, offset)?;
+
+    builder.resume_tree_r_last::<Tree, _, _>(
+        replica,
+        base_tree_leafs,
+        &configs,
+        &completed,
+        |_, root| {
+            manifest.tree_r_last.push(to_bytes(&root));
+            manifest.save(cache)
+        },
+    )
+}
+
+pub(crate) fn base_tree_leafs<Tree: MerkleTreeTrait>(nodes: usize) -> Result<usize> {
+    let base_tree_count = get_base_tree_count::<Tree>();
+    ensure!(
+        nodes % base_tree_count == 0,
+        "{} nodes cannot be split into {} base trees",
+        nodes,
+        base_tree_count
+    );
+
+    Ok(nodes / base_tree_count)
+}
+
+/// Roots of the leading base trees recorded in `recorded` whose stores are
+/// complete and end with the recorded root. The others are dropped from
+/// `recorded`, they are built again.
+fn completed_base_trees<Tree: MerkleTreeTrait>(
+    cache: &CacheDir,
+    configs: &[StoreConfig],
+    base_tree_leafs: usize,
+    kind: StoreKind,
+    recorded: &mut Vec<[u8; 32]>,
+) -> Result<Vec<<Tree::Hasher as Hasher>::Domain>> {
+    let mut completed = Vec::new();
+    for (config, root) in configs.iter().zip(recorded.iter()) {
+        if let Err(err) = cache.check_base_tree::<Tree>(config, base_tree_leafs, kind) {
+            warn!("{}", err);
+            break;
+        }
+
+        // The root is the last node of every store.
+        let path = StoreConfig::data_path(&config.path, &config.id);
+        let mut file = open_at(&path, 0)?;
+        file.seek(SeekFrom::End(-(NODE_SIZE as i64)))?;
+        let mut last = [0u8; NODE_SIZE];
+        file.read_exact(&mut last)?;
+        if last != *root {
+            warn!("{:?} does not end with its recorded root", path);
+            break;
+        }
+
+        completed.push(<Tree::Hasher as Hasher>::Domain::try_from_bytes(root)?);
+    }
+    recorded.truncate(completed.len());
+
+    Ok(completed)
+}
+
+fn to_bytes<D: Domain>(domain: &D) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(domain.as_ref());
+    bytes
+}
+
+fn open_at(path: &Path, offset: u64) -> Result<BufReader<File>> {
+    let mut file = File::open(path).with_context(|| format!("could not open {:?}", path))?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    Ok(BufReader::new(file))
+}
+
+/// Writes `data` to a temporary file first, so `path` is never left partially
+/// written.
+pub(crate) fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)
+        .with_context(|| format!("could not create {:?}", tmp_path))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("could not move {:?} to {:?}", tmp_path, path))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use generic_array::typenum::{U0, U2, U8};
+    use storage_proofs_core::drgraph::BASE_DEGREE;
+    use storage_proofs_core::hasher::{PoseidonDomain, Sha256Hasher};
+    use storage_proofs_core::merkle::DiskTree;
+
+    use super::super::{
+        load_aux, store_aux, AuxFormat, StackedBucketGraph, TemporaryAux, EXP_DEGREE,
+    };
+
+    type H = Sha256Hasher;
+    type Tree = DiskTree<PoseidonHasher, U8, U8, U0>;
+
+    const NODES: usize = 512;
+
+    fn setup() -> (StackedBucketGraph<H>, ParentCache, <H as Hasher>::Domain) {
+        let graph = StackedBucketGraph::<H>::new_stacked(NODES, BASE_DEGREE, EXP_DEGREE, [7u8; 32])
+            .unwrap();
+        let parent_cache = ParentCache::new(&graph).unwrap();
+        let replica_id = <H as Hasher>::Domain::random(&mut rand::thread_rng());
+
+        (graph, parent_cache, replica_id)
+    }
+
+    #[test]
+    fn labeling_resumes_after_last_valid_layer() {
+        let (graph, parent_cache, replica_id) = setup();
+        let config = MulticoreConfig::default();
+
+        let full_dir = tempfile::tempdir().unwrap();
+        let full = CacheDir::new(full_dir.path());
+        let expected =
+            label_layers_resumable(&config, &graph, &parent_cache, &replica_id, 3, &full).unwrap();
+        assert_eq!(expected.layers.len(), 3);
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+        label_layers_resumable(&config, &graph, &parent_cache, &replica_id, 2, &cache).unwrap();
+        assert_eq!(
+            SealManifest::load(&cache, replica_id.as_ref())
+                .unwrap()
+                .layers
+                .len(),
+            2
+        );
+
+        // A corrupted last layer is labeled again.
+        let mut labels = fs::read(layer_path(&cache, 2)).unwrap();
+        labels[100] ^= 1;
+        fs::write(layer_path(&cache, 2), &labels).unwrap();
+
+        let manifest =
+            label_layers_resumable(&config, &graph, &parent_cache, &replica_id, 3, &cache).unwrap();
+        assert_eq!(manifest, expected);
+        for layer in 1..=3 {
+            assert_eq!(
+                fs::read(layer_path(&cache, layer)).unwrap(),
+                fs::read(layer_path(&full, layer)).unwrap()
+            );
+        }
+
+        // Another replica id starts over.
+        let other = <H as Hasher>::Domain::random(&mut rand::thread_rng());
+        assert_eq!(
+            SealManifest::load(&cache, other.as_ref()).unwrap(),
+            SealManifest {
+                replica_id: other.into_bytes(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn layers_open_through_t_aux() {
+        let (graph, parent_cache, replica_id) = setup();
+        let config = MulticoreConfig::default();
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+        let manifest =
+            label_layers_resumable(&config, &graph, &parent_cache, &replica_id, 2, &cache).unwrap();
+        store_aux(
+            &cache,
+            &TemporaryAux::new::<Tree>(&cache, 2, NODES).unwrap(),
+            AuxFormat::Binary,
+        )
+        .unwrap();
+
+        let t_aux = load_aux::<TemporaryAux>(&cache, AuxFormat::Binary).unwrap();
+        assert_eq!(t_aux.layers(), 2);
+        for (config, digest) in t_aux.labels.iter().zip(&manifest.layers) {
+            let labels = fs::read(StoreConfig::data_path(&config.path, &config.id)).unwrap();
+            assert_eq!(labels.len(), NODES * NODE_SIZE);
+            assert_eq!(Sha256::digest(&labels)[..], digest[..]);
+        }
+    }
+
+    #[test]
+    fn tree_c_resumes_after_completed_base_trees() {
+        let (graph, parent_cache, replica_id) = setup();
+        let config = MulticoreConfig::default();
+        let builder = PoseidonTreeBuilder::new().with_batch_size(16);
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+        let mut manifest =
+            label_layers_resumable(&config, &graph, &parent_cache, &replica_id, 2, &cache).unwrap();
+        let expected =
+            build_tree_c_resumable::<Tree, U2>(&builder, &cache, &mut manifest, NODES).unwrap();
+        assert_eq!(manifest.tree_c.len(), 8);
+        assert_eq!(
+            SealManifest::load(&cache, replica_id.as_ref()).unwrap(),
+            manifest
+        );
+
+        // Crash after 3 base trees, with the store of the third one truncated.
+        let configs = cache.tree_configs::<Tree>(CacheKey::CommCTree, 64).unwrap();
+        for config in &configs[3..] {
+            fs::remove_file(StoreConfig::data_path(&config.path, &config.id)).unwrap();
+        }
+        let path = StoreConfig::data_path(&configs[2].path, &configs[2].id);
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(100)
+            .unwrap();
+        manifest.tree_c.truncate(3);
+
+        let roots =
+            build_tree_c_resumable::<Tree, U2>(&builder, &cache, &mut manifest, NODES).unwrap();
+        assert_eq!(roots, expected);
+        assert_eq!(manifest.tree_c.len(), 8);
+        cache
+            .check_tree::<Tree>(CacheKey::CommCTree, 64, StoreKind::Disk)
+            .unwrap();
+    }
+
+    #[test]
+    fn tree_r_last_resumes_after_completed_base_trees() {
+        let builder = PoseidonTreeBuilder::new();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path()).with_rows_to_discard(1);
+        let replica_path = dir.path().join("replica");
+        let mut rng = rand::thread_rng();
+        let replica: Vec<u8> = (0..NODES)
+            .flat_map(|_| PoseidonDomain::random(&mut rng).into_bytes())
+            .collect();
+        fs::write(&replica_path, &replica).unwrap();
+
+        let mut manifest = SealManifest::default();
+        let expected = build_tree_r_last_resumable::<Tree>(
+            &builder,
+            &cache,
+            &mut manifest,
+            &replica_path,
+            NODES,
+        )
+        .unwrap();
+        assert_eq!(manifest.tree_r_last.len(), 8);
+
+        // A recorded root not matching its store is built again.
+        manifest.tree_r_last[5] = [0u8; 32];
+        let roots = build_tree_r_last_resumable::<Tree>(
+            &builder,
+            &cache,
+            &mut manifest,
+            &replica_path,
+            NODES,
+        )
+        .unwrap();
+        assert_eq!(roots, expected);
+        assert_eq!(
+            manifest.tree_r_last,
+            expected.base.iter().map(to_bytes).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use anyhow::ensure;
+use sha2::{Digest, Sha256};
+use storage_proofs_core::{
+    crypto::sha256_multi::{sha256_many_with, Sha256Backend},
+    drgraph::Graph,
+    error::Result,
+    hasher::Hasher,
+    parameter_cache::ParameterSetMetadata,
+    util::NODE_SIZE,
+};
+
+use super::graph::StackedGraph;
+
+/// Number of parent labels hashed into every label. The parents of a node are
+/// repeated, in order, until all slots are filled.
+pub const LABEL_PARENTS: usize = 37;
+
+/// Length of the message hashed for a node with parents: the replica id, the
+/// layer and node indices padded to a node, and the parent labels.
+pub(crate) const MESSAGE_LEN: usize = 2 * NODE_SIZE + LABEL_PARENTS * NODE_SIZE;
+
+/// Computes the label of `node` in layer `layer_index`, counted from 1, into
+/// `layer_labels`, which must already hold the labels of all previous nodes
+/// of the layer. `exp_labels` are the labels of the previous layer, for all
+/// layers but the first.
+pub fn create_label<H, G>(
+    graph: &StackedGraph<H, G>,
+    replica_id: &H::Domain,
+    layer_index: usize,
+    node: usize,
+    layer_labels: &mut [u8],
+    exp_labels: Option<&[u8]>,
+) -> Result<()>
+where
+    H: Hasher,
+    G: Graph<H> + ParameterSetMetadata + Sync + Send,
+{
+    let mut parents = vec![0u32; graph.degree()];
+    let mut message = vec![0u8; MESSAGE_LEN];
+    let len = write_message(
+        graph,
+        replica_id,
+        layer_index,
+        node,
+        layer_labels,
+        exp_labels,
+        &mut parents,
+        &mut message,
+    )?;
+
+    let mut label = [0u8; 32];
+    label.copy_from_slice(&Sha256::digest(&message[..len]));
+    write_label(layer_labels, node, label);
+
+    Ok(())
+}
+
+/// Labels all nodes of layer `layer_index` of a single sector.
+pub fn create_layer_labels<H, G>(
+    graph: &StackedGraph<H, G>,
+    replica_id: &H::Domain,
+    layer_index: usize,
+    layer_labels: &mut [u8],
+    exp_labels: Option<&[u8]>,
+) -> Result<()>
+where
+    H: Hasher,
+    G: Graph<H> + ParameterSetMetadata + Sync + Send,
+{
+    ensure!(
+        layer_labels.len() == graph.size() * NODE_SIZE,
+        "layer of {} bytes for {} nodes",
+        layer_labels.len(),
+        graph.size()
+    );
+
+    for node in 0..graph.size() {
+        create_label(
+            graph,
+            replica_id,
+            layer_index,
+            node,
+            layer_labels,
+            exp_labels,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Labels layer `layer_index` of several sectors sharing `graph`, one entry
+/// of `replica_ids`, `layers` and `exp_layers` per sector.
+///
+/// The nodes of a layer depend on each other, but the same node of different
+/// sectors does not, so every node is labeled in all sectors at once with
+/// `sha256_many`, using as many SIMD lanes as the CPU offers.
+///
+/// Sealing a single sector gains nothing from it and labels with
+/// `create_layer_labels_multicore`, this is for callers sealing several
+/// sectors at once.
+pub fn create_layer_labels_multi<H, G>(
+    graph: &StackedGraph<H, G>,
+    replica_ids: &[H::Domain],
+    layer_index: usize,
+    layers: &mut [&mut [u8]],
+    exp_layers: Option<&[&[u8]]>,
+) -> Result<()>
+where
+    H: Hasher,
+    G: Graph<H> + ParameterSetMetadata + Sync + Send,
+{
+    create_layer_labels_multi_with(
+        Sha256Backend::detect(),
+        graph,
+        replica_ids,
+        layer_index,
+        layers,
+        exp_layers,
+    )
+}
+
+/// Like `create_layer_labels_multi`, hashing with `backend`.
+pub fn create_layer_labels_multi_with<H, G>(
+    backend: Sha256Backend,
+    graph: &StackedGraph<H, G>,
+    replica_ids: &[H::Domain],
+    layer_index: usize,
+    layers: &mut [&mut [u8]],
+    exp_layers: Option<&[&[u8]]>,
+) -> Result<()>
+where
+    H: Hasher,
+    G: Graph<H> + ParameterSetMetadata + Sync + Send,
+{
+    let sectors = replica_ids.len();
+    ensure!(
+        layers.len() == sectors,
+        "expected {} layers, got {}",
+        sectors,
+        layers.len()
+    );
+    ensure!(
+        layers
+            .iter()
+            .all(|layer| layer.len() == graph.size() * NODE_SIZE),
+        "layers must have {} bytes",
+        graph.size() * NODE_SIZE
+    );
+    if let Some(exp_layers) = exp_layers {
+        ensure!(
+            exp_layers.len() == sectors,
+            "expected {} previous layers, got {}",
+            sectors,
+            exp_layers.len()
+        );
+    }
+
+    let mut parents = vec![0u32; graph.degree()];
+    let mut messages = vec![vec![0u8; MESSAGE_LEN]; sectors];
+    let mut digests = vec![[0u8; 32]; sectors];
+
+    for node in 0..graph.size() {
+        let mut len = 0;
+        for (sector, message) in messages.iter_mut().enumerate() {
+            len = write_message(
+                graph,
+                &replica_ids[sector],
+                layer_index,
+                node,
+                &*layers[sector],
+                exp_layers.map(|exp_layers| exp_layers[sector]),
+                &mut parents,
+                message,
+            )?;
+        }
+
+        let refs: Vec<&[u8]> = messages.iter().map(|message| &message[..len]).collect();
+        sha256_many_with(backend, &refs, &mut digests);
+
+        for (layer, digest) in layers.iter_mut().zip(digests.iter()) {
+            write_label(layer, node, *digest);
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the message hashed into the label of `node` and returns its length.
+#[allow(clippy::too_many_arguments)]
+fn write_message<H, G>(
+    graph: &StackedGraph<H, G>,
+    replica_id: &H::Domain,
+    layer_index: usize,
+    node: usize,
+    layer_labels: &[u8],
+    exp_labels: Option<&[u8]>,
+    parents: &mut [u32],
+    message: &mut [u8],
+) -> Result<usize>
+where
+    H: Hasher,
+    G: Graph<H> + ParameterSetMetadata + Sync + Send,
+{
+    write_header::<H>(replica_id, layer_index, node, message);
+
+    // The first node has no parents.
+    if node == 0 {
+        return Ok(2 * NODE_SIZE);
+    }
+
+    graph.parents(node, parents)?;
+    let base_degree = graph.base_graph().degree();
+    let count = if exp_labels.is_some() {
+        graph.degree()
+    } else {
+        base_degree
+    };
+
+    for slot in 0..LABEL_PARENTS {
+        let i = slot % count;
+        let start = parents[i] as usize * NODE_SIZE;
+        let label = if i < base_degree {
+            &layer_labels[start..start + NODE_SIZE]
+        } else {
+            let exp_labels = exp_labels.expect("checked above");
+            &exp_labels[start..start + NODE_SIZE]
+        };
+
+        let offset = 2 * NODE_SIZE + slot * NODE_SIZE;
+        message[offset..offset + NODE_SIZE].copy_from_slice(label);
+    }
+
+    Ok(MESSAGE_LEN)
+}
+
+/// Writes the replica id and the layer and node indices to the first two nodes of
+/// `message`.
+pub(crate) fn write_header<H: Hasher>(
+    replica_id: &H::Domain,
+    layer_index: usize,
+    node: usize,
+    message: &mut [u8],
+) {
+    message[..NODE_SIZE].copy_from_slice(AsRef::<[u8]>::as_ref(replica_id));
+    for byte in &mut message[NODE_SIZE..2 * NODE_SIZE] {
+        *byte = 0;
+    }
+    message[NODE_SIZE..NODE_SIZE + 4].copy_from_slice(&(layer_index as u32).to_be_bytes());
+    message[NODE_SIZE + 4..NODE_SIZE + 12].copy_from_slice(&(node as u64).to_be_bytes());
+}
+
+fn write_label(layer_labels: &mut [u8], node: usize, mut label: [u8; 32]) {
+    trim_label(&mut label);
+    layer_labels[node * NODE_SIZE..(node + 1) * NODE_SIZE].copy_from_slice(&label);
+}
+
+pub(crate) fn trim_label(label: &mut [u8; 32]) {
+    // Strip the last two bits, to ensure the label is in Fr.
+    label[31] &= 0b0011_1111;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use storage_proofs_core::drgraph::BASE_DEGREE;
+    use storage_proofs_core::hasher::{Domain, Sha256Hasher};
+
+    use super::super::{StackedBucketGraph, EXP_DEGREE};
+
+    type H = Sha256Hasher;
+
+    fn check_multi(backend: Sha256Backend, base_degree: usize, expansion_degree: usize) {
+        if !backend.is_available() {
+            return;
+        }
+
+        let mut rng = rand::thread_rng();
+        let nodes = 64;
+        let graph =
+            StackedBucketGraph::<H>::new_stacked(nodes, base_degree, expansion_degree, [1u8; 32])
+                .unwrap();
+        let replica_ids: Vec<_> = (0..5)
+            .map(|_| <H as Hasher>::Domain::random(&mut rng))
+            .collect();
+
+        let mut expected = vec![vec![vec![0u8; nodes * NODE_SIZE]; 2]; replica_ids.len()];
+        for (replica_id, layers) in replica_ids.iter().zip(expected.iter_mut()) {
+            let (first, second) = layers.split_at_mut(1);
+            create_layer_labels(&graph, replica_id, 1, &mut first[0], None).unwrap();
+            create_layer_labels(&graph, replica_id, 2, &mut second[0], Some(&first[0][..]))
+                .unwrap();
+        }
+
+        let mut first = vec![vec![0u8; nodes * NODE_SIZE]; replica_ids.len()];
+        let mut second = first.clone();
+        {
+            let mut layers: Vec<&mut [u8]> = first.iter_mut().map(|l| &mut l[..]).collect();
+            create_layer_labels_multi_with(backend, &graph, &replica_ids, 1, &mut layers, None)
+                .unwrap();
+        }
+        {
+            let exp_layers: Vec<&[u8]> = first.iter().map(|l| &l[..]).collect();
+            let mut layers: Vec<&mut [u8]> = second.iter_mut().map(|l| &mut l[..]).collect();
+            create_layer_labels_multi_with(
+                backend,
+                &graph,
+                &replica_ids,
+                2,
+                &mut layers,
+                Some(&exp_layers),
+            )
+            .unwrap();
+        }
+
+        for (sector, layers) in expected.iter().enumerate() {
+            assert_eq!(layers[0], first[sector], "{:?}", backend);
+            assert_eq!(layers[1], second[sector], "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn multi_labels_match_single_labels() {
+        check_multi(Sha256Backend::Scalar, BASE_DEGREE, EXP_DEGREE);
+        check_multi(Sha256Backend::Sse2, BASE_DEGREE, EXP_DEGREE);
+        check_multi(Sha256Backend::Avx2, BASE_DEGREE, EXP_DEGREE);
+    }
+
+    #[test]
+    fn multi_labels_with_other_degrees() {
+        check_multi(Sha256Backend::Scalar, 3, 5);
+        check_multi(Sha256Backend::detect(), 8, 12);
+    }
+
+    #[test]
+    fn first_label_hashes_replica_id_and_indices() {
+        let graph =
+            StackedBucketGraph::<H>::new_stacked(8, BASE_DEGREE, EXP_DEGREE, [1u8; 32]).unwrap();
+        let replica_id = <H as Hasher>::Domain::random(&mut rand::thread_rng());
+        let mut labels = vec![0u8; 8 * NODE_SIZE];
+        create_label(&graph, &replica_id, 3, 0, &mut labels, None).unwrap();
+
+        let mut message = replica_id.into_bytes();
+        message.extend_from_slice(&3u32.to_be_bytes());
+        message.extend_from_slice(&[0u8; 28]);
+        let mut expected = [0u8; 32];
+        expected.copy_from_slice(&Sha256::digest(&message));
+        expected[31] &= 0b0011_1111;
+
+        assert_eq!(&labels[..NODE_SIZE], &expected[..]);
+    }
+}
@@ -0,0 +1,404 @@
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+
+use anyhow::{ensure, Context};
+use sha2::{Digest, Sha256};
+use storage_proofs_core::{
+    drgraph::Graph, error::Result, hasher::Hasher, parameter_cache::ParameterSetMetadata,
+    util::NODE_SIZE,
+};
+
+use super::cache::ParentCache;
+use super::create_label::{trim_label, write_header, LABEL_PARENTS, MESSAGE_LEN};
+use super::graph::StackedGraph;
+
+/// Settings of `create_layer_labels_multicore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MulticoreConfig {
+    /// Number of threads prefetching parent labels. One more thread hashes them.
+    pub producers: usize,
+    /// Number of nodes whose parent labels can be prefetched ahead of the hashing.
+    pub ring_size: usize,
+    /// Number of consecutive nodes a producer claims at once.
+    pub batch_size: usize,
+}
+
+impl Default for MulticoreConfig {
+    fn default() -> Self {
+        // A few producers keep a single hashing core busy, more only compete for
+        // memory bandwidth.
+        let producers = std::cmp::min(4, rayon::current_num_threads().saturating_sub(1)).max(1);
+
+        MulticoreConfig {
+            producers,
+            ring_size: 1 << 12,
+            batch_size: 64,
+        }
+    }
+}
+
+/// A byte buffer written by one thread and read by others. Accesses are ordered
+/// through the atomics of `create_layer_labels_multicore`, which guarantee that no
+/// range is read while it is written.
+#[derive(Clone, Copy)]
+struct SharedBuffer<'a> {
+    ptr: *mut u8,
+    len: usize,
+    _buf: PhantomData<&'a mut [u8]>,
+}
+
+unsafe impl Send for SharedBuffer<'_> {}
+unsafe impl Sync for SharedBuffer<'_> {}
+
+impl<'a> SharedBuffer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        SharedBuffer {
+            ptr: buf.as_mut_ptr(),
+            len: buf.len(),
+            _buf: PhantomData,
+        }
+    }
+
+    /// The caller must make sure no other thread writes to the range.
+    unsafe fn slice(&self, offset: usize, len: usize) -> &[u8] {
+        assert!(offset + len <= self.len);
+        std::slice::from_raw_parts(self.ptr.add(offset), len)
+    }
+
+    /// The caller must make sure no other thread accesses the range.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn slice_mut(&self, offset: usize, len: usize) -> &mut [u8] {
+        assert!(offset + len <= self.len);
+        std::slice::from_raw_parts_mut(self.ptr.add(offset), len)
+    }
+}
+
+/// State shared by the producers and the consumer of one layer.
+struct Pipeline<'a> {
+    cache: &'a ParentCache,
+    layer_labels: SharedBuffer<'a>,
+    exp_labels: Option<&'a [u8]>,
+    /// Parent labels of `ring_size` nodes, `count` labels per node.
+    ring: SharedBuffer<'a>,
+    ring_size: usize,
+    batch_size: usize,
+    nodes: usize,
+    base_degree: usize,
+    /// Number of parents hashed into a label.
+    count: usize,
+    /// Per slot, the base parents copied by the producer, as a bit mask.
+    copied: Vec<AtomicU64>,
+    /// Per slot, the node whose parents it holds, plus one.
+    filled: Vec<AtomicUsize>,
+    /// Number of labels written so far.
+    done: AtomicUsize,
+    /// Next batch to be claimed by a producer.
+    next_batch: AtomicUsize,
+}
+
+impl Pipeline<'_> {
+    fn slot_len(&self) -> usize {
+        self.count * NODE_SIZE
+    }
+
+    /// Prefetches the parent labels of batches of nodes until all are claimed.
+    ///
+    /// Expander parents come from the previous layer and are always available. Base
+    /// parents are labels of the same layer, only the ones already written are
+    /// copied, the consumer reads the rest itself.
+    fn produce(&self) {
+        let mut parents = vec![0u32; self.cache.degree()];
+
+        loop {
+            let start = self.next_batch.fetch_add(1, Ordering::SeqCst) * self.batch_size;
+            if start >= self.nodes {
+                return;
+            }
+            let end = std::cmp::min(start + self.batch_size, self.nodes);
+
+            for node in start..end {
+                // Wait until the slot is no longer needed by the consumer.
+                while node >= self.done.load(Ordering::Acquire) + self.ring_size {
+                    thread::yield_now();
+                }
+
+                let slot = node % self.ring_size;
+                let mut copied = 0u64;
+                // The first node has no parents.
+                if node > 0 {
+                    // Safe: the consumer is done with this slot and only reads it
+                    // again once `filled` is set below.
+                    let labels =
+                        unsafe { self.ring.slice_mut(slot * self.slot_len(), self.slot_len()) };
+                    self.cache.read(node, &mut parents);
+                    let available = self.done.load(Ordering::Acquire);
+
+                    for (i, (parent, label)) in parents[..self.count]
+                        .iter()
+                        .zip(labels.chunks_exact_mut(NODE_SIZE))
+                        .enumerate()
+                    {
+                        let start = *parent as usize * NODE_SIZE;
+                        if i >= self.base_degree {
+                            let exp_labels = self.exp_labels.expect("count includes expanders");
+                            label.copy_from_slice(&exp_labels[start..start + NODE_SIZE]);
+                        } else if (*parent as usize) < available {
+                            // Safe: labels below `done` are never written again.
+                            label.copy_from_slice(unsafe {
+                                self.layer_labels.slice(start, NODE_SIZE)
+                            });
+                            copied |= 1 << i;
+                        }
+                    }
+                }
+
+                self.copied[slot].store(copied, Ordering::Relaxed);
+                self.filled[slot].store(node + 1, Ordering::Release);
+            }
+        }
+    }
+
+    /// Labels all nodes in order, from the parent labels in the ring.
+    fn consume<H: Hasher>(&self, replica_id: &H::Domain, layer_index: usize) {
+        let mut parents = vec![0u32; self.cache.degree()];
+        let mut message = vec![0u8; MESSAGE_LEN];
+        let all_copied = (1u64 << self.base_degree) - 1;
+
+        for node in 0..self.nodes {
+            let slot = node % self.ring_size;
+            while self.filled[slot].load(Ordering::Acquire) != node + 1 {
+                thread::yield_now();
+            }
+
+            write_header::<H>(replica_id, layer_index, node, &mut message);
+            let len = if node == 0 {
+                2 * NODE_SIZE
+            } else {
+                // Safe: the producer of this slot does not touch it before `done`
+                // moves past `node`.
+                let labels =
+                    unsafe { self.ring.slice_mut(slot * self.slot_len(), self.slot_len()) };
+
+                let copied = self.copied[slot].load(Ordering::Relaxed);
+                if copied != all_copied {
+                    self.cache.read(node, &mut parents);
+                    for (i, parent) in parents[..self.base_degree].iter().enumerate() {
+                        if copied & (1 << i) == 0 {
+                            let start = *parent as usize * NODE_SIZE;
+                            // Safe: parents precede `node`, their labels are final.
+                            labels[i * NODE_SIZE..(i + 1) * NODE_SIZE].copy_from_slice(unsafe {
+                                self.layer_labels.slice(start, NODE_SIZE)
+                            });
+                        }
+                    }
+                }
+
+                for slot in 0..LABEL_PARENTS {
+                    let i = slot % self.count;
+                    let offset = 2 * NODE_SIZE + slot * NODE_SIZE;
+                    message[offset..offset + NODE_SIZE]
+                        .copy_from_slice(&labels[i * NODE_SIZE..(i + 1) * NODE_SIZE]);
+                }
+
+                MESSAGE_LEN
+            };
+
+            let mut label = [0u8; 32];
+            label.copy_from_slice(&Sha256::digest(&message[..len]));
+            trim_label(&mut label);
+            // Safe: producers only read labels below `done`.
+            unsafe { self.layer_labels.slice_mut(node * NODE_SIZE, NODE_SIZE) }
+                .copy_from_slice(&label);
+
+            self.done.store(node + 1, Ordering::Release);
+        }
+    }
+}
+
+/// Labels all nodes of layer `layer_index` of a single sector, like
+/// `create_layer_labels`, on `config.producers + 1` threads.
+///
+/// Labeling is sequential, every label depends on the previous ones, but it is
+/// bound by the latency of loading the parent labels rather than by hashing. So
+/// producer threads read the parents of upcoming nodes from `cache` and copy
+/// their labels into a ring buffer, while a consumer thread hashes them in order.
+pub fn create_layer_labels_multicore<H, G>(
+    config: &MulticoreConfig,
+    graph: &StackedGraph<H, G>,
+    cache: &ParentCache,
+    replica_id: &H::Domain,
+    layer_index: usize,
+    layer_labels: &mut [u8],
+    exp_labels: Option<&[u8]>,
+) -> Result<()>
+where
+    H: Hasher,
+    G: Graph<H> + ParameterSetMetadata + Sync + Send,
+{
+    ensure!(config.producers > 0, "at least one producer is needed");
+    ensure!(config.batch_size > 0, "batches must not be empty");
+    ensure!(
+        config.ring_size >= config.batch_size,
+        "ring of {} nodes is smaller than a batch of {}",
+        config.ring_size,
+        config.batch_size
+    );
+    ensure!(
+        cache.nodes() == graph.size() && cache.degree() == graph.degree(),
+        "parent cache does not match the graph"
+    );
+    ensure!(
+        layer_labels.len() == graph.size() * NODE_SIZE,
+        "layer of {} bytes for {} nodes",
+        layer_labels.len(),
+        graph.size()
+    );
+    if let Some(exp_labels) = exp_labels {
+        ensure!(
+            exp_labels.len() == graph.size() * NODE_SIZE,
+            "previous layer of {} bytes for {} nodes",
+            exp_labels.len(),
+            graph.size()
+        );
+    }
+
+    let base_degree = graph.base_graph().degree();
+    ensure!(
+        base_degree < 64,
+        "base degree {} is too large to be prefetched",
+        base_degree
+    );
+    let count = if exp_labels.is_some() {
+        graph.degree()
+    } else {
+        base_degree
+    };
+
+    let mut ring = vec![0u8; config.ring_size * count * NODE_SIZE];
+    let pipeline = Pipeline {
+        cache,
+        layer_labels: SharedBuffer::new(layer_labels),
+        exp_labels,
+        ring: SharedBuffer::new(&mut ring),
+        ring_size: config.ring_size,
+        batch_size: config.batch_size,
+        nodes: graph.size(),
+        base_degree,
+        count,
+        copied: (0..config.ring_size).map(|_| AtomicU64::new(0)).collect(),
+        filled: (0..config.ring_size).map(|_| AtomicUsize::new(0)).collect(),
+        done: AtomicUsize::new(0),
+        next_batch: AtomicUsize::new(0),
+    };
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.producers + 1)
+        .build()
+        .context("could not create the labeling threads")?;
+    pool.scope(|s| {
+        for _ in 0..config.producers {
+            s.spawn(|_| pipeline.produce());
+        }
+        pipeline.consume::<H>(replica_id, layer_index);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use storage_proofs_core::drgraph::BASE_DEGREE;
+    use storage_proofs_core::hasher::{Domain, Sha256Hasher};
+
+    use super::super::{create_layer_labels, StackedBucketGraph, EXP_DEGREE};
+
+    type H = Sha256Hasher;
+
+    fn check_multicore(base_degree: usize, expansion_degree: usize, config: MulticoreConfig) {
+        let nodes = 500;
+        let graph =
+            StackedBucketGraph::<H>::new_stacked(nodes, base_degree, expansion_degree, [3u8; 32])
+                .unwrap();
+        let cache = ParentCache::new(&graph).unwrap();
+        let replica_id = <H as Hasher>::Domain::random(&mut rand::thread_rng());
+
+        let mut expected = vec![vec![0u8; nodes * NODE_SIZE]; 2];
+        let mut labels = expected.clone();
+        for layer in 0..2 {
+            let (previous, current) = expected.split_at_mut(layer);
+            let exp_labels = previous.last().map(|l| &l[..]);
+            create_layer_labels(&graph, &replica_id, layer + 1, &mut current[0], exp_labels)
+                .unwrap();
+
+            let (previous, current) = labels.split_at_mut(layer);
+            let exp_labels = previous.last().map(|l| &l[..]);
+            create_layer_labels_multicore(
+                &config,
+                &graph,
+                &cache,
+                &replica_id,
+                layer + 1,
+                &mut current[0],
+                exp_labels,
+            )
+            .unwrap();
+
+            assert_eq!(expected[layer], labels[layer], "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn multicore_labels_match_single_labels() {
+        let configs = [
+            MulticoreConfig {
+                producers: 1,
+                ring_size: 4,
+                batch_size: 2,
+            },
+            MulticoreConfig {
+                producers: 3,
+                ring_size: 16,
+                batch_size: 4,
+            },
+            MulticoreConfig {
+                producers: 2,
+                ring_size: 1000,
+                batch_size: 1,
+            },
+            MulticoreConfig::default(),
+        ];
+
+        for config in &configs {
+            check_multicore(BASE_DEGREE, EXP_DEGREE, *config);
+        }
+        check_multicore(3, 5, configs[1]);
+    }
+
+    #[test]
+    fn multicore_rejects_invalid_config() {
+        let graph =
+            StackedBucketGraph::<H>::new_stacked(16, BASE_DEGREE, EXP_DEGREE, [3u8; 32]).unwrap();
+        let cache = ParentCache::new(&graph).unwrap();
+        let replica_id = <H as Hasher>::Domain::random(&mut rand::thread_rng());
+        let mut labels = vec![0u8; 16 * NODE_SIZE];
+
+        let config = MulticoreConfig {
+            producers: 1,
+            ring_size: 2,
+            batch_size: 4,
+        };
+        assert!(create_layer_labels_multicore(
+            &config,
+            &graph,
+            &cache,
+            &replica_id,
+            1,
+            &mut labels,
+            None
+        )
+        .is_err());
+    }
+}
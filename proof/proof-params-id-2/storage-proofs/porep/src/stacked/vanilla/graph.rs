@@ -8,17 +8,19 @@ use storage_proofs_core::{
         feistel::{self, FeistelPrecomputed},
         FEISTEL_DST,
     },
-    drgraph::BASE_DEGREE,
-    drgraph::{BucketGraph, Graph},
+    drgraph::{
+        check_parents_range, BucketGraph, ChaCha20BucketGraph, DrSampleGraph, Graph, UniformGraph,
+    },
     error::Result,
     hasher::Hasher,
     parameter_cache::ParameterSetMetadata,
 };
 
-/// The expansion degree used for Stacked Graphs.
-pub const EXP_DEGREE: usize = 8;
+use super::create_label::LABEL_PARENTS;
 
-pub(crate) const DEGREE: usize = BASE_DEGREE + EXP_DEGREE;
+/// The expansion degree used for Stacked Graphs. Other expansion degrees, like
+/// other base degrees than `BASE_DEGREE`, are only meant for experiments.
+pub const EXP_DEGREE: usize = 8;
 
 #[derive(Clone)]
 pub struct StackedGraph<H, G>
@@ -51,6 +53,12 @@ where
 
 pub type StackedBucketGraph<H> = StackedGraph<H, BucketGraph<H>>;
 
+// Stacked graphs over the alternative DRG samplers, to compare them with the
+// bucket sampling.
+pub type StackedDrSampleGraph<H> = StackedGraph<H, DrSampleGraph<H>>;
+pub type StackedChaCha20BucketGraph<H> = StackedGraph<H, ChaCha20BucketGraph<H>>;
+pub type StackedUniformGraph<H> = StackedGraph<H, UniformGraph<H>>;
+
 pub fn derive_feistel_keys(porep_id: [u8; 32]) -> [u64; 4] {
     let mut feistel_keys = [0u64; 4];
     let raw_seed = derive_porep_domain_seed(FEISTEL_DST, porep_id);
@@ -73,14 +81,34 @@ where
         expansion_degree: usize,
         porep_id: [u8; 32],
     ) -> Result<Self> {
-        assert_eq!(base_degree, BASE_DEGREE);
-        assert_eq!(expansion_degree, EXP_DEGREE);
+        ensure!(base_degree >= 2, "base degree must be at least 2");
+        ensure!(expansion_degree >= 1, "expansion degree must be at least 1");
+        ensure!(
+            base_degree + expansion_degree <= LABEL_PARENTS,
+            "at most {} parents per node are supported, got {}",
+            LABEL_PARENTS,
+            base_degree + expansion_degree
+        );
         ensure!(nodes <= std::u32::MAX as usize, "too many nodes");
+        ensure!(
+            (nodes as u64)
+                .checked_mul(expansion_degree as u64)
+                .is_some(),
+            "too many expanded parents"
+        );
 
         let base_graph = match base_graph {
             Some(graph) => graph,
             None => G::new(nodes, base_degree, 0, porep_id)?,
         };
+        ensure!(
+            base_graph.size() == nodes && base_graph.degree() == base_degree,
+            "base graph of {} nodes of degree {}, expected {} nodes of degree {}",
+            base_graph.size(),
+            base_graph.degree(),
+            nodes,
+            base_degree
+        );
         let bg_id = base_graph.identifier();
 
         let feistel_keys = derive_feistel_keys(porep_id);
@@ -144,6 +172,31 @@ where
         Ok(())
     }
 
+    fn parents_range(&self, start: usize, end: usize, parents: &mut [u32]) -> Result<()> {
+        let base_degree = self.base_graph().degree();
+        let degree = self.degree();
+        check_parents_range(start, end, self.size(), degree, parents)?;
+
+        // Generate all base parents, then all expanded parents of the range at once,
+        // and interleave them.
+        let mut base_parents = vec![0u32; (end - start) * base_degree];
+        self.base_graph()
+            .parents_range(start, end, &mut base_parents)?;
+        let mut expanded_parents = vec![0u32; (end - start) * self.expansion_degree];
+        self.generate_expanded_parents_range(start, &mut expanded_parents);
+
+        for ((node_parents, base), expanded) in parents
+            .chunks_exact_mut(degree)
+            .zip(base_parents.chunks_exact(base_degree))
+            .zip(expanded_parents.chunks_exact(self.expansion_degree))
+        {
+            node_parents[..base_degree].copy_from_slice(base);
+            node_parents[base_degree..].copy_from_slice(expanded);
+        }
+
+        Ok(())
+    }
+
     fn seed(&self) -> [u8; 28] {
         self.base_graph().seed()
     }
@@ -222,6 +275,30 @@ where
         }
     }
 
+    /// Generates the expanded parents of consecutive nodes, starting at `first_node`,
+    /// `self.expansion_degree` parents per node, the same as calling `correspondent`
+    /// for each of them.
+    ///
+    /// The permuted indices of all these parents form a contiguous range, which is
+    /// permuted at once with `feistel::permute_range`, so generating the parents of
+    /// many nodes per call is much faster than one node at a time.
+    pub fn generate_expanded_parents_range(&self, first_node: usize, expanded_parents: &mut [u32]) {
+        debug_assert_eq!(expanded_parents.len() % self.expansion_degree, 0);
+
+        let mut permuted = vec![0; expanded_parents.len()];
+        feistel::permute_range(
+            self.size() as feistel::Index * self.expansion_degree as feistel::Index,
+            (first_node * self.expansion_degree) as feistel::Index,
+            &self.feistel_keys,
+            self.feistel_precomputed,
+            &mut permuted,
+        );
+
+        for (el, transformed) in expanded_parents.iter_mut().zip(permuted.into_iter()) {
+            *el = transformed as u32 / self.expansion_degree as u32;
+        }
+    }
+
     pub fn new_stacked(
         nodes: usize,
         base_degree: usize,
@@ -270,4 +347,159 @@ where
     H: Hasher,
     G: Graph<H>,
 {
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rayon::prelude::*;
+    use storage_proofs_core::drgraph::{par_parents, BASE_DEGREE};
+    use storage_proofs_core::hasher::Sha256Hasher;
+
+    fn check_stacked_parents<G>(graph: &StackedGraph<Sha256Hasher, G>)
+    where
+        G: Graph<Sha256Hasher> + ParameterSetMetadata + Sync + Send,
+    {
+        let mut parents = vec![0u32; graph.degree()];
+        for node in 2..graph.size() {
+            graph.parents(node, &mut parents).unwrap();
+            let base_degree = graph.base_graph().degree();
+            assert!(parents[..base_degree]
+                .iter()
+                .all(|parent| (*parent as usize) < node));
+            assert!(parents[base_degree..]
+                .iter()
+                .all(|parent| (*parent as usize) < graph.size()));
+        }
+    }
+
+    #[test]
+    fn stacked_graphs_over_any_sampler() {
+        let nodes = 256;
+        let porep_id = [5u8; 32];
+
+        let bucket = StackedBucketGraph::<Sha256Hasher>::new_stacked(
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            porep_id,
+        )
+        .unwrap();
+        let dr_sample = StackedDrSampleGraph::<Sha256Hasher>::new_stacked(
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            porep_id,
+        )
+        .unwrap();
+        let chacha20 = StackedChaCha20BucketGraph::<Sha256Hasher>::new_stacked(
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            porep_id,
+        )
+        .unwrap();
+        let uniform = StackedUniformGraph::<Sha256Hasher>::new_stacked(
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            porep_id,
+        )
+        .unwrap();
+
+        check_stacked_parents(&bucket);
+        check_stacked_parents(&dr_sample);
+        check_stacked_parents(&chacha20);
+        check_stacked_parents(&uniform);
+
+        let identifiers = [
+            bucket.identifier(),
+            dr_sample.identifier(),
+            chacha20.identifier(),
+            uniform.identifier(),
+        ];
+        for (i, a) in identifiers.iter().enumerate() {
+            assert!(identifiers[i + 1..].iter().all(|b| a != b));
+        }
+    }
+
+    #[test]
+    fn stacked_parents_in_blocks() {
+        let graph = StackedBucketGraph::<Sha256Hasher>::new_stacked(
+            500,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [9u8; 32],
+        )
+        .unwrap();
+        let degree = graph.degree();
+
+        let mut expected = vec![0u32; 500 * degree];
+        for (node, node_parents) in expected.chunks_exact_mut(degree).enumerate() {
+            graph.parents(node, node_parents).unwrap();
+        }
+
+        let mut range = vec![0u32; 500 * degree];
+        graph.parents_range(0, 500, &mut range).unwrap();
+        assert_eq!(range, expected);
+
+        let blocks = par_parents::<Sha256Hasher, _>(&graph, 0..500, 64)
+            .flat_map(|block| block.unwrap().parents)
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, expected);
+    }
+
+    #[test]
+    fn other_degrees() {
+        let graph = StackedBucketGraph::<Sha256Hasher>::new_stacked(256, 4, 3, [5u8; 32]).unwrap();
+        assert_eq!(graph.degree(), 7);
+        check_stacked_parents(&graph);
+
+        let default = StackedBucketGraph::<Sha256Hasher>::new_stacked(
+            256,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [5u8; 32],
+        )
+        .unwrap();
+        assert_ne!(graph.identifier(), default.identifier());
+
+        assert!(StackedBucketGraph::<Sha256Hasher>::new_stacked(256, 1, 3, [5u8; 32]).is_err());
+        assert!(StackedBucketGraph::<Sha256Hasher>::new_stacked(256, 4, 0, [5u8; 32]).is_err());
+        // More parents than a label has room for.
+        assert!(StackedBucketGraph::<Sha256Hasher>::new_stacked(256, 30, 8, [5u8; 32]).is_err());
+
+        let base_graph = BucketGraph::new(256, 4, 0, [5u8; 32]).unwrap();
+        assert!(
+            StackedBucketGraph::<Sha256Hasher>::new(Some(base_graph), 256, 6, 3, [5u8; 32])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn expanded_parents_range_matches_correspondent() {
+        // 1000 nodes are not a power of 4, so some indices are cycle-walked.
+        let nodes = 1000;
+        let graph = StackedBucketGraph::<Sha256Hasher>::new_stacked(
+            nodes,
+            BASE_DEGREE,
+            EXP_DEGREE,
+            [3u8; 32],
+        )
+        .unwrap();
+
+        let mut parents = vec![0u32; nodes * EXP_DEGREE];
+        graph.generate_expanded_parents_range(0, &mut parents);
+
+        let mut single = [0u32; EXP_DEGREE];
+        for node in 0..nodes {
+            graph.generate_expanded_parents(node, &mut single);
+            for (i, parent) in single.iter().enumerate() {
+                let expected = graph.correspondent(node, i);
+                assert_eq!(parents[node * EXP_DEGREE + i], expected);
+                assert_eq!(*parent, expected);
+            }
+        }
+    }
+}
@@ -1,9 +1,19 @@
+mod cache;
 mod challenges;
+mod checkpoint;
+mod create_label;
+mod create_label_multicore;
 mod params;
 mod proof;
 mod graph;
+mod tree_r_last;
 
+pub use self::cache::*;
 pub use self::challenges::{ LayerChallenges };
+pub use self::checkpoint::*;
+pub use self::create_label::*;
+pub use self::create_label_multicore::*;
 pub use self::graph::*;
 pub use self::proof::*;
-pub use self::params::*;
\ No newline at end of file
+pub use self::params::*;
+pub use self::tree_r_last::*;
\ No newline at end of file
@@ -1,12 +1,26 @@
+use std::convert::TryFrom;
+use std::fs;
 use std::marker::PhantomData;
 
+use anyhow::{anyhow, bail, ensure, Context};
+use generic_array::typenum::Unsigned;
+use merkletree::store::StoreConfig;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use storage_proofs_core::{
-    merkle::{MerkleTreeTrait},
+    cache_key::CacheKey,
+    error::Result,
+    hasher::Domain,
+    merkle::{CacheDir, MerkleTreeTrait},
     parameter_cache::{ParameterSetMetadata},
+    util::NODE_SIZE,
 };
 
-use super::{ graph::StackedBucketGraph, LayerChallenges };
+use super::{
+    checkpoint::{base_tree_leafs, layer_config, write_atomically},
+    graph::StackedBucketGraph,
+    LayerChallenges,
+};
 
 #[derive(Debug, Clone)]
 pub struct SetupParams {
@@ -83,4 +97,353 @@ where
     fn from(other: &PublicParams<Tree>) -> PublicParams<Tree> {
         PublicParams::new(other.graph.clone(), other.layer_challenges.clone())
     }
-}
\ No newline at end of file
+}
+
+/// Commitments of a sealed sector needed to prove it, stored in its cache directory
+/// under `CacheKey::PAux`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistentAux<D> {
+    pub comm_c: D,
+    pub comm_r_last: D,
+}
+
+/// Where sealing wrote the layers and trees of a sector, stored in its cache
+/// directory under `CacheKey::TAux`, so the prover can open them after PC2
+/// without computing anything again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemporaryAux {
+    /// One config per layer, in order.
+    pub labels: Vec<StoreConfig>,
+    pub tree_d_config: StoreConfig,
+    /// Also holds the number of rows discarded from tree-r-last.
+    pub tree_r_last_config: StoreConfig,
+    pub tree_c_config: StoreConfig,
+}
+
+impl TemporaryAux {
+    /// Configs of the files written to `cache` when sealing a sector of `nodes`
+    /// nodes over `layers` layers. Fails if the nodes cannot be split evenly
+    /// into the base trees of `Tree`.
+    pub fn new<Tree: MerkleTreeTrait>(
+        cache: &CacheDir,
+        layers: usize,
+        nodes: usize,
+    ) -> Result<Self> {
+        let base_tree_leafs = base_tree_leafs::<Tree>(nodes)?;
+        let rows_to_discard = cache.rows_to_discard(base_tree_leafs, Tree::Arity::to_usize());
+
+        Ok(TemporaryAux {
+            labels: (1..=layers)
+                .map(|layer| layer_config(cache, layer))
+                .collect(),
+            tree_d_config: cache.store_config(CacheKey::CommDTree, 0),
+            tree_r_last_config: cache.store_config(CacheKey::CommRLastTree, rows_to_discard),
+            tree_c_config: cache.store_config(CacheKey::CommCTree, 0),
+        })
+    }
+
+    pub fn layers(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Rows discarded from tree-r-last, which it must be reopened with.
+    pub fn rows_to_discard(&self) -> usize {
+        self.tree_r_last_config.rows_to_discard
+    }
+}
+
+/// Version of the layout of the aux data written by `encode_aux`. Every change to
+/// `PersistentAux` or `TemporaryAux` bumps it, and teaches `SectorAux::migrate` to
+/// read the previous layout.
+pub const AUX_VERSION: u32 = 1;
+
+/// Starts binary aux data, followed by the version as a little-endian `u32`. Data
+/// written before versioning is told apart by `SectorAux::is_unversioned` and read
+/// as version 0.
+const AUX_MAGIC: [u8; 4] = *b"\xffaux";
+
+/// Encoding of aux data.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AuxFormat {
+    /// bincode, compact and used by the sealing pipeline.
+    Binary,
+    /// JSON, to be read by humans and external tooling.
+    Json,
+}
+
+impl AuxFormat {
+    /// Decodes `payload`, without any version information.
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T> {
+        Ok(match self {
+            AuxFormat::Binary => bincode::deserialize(payload)?,
+            AuxFormat::Json => serde_json::from_slice(payload)?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct JsonEnvelope<T> {
+    version: u32,
+    data: T,
+}
+
+/// Aux data kept in the cache directory of a sector.
+pub trait SectorAux: Serialize + DeserializeOwned {
+    const KEY: CacheKey;
+
+    /// Whether the binary aux data `bytes` was written before versioning, without
+    /// `AUX_MAGIC` and the version.
+    fn is_unversioned(bytes: &[u8]) -> bool;
+
+    /// Decodes `payload`, written in `format` with the layout of `version`, which
+    /// is older than `AUX_VERSION`.
+    fn migrate(version: u32, format: AuxFormat, payload: &[u8]) -> Result<Self>;
+}
+
+impl<D: Domain> SectorAux for PersistentAux<D> {
+    const KEY: CacheKey = CacheKey::PAux;
+
+    fn is_unversioned(bytes: &[u8]) -> bool {
+        // Unversioned data is only the two commitments, versioned data is 8 bytes
+        // longer.
+        bytes.len() == 2 * NODE_SIZE
+    }
+
+    fn migrate(version: u32, format: AuxFormat, payload: &[u8]) -> Result<Self> {
+        match version {
+            // Only the envelope is new in version 1.
+            0 => format.decode(payload),
+            _ => bail!("unsupported p_aux version {}", version),
+        }
+    }
+}
+
+impl SectorAux for TemporaryAux {
+    const KEY: CacheKey = CacheKey::TAux;
+
+    fn is_unversioned(bytes: &[u8]) -> bool {
+        // Unversioned data starts with the number of layers as a `u64`, which is at
+        // most the number of bytes. Read from versioned data, the version makes it
+        // at least 2^32.
+        let mut layers = [0u8; 8];
+        match bytes.get(..8) {
+            Some(prefix) => layers.copy_from_slice(prefix),
+            None => return true,
+        }
+        u64::from_le_bytes(layers) <= bytes.len() as u64
+    }
+
+    fn migrate(version: u32, format: AuxFormat, payload: &[u8]) -> Result<Self> {
+        match version {
+            // Written before versioning, with the layout of version 1.
+            0 => format.decode(payload),
+            _ => bail!("unsupported t_aux version {}", version),
+        }
+    }
+}
+
+/// Encodes `aux` in `format` with the current `AUX_VERSION`.
+pub fn encode_aux<T: SectorAux>(aux: &T, format: AuxFormat) -> Result<Vec<u8>> {
+    Ok(match format {
+        AuxFormat::Binary => {
+            let mut bytes = AUX_MAGIC.to_vec();
+            bytes.extend_from_slice(&AUX_VERSION.to_le_bytes());
+            bytes.extend(bincode::serialize(aux)?);
+            bytes
+        }
+        AuxFormat::Json => serde_json::to_vec_pretty(&JsonEnvelope {
+            version: AUX_VERSION,
+            data: aux,
+        })?,
+    })
+}
+
+/// Decodes aux data encoded in `format` by `encode_aux` of this or an older
+/// version, or written before versioning.
+pub fn decode_aux<T: SectorAux>(bytes: &[u8], format: AuxFormat) -> Result<T> {
+    let (version, payload) = match format {
+        AuxFormat::Binary => {
+            if T::is_unversioned(bytes) {
+                (0, bytes.to_vec())
+            } else {
+                ensure!(
+                    bytes.len() >= 8 && bytes[..4] == AUX_MAGIC,
+                    "{} is neither versioned nor unversioned aux data",
+                    T::KEY
+                );
+                let mut version = [0u8; 4];
+                version.copy_from_slice(&bytes[4..8]);
+                (u32::from_le_bytes(version), bytes[8..].to_vec())
+            }
+        }
+        AuxFormat::Json => {
+            let value: serde_json::Value = serde_json::from_slice(bytes)?;
+            match (
+                value.get("version").and_then(|v| v.as_u64()),
+                value.get("data"),
+            ) {
+                (Some(version), Some(data)) if value.as_object().map_or(0, |o| o.len()) == 2 => {
+                    (u32::try_from(version)?, serde_json::to_vec(data)?)
+                }
+                _ => (0, bytes.to_vec()),
+            }
+        }
+    };
+
+    match version {
+        AUX_VERSION => format.decode(&payload),
+        version if version < AUX_VERSION => T::migrate(version, format, &payload),
+        version => Err(anyhow!(
+            "{} has version {}, newer than the supported {}",
+            T::KEY,
+            version,
+            AUX_VERSION
+        )),
+    }
+}
+
+/// Loads the aux data of type `T` stored in `cache`.
+pub fn load_aux<T: SectorAux>(cache: &CacheDir, format: AuxFormat) -> Result<T> {
+    let path = cache.file_path(T::KEY);
+    let bytes = fs::read(&path).with_context(|| format!("could not read {:?}", path))?;
+
+    decode_aux(&bytes, format).with_context(|| format!("could not decode {:?}", path))
+}
+
+/// Stores `aux` in `cache`, replacing any previous version atomically.
+pub fn store_aux<T: SectorAux>(cache: &CacheDir, aux: &T, format: AuxFormat) -> Result<()> {
+    write_atomically(&cache.file_path(T::KEY), &encode_aux(aux, format)?)
+}
+
+/// Rewrites the aux data of type `T` stored in `cache` with the current layout.
+pub fn upgrade_aux<T: SectorAux>(cache: &CacheDir, format: AuxFormat) -> Result<T> {
+    let aux = load_aux::<T>(cache, format)?;
+    store_aux(cache, &aux, format)?;
+
+    Ok(aux)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use generic_array::typenum::{U0, U8};
+    use storage_proofs_core::hasher::{Hasher, Sha256Hasher};
+    use storage_proofs_core::merkle::DiskTree;
+
+    use super::super::layer_path;
+
+    type D = <Sha256Hasher as Hasher>::Domain;
+    type Tree = DiskTree<Sha256Hasher, U8, U8, U0>;
+
+    fn p_aux() -> PersistentAux<D> {
+        let mut rng = rand::thread_rng();
+        PersistentAux {
+            comm_c: D::random(&mut rng),
+            comm_r_last: D::random(&mut rng),
+        }
+    }
+
+    fn config_fields(config: &StoreConfig) -> (std::path::PathBuf, String, usize) {
+        (
+            config.path.clone(),
+            config.id.clone(),
+            config.rows_to_discard,
+        )
+    }
+
+    fn assert_same_t_aux(a: &TemporaryAux, b: &TemporaryAux) {
+        let fields = |t_aux: &TemporaryAux| {
+            let mut configs: Vec<_> = t_aux.labels.iter().map(config_fields).collect();
+            configs.push(config_fields(&t_aux.tree_d_config));
+            configs.push(config_fields(&t_aux.tree_r_last_config));
+            configs.push(config_fields(&t_aux.tree_c_config));
+            configs
+        };
+        assert_eq!(fields(a), fields(b));
+    }
+
+    #[test]
+    fn aux_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path()).with_rows_to_discard(2);
+        let p_aux = p_aux();
+        let t_aux = TemporaryAux::new::<Tree>(&cache, 11, 512).unwrap();
+        assert_eq!(t_aux.layers(), 11);
+        assert_eq!(t_aux.rows_to_discard(), 2);
+        assert_eq!(t_aux.labels[10].id, "layer-11");
+        assert_eq!(
+            StoreConfig::data_path(&t_aux.labels[10].path, &t_aux.labels[10].id),
+            layer_path(&cache, 11)
+        );
+
+        // 500 nodes do not split into 8 base trees.
+        assert!(TemporaryAux::new::<Tree>(&cache, 11, 500).is_err());
+
+        for format in &[AuxFormat::Binary, AuxFormat::Json] {
+            store_aux(&cache, &p_aux, *format).unwrap();
+            store_aux(&cache, &t_aux, *format).unwrap();
+
+            assert_eq!(
+                load_aux::<PersistentAux<D>>(&cache, *format).unwrap(),
+                p_aux
+            );
+            assert_same_t_aux(&load_aux::<TemporaryAux>(&cache, *format).unwrap(), &t_aux);
+        }
+    }
+
+    #[test]
+    fn aux_without_version_is_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+        let p_aux = p_aux();
+        let t_aux = TemporaryAux::new::<Tree>(&cache, 2, 512).unwrap();
+
+        // Layouts written before the envelope.
+        let path = cache.file_path(CacheKey::PAux);
+        fs::write(&path, bincode::serialize(&p_aux).unwrap()).unwrap();
+        assert_eq!(
+            upgrade_aux::<PersistentAux<D>>(&cache, AuxFormat::Binary).unwrap(),
+            p_aux
+        );
+        assert_eq!(&fs::read(&path).unwrap()[..4], &AUX_MAGIC[..]);
+        assert_eq!(
+            load_aux::<PersistentAux<D>>(&cache, AuxFormat::Binary).unwrap(),
+            p_aux
+        );
+
+        let json = serde_json::to_vec(&t_aux).unwrap();
+        assert_same_t_aux(&decode_aux(&json, AuxFormat::Json).unwrap(), &t_aux);
+    }
+
+    #[test]
+    fn aux_from_newer_version_is_rejected() {
+        let mut bytes = encode_aux(&p_aux(), AuxFormat::Binary).unwrap();
+        bytes[4..8].copy_from_slice(&(AUX_VERSION + 1).to_le_bytes());
+        assert!(decode_aux::<PersistentAux<D>>(&bytes, AuxFormat::Binary).is_err());
+
+        let json = serde_json::json!({ "version": AUX_VERSION + 1, "data": p_aux() });
+        let bytes = serde_json::to_vec(&json).unwrap();
+        assert!(decode_aux::<PersistentAux<D>>(&bytes, AuxFormat::Json).is_err());
+    }
+
+    #[test]
+    fn aux_without_version_starting_like_magic_is_not_misread() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path());
+
+        let mut p_aux = p_aux();
+        p_aux.comm_c.0[..4].copy_from_slice(&AUX_MAGIC);
+        p_aux.comm_c.0[4..8].copy_from_slice(&AUX_VERSION.to_le_bytes());
+        let bytes = bincode::serialize(&p_aux).unwrap();
+        assert_eq!(&bytes[..4], &AUX_MAGIC[..]);
+        assert_eq!(
+            decode_aux::<PersistentAux<D>>(&bytes, AuxFormat::Binary).unwrap(),
+            p_aux
+        );
+
+        let t_aux = TemporaryAux::new::<Tree>(&cache, 2, 512).unwrap();
+        let bytes = bincode::serialize(&t_aux).unwrap();
+        assert_same_t_aux(&decode_aux(&bytes, AuxFormat::Binary).unwrap(), &t_aux);
+    }
+}
@@ -0,0 +1,348 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use anyhow::{ensure, Context};
+use generic_array::typenum::Unsigned;
+use log::{info, warn};
+use merkletree::hash::Algorithm;
+use merkletree::store::ReplicaConfig;
+use storage_proofs_core::{
+    cache_key::CacheKey,
+    error::{Error, Result},
+    hasher::{Domain, Hasher},
+    merkle::{
+        get_base_tree_count, CacheDir, LCTree, LCTreeWith, MerkleTreeTrait, ReplicaReader,
+        StoreKind,
+    },
+    util::NODE_SIZE,
+};
+
+/// Level cache version of tree-r-last for the shape `Tree`.
+pub type TreeRLast<Tree> = LCTree<
+    <Tree as MerkleTreeTrait>::Hasher,
+    <Tree as MerkleTreeTrait>::Arity,
+    <Tree as MerkleTreeTrait>::SubTreeArity,
+    <Tree as MerkleTreeTrait>::TopTreeArity,
+>;
+
+/// Version of `TreeRLast` reading the replica through `R`.
+pub type TreeRLastWith<Tree, R> = LCTreeWith<
+    <Tree as MerkleTreeTrait>::Hasher,
+    R,
+    <Tree as MerkleTreeTrait>::Arity,
+    <Tree as MerkleTreeTrait>::SubTreeArity,
+    <Tree as MerkleTreeTrait>::TopTreeArity,
+>;
+
+/// Replica config for tree-r-last of a sector of `nodes_count` nodes: each
+/// base tree reads its leaves from its own slice of the replica.
+pub fn replica_config<Tree: MerkleTreeTrait>(
+    replica_path: &Path,
+    nodes_count: usize,
+) -> Result<ReplicaConfig> {
+    Ok(ReplicaConfig::new(
+        replica_path,
+        replica_offsets::<Tree>(nodes_count)?,
+    ))
+}
+
+/// Byte offsets of the slices of the replica read by each base tree.
+pub fn replica_offsets<Tree: MerkleTreeTrait>(nodes_count: usize) -> Result<Vec<usize>> {
+    let base_tree_leafs = base_tree_leafs::<Tree>(nodes_count)?;
+
+    Ok((0..get_base_tree_count::<Tree>())
+        .map(|i| i * base_tree_leafs * NODE_SIZE)
+        .collect())
+}
+
+/// Rebuilds the cached rows of tree-r-last in `cache` from the replica, using
+/// the rows to discard configured on `cache`. Existing tree-r-last files are
+/// overwritten, so this can also be used to change how many rows a machine
+/// keeps on disk.
+///
+/// Base trees are built one at a time from the mapped replica, so memory use
+/// does not grow with the sector.
+pub fn rebuild_tree_r_last<Tree: MerkleTreeTrait>(
+    cache: &CacheDir,
+    replica_path: &Path,
+    nodes_count: usize,
+) -> Result<TreeRLast<Tree>> {
+    info!(
+        "rebuilding tree-r-last in {:?} from {:?}",
+        cache.path(),
+        replica_path
+    );
+    check_replica_len(replica_path, nodes_count)?;
+    let replica_config = replica_config::<Tree>(replica_path, nodes_count)?;
+
+    cache.persist_lc_tree_from_replica::<Tree>(
+        CacheKey::CommRLastTree,
+        base_tree_leafs::<Tree>(nodes_count)?,
+        &replica_config,
+    )
+}
+
+/// Opens tree-r-last from `cache`, rebuilding it from the replica if its
+/// files are missing or were written with a different number of rows to
+/// discard.
+pub fn open_tree_r_last<Tree: MerkleTreeTrait>(
+    cache: &CacheDir,
+    replica_path: &Path,
+    nodes_count: usize,
+) -> Result<TreeRLast<Tree>> {
+    let base_tree_leafs = base_tree_leafs::<Tree>(nodes_count)?;
+
+    if let Err(err) = cache.check_tree::<Tree>(
+        CacheKey::CommRLastTree,
+        base_tree_leafs,
+        StoreKind::LevelCache,
+    ) {
+        match err.downcast_ref::<Error>() {
+            Some(Error::MissingCacheFile { .. }) | Some(Error::TruncatedCacheFile { .. }) => {
+                warn!("{}", err);
+                return rebuild_tree_r_last::<Tree>(cache, replica_path, nodes_count);
+            }
+            _ => return Err(err),
+        }
+    }
+
+    let replica_config = replica_config::<Tree>(replica_path, nodes_count)?;
+    cache.open_lc_tree::<Tree>(CacheKey::CommRLastTree, base_tree_leafs, &replica_config)
+}
+
+/// Opens tree-r-last from `cache`, reading the replica through `reader`
+/// instead of from a local file, e.g. from another host or storage tier.
+///
+/// Unlike `open_tree_r_last`, missing tree files are not rebuilt.
+pub fn open_tree_r_last_with_reader<Tree: MerkleTreeTrait, R: ReplicaReader>(
+    cache: &CacheDir,
+    reader: &R,
+    nodes_count: usize,
+) -> Result<TreeRLastWith<Tree, R>> {
+    let readers = reader.external_readers(&replica_offsets::<Tree>(nodes_count)?);
+
+    cache.open_lc_tree_with_readers::<Tree, R>(
+        CacheKey::CommRLastTree,
+        base_tree_leafs::<Tree>(nodes_count)?,
+        readers,
+    )
+}
+
+/// Recomputes the root of tree-r-last from the replica and checks that it,
+/// as well as the root of the tree stored in `cache`, equal `comm_r_last`.
+pub fn verify_tree_r_last<Tree: MerkleTreeTrait>(
+    cache: &CacheDir,
+    replica_path: &Path,
+    nodes_count: usize,
+    comm_r_last: <Tree::Hasher as Hasher>::Domain,
+) -> Result<bool> {
+    let root = replica_root::<Tree>(replica_path, nodes_count)?;
+    if root != comm_r_last {
+        warn!("tree-r-last root recomputed from the replica does not match comm_r_last");
+        return Ok(false);
+    }
+
+    let tree = open_tree_r_last::<Tree>(cache, replica_path, nodes_count)?;
+    if tree.root() != comm_r_last {
+        warn!(
+            "tree-r-last root in {:?} does not match comm_r_last",
+            cache.path()
+        );
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+fn base_tree_leafs<Tree: MerkleTreeTrait>(nodes_count: usize) -> Result<usize> {
+    let base_tree_count = get_base_tree_count::<Tree>();
+    ensure!(
+        nodes_count % base_tree_count == 0,
+        "{} nodes cannot be split into {} base trees",
+        nodes_count,
+        base_tree_count
+    );
+
+    Ok(nodes_count / base_tree_count)
+}
+
+fn check_replica_len(replica_path: &Path, nodes_count: usize) -> Result<()> {
+    let len = std::fs::metadata(replica_path)
+        .with_context(|| format!("could not open {:?}", replica_path))?
+        .len();
+    ensure!(
+        len == (nodes_count * NODE_SIZE) as u64,
+        "replica {:?} has {} bytes, expected {}",
+        replica_path,
+        len,
+        nodes_count * NODE_SIZE
+    );
+
+    Ok(())
+}
+
+/// Root of tree-r-last recomputed from the replica, which is read once, in
+/// order, without storing any part of the tree.
+fn replica_root<Tree: MerkleTreeTrait>(
+    replica_path: &Path,
+    nodes_count: usize,
+) -> Result<<Tree::Hasher as Hasher>::Domain> {
+    check_replica_len(replica_path, nodes_count)?;
+    let base_tree_leafs = base_tree_leafs::<Tree>(nodes_count)?;
+    let mut replica = BufReader::new(
+        File::open(replica_path).with_context(|| format!("could not open {:?}", replica_path))?,
+    );
+
+    let mut roots = (0..get_base_tree_count::<Tree>())
+        .map(|_| base_tree_root::<Tree, _>(&mut replica, base_tree_leafs))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Sub tree roots, then the top tree root, are hashed from the roots below.
+    let mut a = <Tree::Hasher as Hasher>::Function::default();
+    for arity in &[
+        Tree::SubTreeArity::to_usize(),
+        Tree::TopTreeArity::to_usize(),
+    ] {
+        if *arity > 0 {
+            roots = roots
+                .chunks(*arity)
+                .map(|children| {
+                    a.reset();
+                    a.multi_node(children, 0)
+                })
+                .collect();
+        }
+    }
+    ensure!(roots.len() == 1, "tree-r-last has {} roots", roots.len());
+
+    Ok(roots[0])
+}
+
+/// Root of the base tree over the next `leafs` leaves read from `replica`.
+/// Only the nodes of every row whose siblings are not all known yet are kept.
+fn base_tree_root<Tree: MerkleTreeTrait, R: Read>(
+    replica: &mut R,
+    leafs: usize,
+) -> Result<<Tree::Hasher as Hasher>::Domain> {
+    let arity = Tree::Arity::to_usize();
+    let mut a = <Tree::Hasher as Hasher>::Function::default();
+    let mut frontier: Vec<Vec<<Tree::Hasher as Hasher>::Domain>> = Vec::new();
+    let mut buf = [0u8; NODE_SIZE];
+
+    for _ in 0..leafs {
+        replica.read_exact(&mut buf)?;
+        let mut node = <Tree::Hasher as Hasher>::Domain::try_from_bytes(&buf)?;
+        for row in 0.. {
+            if row == frontier.len() {
+                frontier.push(Vec::with_capacity(arity));
+            }
+            frontier[row].push(node);
+            if frontier[row].len() < arity {
+                break;
+            }
+
+            a.reset();
+            node = a.multi_node(&frontier[row], row);
+            frontier[row].clear();
+        }
+    }
+
+    let root = frontier.pop().unwrap_or_default();
+    ensure!(
+        root.len() == 1 && frontier.iter().all(Vec::is_empty),
+        "{} leaves do not make a full tree of arity {}",
+        leafs,
+        arity
+    );
+
+    Ok(root[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use generic_array::typenum::{U0, U2, U8};
+    use storage_proofs_core::hasher::Sha256Hasher;
+    use storage_proofs_core::merkle::{DiskTree, MerkleProofTrait, MmapReader};
+
+    type D = <Sha256Hasher as Hasher>::Domain;
+
+    fn write_replica(path: &Path, nodes_count: usize) -> Vec<D> {
+        let mut rng = rand::thread_rng();
+        let mut file = File::create(path).unwrap();
+        (0..nodes_count)
+            .map(|_| {
+                let node = D::random(&mut rng);
+                file.write_all(node.as_ref()).unwrap();
+                node
+            })
+            .collect()
+    }
+
+    fn check_rebuild<Tree: 'static + MerkleTreeTrait<Hasher = Sha256Hasher>>(nodes_count: usize) {
+        let dir = tempfile::tempdir().unwrap();
+        let replica_path = dir.path().join("replica");
+        let nodes = write_replica(&replica_path, nodes_count);
+
+        // Reference root, from a tree built in memory over the same leaves.
+        let base_leafs = nodes
+            .chunks(base_tree_leafs::<Tree>(nodes_count).unwrap())
+            .map(|leafs| leafs.to_vec())
+            .collect();
+        let reference_dir = tempfile::tempdir().unwrap();
+        let comm_r_last = CacheDir::new(reference_dir.path())
+            .persist_disk_tree::<Tree>(CacheKey::CommDTree, base_leafs)
+            .unwrap()
+            .root();
+        assert_eq!(
+            replica_root::<Tree>(&replica_path, nodes_count).unwrap(),
+            comm_r_last
+        );
+
+        // Every supported number of cached rows yields the same tree.
+        for rows_to_discard in 1..3 {
+            let cache = CacheDir::new(dir.path()).with_rows_to_discard(rows_to_discard);
+            let tree = open_tree_r_last::<Tree>(&cache, &replica_path, nodes_count).unwrap();
+            assert_eq!(tree.root(), comm_r_last);
+
+            for i in &[0, nodes_count / 2, nodes_count - 1] {
+                let proof = tree.gen_cached_proof(*i, Some(rows_to_discard)).unwrap();
+                assert!(proof.verify());
+            }
+            drop(tree);
+
+            assert!(
+                verify_tree_r_last::<Tree>(&cache, &replica_path, nodes_count, comm_r_last)
+                    .unwrap()
+            );
+
+            let reader = MmapReader::open(&replica_path).unwrap();
+            let tree =
+                open_tree_r_last_with_reader::<Tree, _>(&cache, &reader, nodes_count).unwrap();
+            assert_eq!(tree.root(), comm_r_last);
+        }
+
+        let cache = CacheDir::new(dir.path()).with_rows_to_discard(2);
+        let other = D::random(&mut rand::thread_rng());
+        assert!(!verify_tree_r_last::<Tree>(&cache, &replica_path, nodes_count, other).unwrap());
+    }
+
+    #[test]
+    fn rebuild_tree_r_last_single() {
+        check_rebuild::<DiskTree<Sha256Hasher, U8, U0, U0>>(512);
+    }
+
+    #[test]
+    fn rebuild_tree_r_last_sub() {
+        check_rebuild::<DiskTree<Sha256Hasher, U8, U2, U0>>(1024);
+    }
+
+    #[test]
+    fn rebuild_tree_r_last_top() {
+        check_rebuild::<DiskTree<Sha256Hasher, U8, U8, U2>>(8192);
+    }
+}